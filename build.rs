@@ -2,6 +2,25 @@ use std::process::Command;
 
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=incompatible_crates/data.json");
+
+    // Date of the incompatible crates list that is bundled for offline use.
+    if let Ok(output) = Command::new("git")
+        .args([
+            "log",
+            "-1",
+            "--format=%cs",
+            "--",
+            "incompatible_crates/data.json",
+        ])
+        .output()
+    {
+        let date = String::from_utf8(output.stdout).unwrap();
+        if output.status.success() && !date.trim().is_empty() {
+            println!("cargo:rustc-env=INCOMPATIBLE_CRATES_DATE={}", date.trim());
+        }
+    }
+
     if let Ok(output) = Command::new("git").arg("rev-parse").arg("HEAD").output() {
         if output.status.success() {
             let sha = String::from_utf8(output.stdout).unwrap();
@@ -0,0 +1,110 @@
+//! The parts of `cargo wasix` that other tools can use without running it.
+//!
+//! Unlike the CLI these don't read `Cargo.toml`, everything is configured
+//! through their arguments.
+
+use crate::config::Config;
+use crate::toolchain::{self, RustupToolchain};
+use crate::{ProcessOrder, Producers, SharedMemoryCheck};
+use anyhow::{Context, Result};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// How [`postprocess_wasm`] processes a module, which `cargo wasix` derives
+/// from the Cargo profile and `[package.metadata]`.
+///
+/// New fields may be added, so start from `PostprocessOptions::default()`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PostprocessOptions {
+    /// The `-O` level of `wasm-opt`, such as `"3"` or `"z"`.
+    pub opt_level: String,
+    /// Whether the module contains DWARF debug information that is kept.
+    pub debuginfo: bool,
+    /// Whether the DWARF is moved into a `*.debug.wasm` sidecar file.
+    pub split_debuginfo: bool,
+    /// Whether `wasm-opt` is run at all.
+    pub wasm_opt: bool,
+    /// Additional arguments passed to `wasm-opt`.
+    pub wasm_opt_args: Vec<String>,
+    /// Target features the module was compiled with, such as `simd128`.
+    pub target_features: Vec<String>,
+    /// Whether the module may only use the features of the WebAssembly MVP
+    /// and `target_features`, as with `compat = "mvp"`.
+    pub mvp: bool,
+    /// Whether the `name` section is kept.
+    pub name_section: bool,
+    /// Whether the `producers` section is kept.
+    pub producers_section: bool,
+    /// What the `producers` section contains.
+    pub producers: Producers,
+    /// Whether names are demangled before or after running `wasm-opt`.
+    pub process_order: ProcessOrder,
+    /// Whether the `asyncify_*` exports added by `wasm-opt` are removed.
+    pub hide_asyncify_exports: bool,
+    /// What to do if the memory of the module isn't shared.
+    pub shared_memory: SharedMemoryCheck,
+    /// Prefixes of paths in function names and what they are replaced with.
+    pub path_remaps: Vec<(String, String)>,
+    /// Whether status messages, such as those of downloading `wasm-opt`, are
+    /// suppressed like with `--quiet`. The only warning is that of
+    /// `shared_memory`, which [`SharedMemoryCheck::Off`] silences.
+    pub quiet: bool,
+}
+
+impl Default for PostprocessOptions {
+    /// The options of a `dev` build without debug information.
+    fn default() -> Self {
+        PostprocessOptions {
+            opt_level: "0".to_string(),
+            debuginfo: false,
+            split_debuginfo: false,
+            wasm_opt: true,
+            wasm_opt_args: Vec::new(),
+            target_features: Vec::new(),
+            mvp: false,
+            name_section: true,
+            producers_section: true,
+            producers: Producers::default(),
+            process_order: ProcessOrder::default(),
+            hide_asyncify_exports: false,
+            shared_memory: SharedMemoryCheck::default(),
+            path_remaps: Vec::new(),
+            quiet: false,
+        }
+    }
+}
+
+/// Post-processes the module at `path` in place, as `cargo wasix build` does
+/// for the modules Cargo produced: names are demangled and `wasm-opt` runs
+/// the passes WASIX needs, such as asyncify.
+///
+/// `wasm-opt` is downloaded if needed, see `WASM_OPT` to use another one.
+pub fn postprocess_wasm(path: &Path, options: &PostprocessOptions) -> Result<()> {
+    let mut config = Config::new();
+    config.set_quiet(options.quiet);
+    config.load_cache()?;
+
+    let path = env::current_dir()?.join(path);
+    let dir = path
+        .parent()
+        .context("the path of the module has no parent")?;
+    let input = tempfile::Builder::new()
+        .suffix(".wasm")
+        .tempfile_in(dir)
+        .context("failed to create temporary file")?;
+    fs::copy(&path, input.path()).context(format!("failed to read `{}`", path.display()))?;
+    crate::process_wasm(&path, input.path(), &path, options, &config)
+        .with_context(|| format!("failed to process wasm at `{}`", path.display()))?;
+    Ok(())
+}
+
+/// Makes sure that the wasix toolchain is installed and linked with `rustup`,
+/// and returns it. Like `cargo wasix build` this downloads the release in
+/// `WASIX_TOOLCHAIN_VERSION` if it is missing, or otherwise the latest one.
+pub fn ensure_toolchain(is64bit: bool) -> Result<RustupToolchain> {
+    let mut config = Config::new();
+    config.load_cache()?;
+    toolchain::ensure_toolchain(&config, is64bit)
+}
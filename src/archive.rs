@@ -0,0 +1,153 @@
+//! Extraction of the archives that toolchains and tools are distributed in.
+//!
+//! Gzip compressed tarballs are extracted in process, the other formats are
+//! handed to the `tar` and `unzip` commands.
+
+use crate::utils::CommandExt;
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Format of an archive, detected from its file name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    TarGz,
+    TarXz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// All formats, in order of preference when a release ships several.
+    pub const ALL: [ArchiveFormat; 4] = [
+        ArchiveFormat::TarGz,
+        ArchiveFormat::TarXz,
+        ArchiveFormat::TarZst,
+        ArchiveFormat::Zip,
+    ];
+
+    /// Detects the format from the extension of `name`, which may also be a
+    /// URL.
+    pub fn from_file_name(name: &str) -> Option<ArchiveFormat> {
+        ArchiveFormat::ALL.into_iter().find(|format| {
+            format
+                .extensions()
+                .iter()
+                .any(|extension| name.ends_with(extension))
+        })
+    }
+
+    /// Canonical extension, including the leading `.`.
+    pub fn extension(self) -> &'static str {
+        self.extensions()[0]
+    }
+
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            ArchiveFormat::TarGz => &[".tar.gz", ".tgz"],
+            ArchiveFormat::TarXz => &[".tar.xz", ".txz"],
+            ArchiveFormat::TarZst => &[".tar.zst", ".tzst"],
+            ArchiveFormat::Zip => &[".zip"],
+        }
+    }
+}
+
+/// Extracts the archive read from `reader` into `out_dir`.
+pub fn extract(reader: impl Read, format: ArchiveFormat, out_dir: &Path) -> Result<()> {
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("failed to create directory `{}`", out_dir.display()))?;
+
+    if format == ArchiveFormat::TarGz {
+        let decoder = flate2::read::GzDecoder::new(reader);
+        return tar::Archive::new(decoder)
+            .unpack(out_dir)
+            .with_context(|| format!("failed to unpack archive to `{}`", out_dir.display()));
+    }
+
+    // The external tools need a file to read from.
+    let mut file = tempfile::Builder::new()
+        .suffix(format.extension())
+        .tempfile_in(out_dir.parent().unwrap_or(out_dir))
+        .context("failed to create temporary archive file")?;
+    io::copy(&mut { reader }, &mut file).context("failed to download archive")?;
+    let archive_path = file.path();
+
+    let mut cmd = match format {
+        ArchiveFormat::TarGz => unreachable!(),
+        ArchiveFormat::TarXz => tar_command(&["xJf"], archive_path, out_dir),
+        ArchiveFormat::TarZst => tar_command(&["--zstd", "-xf"], archive_path, out_dir),
+        // Windows ships a `tar` that also extracts zip files, other platforms
+        // usually have `unzip`.
+        ArchiveFormat::Zip if cfg!(windows) => tar_command(&["-xf"], archive_path, out_dir),
+        ArchiveFormat::Zip => {
+            let mut cmd = Command::new("unzip");
+            cmd.arg("-q").arg(archive_path).arg("-d").arg(out_dir);
+            cmd
+        }
+    };
+    cmd.run()
+        .with_context(|| format!("failed to unpack archive to `{}`", out_dir.display()))
+}
+
+fn tar_command(args: &[&str], archive_path: &Path, out_dir: &Path) -> Command {
+    let mut cmd = Command::new("tar");
+    cmd.args(args).arg(archive_path).arg("-C").arg(out_dir);
+    cmd
+}
+
+/// Returns all files below `dir`, recursively.
+pub fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in
+            fs::read_dir(&dir).with_context(|| format!("failed to read `{}`", dir.display()))?
+        {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_format_from_file_name() {
+        use ArchiveFormat::*;
+        let tests = [
+            ("rust-toolchain-x86_64-unknown-linux-gnu.tar.gz", Some(TarGz)),
+            ("wasix-libc.tgz", Some(TarGz)),
+            ("wasix-libc.tar.xz", Some(TarXz)),
+            ("rust-toolchain-aarch64-apple-darwin.tar.zst", Some(TarZst)),
+            ("rust-toolchain-x86_64-pc-windows-msvc.zip", Some(Zip)),
+            (
+                "https://github.com/WebAssembly/binaryen/releases/download/version_117/binaryen-version_117-x86_64-linux.tar.gz",
+                Some(TarGz),
+            ),
+            ("wasix-libc.tar", None),
+            ("wasix-libc.gz", None),
+            ("wasix-libc.tar.gz.sha256", None),
+            ("", None),
+        ];
+        for (name, expected) in tests {
+            assert_eq!(ArchiveFormat::from_file_name(name), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_archive_format_extension_round_trips() {
+        for format in ArchiveFormat::ALL {
+            let name = format!("asset{}", format.extension());
+            assert_eq!(ArchiveFormat::from_file_name(&name), Some(format));
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Wrapping the post-processed core modules into WASI Preview 2 components
+//! with `component = true` or `--component`.
+//!
+//! `wasm-tools component new` adapts the WASI Preview 1 imports of a module
+//! to the component model with the WASI adapter of wasmtime. Both are
+//! downloaded on demand like `wasm-opt`.
+
+use crate::config::Config;
+use crate::tool_path::ToolPath;
+use crate::utils;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The `wasm-tools` release that is downloaded, unless `WASM_TOOLS` is set.
+pub const WASM_TOOLS_VERSION: &str = "1.219.1";
+
+/// The wasmtime release the WASI adapter is downloaded from, unless
+/// `WASI_ADAPTER` is set.
+pub const WASI_ADAPTER_VERSION: &str = "v25.0.2";
+
+/// The adapter of a module with a `main` function, and of any other module,
+/// which exports functions for the host to call.
+const COMMAND_ADAPTER: &str = "wasi_snapshot_preview1.command.wasm";
+const REACTOR_ADAPTER: &str = "wasi_snapshot_preview1.reactor.wasm";
+
+/// Returns where the component of the module at `wasm` is written.
+pub fn component_path(wasm: &Path) -> PathBuf {
+    wasm.with_extension("component.wasm")
+}
+
+/// Wraps the module at `wasm` into a component next to it, which is
+/// validated by `wasm-tools`, and returns its path. `bin` selects the adapter
+/// of commands over the one of reactors.
+pub fn new_component(wasm: &Path, bin: bool, config: &Config) -> Result<PathBuf> {
+    // The adapter rejects shared memories, which modules compiled with
+    // `+atomics` have.
+    let module = fs::read(wasm).with_context(|| format!("failed to read `{}`", wasm.display()))?;
+    if has_shared_memory(&module)? {
+        bail!(
+            "`{}` has a shared memory, which the WASI adapter doesn't support, set \
+             `atomics = false` in `[package.metadata.wasix]` to build components",
+            wasm.display()
+        );
+    }
+    let adapter = if bin {
+        COMMAND_ADAPTER
+    } else {
+        REACTOR_ADAPTER
+    };
+    let adapter = wasi_adapter(adapter, config)?;
+    let component = component_path(wasm);
+    let wasm_tools = config.get_wasm_tools(WASM_TOOLS_VERSION);
+
+    let mut cmd = Command::new(wasm_tools.bin_path());
+    cmd.arg("component")
+        .arg("new")
+        .arg(wasm)
+        .arg("--adapt")
+        .arg(format!("wasi_snapshot_preview1={}", adapter.display()))
+        .arg("-o")
+        .arg(&component);
+    crate::run_or_download(
+        wasm_tools.bin_path(),
+        wasm_tools.is_overridden(),
+        &mut cmd,
+        config,
+        || install_wasm_tools(&wasm_tools, config),
+    )
+    .context(
+        "`wasm-tools component new` failed, note that the WASI adapter only provides \
+         WASI Preview 1, so modules that import wasix functions can't be components",
+    )?;
+
+    config.status(
+        "Component",
+        &format!(
+            "{} ({} bytes)",
+            component.display(),
+            fs::metadata(&component)?.len()
+        ),
+    );
+    Ok(component)
+}
+
+/// Returns whether the module `wasm` defines or imports a shared memory.
+fn has_shared_memory(wasm: &[u8]) -> Result<bool> {
+    use wasmparser::{ImportSectionEntryType, MemoryType, Payload};
+
+    let is_shared = |memory: MemoryType| match memory {
+        MemoryType::M32 { shared, .. } | MemoryType::M64 { shared, .. } => shared,
+    };
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(imports) => {
+                for import in imports {
+                    if let ImportSectionEntryType::Memory(memory) = import?.ty {
+                        if is_shared(memory) {
+                            return Ok(true);
+                        }
+                    }
+                }
+            }
+            Payload::MemorySection(memories) => {
+                for memory in memories {
+                    if is_shared(memory?) {
+                        return Ok(true);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+/// Returns the path of the WASI adapter named `file`, downloading it if
+/// needed.
+fn wasi_adapter(file: &str, config: &Config) -> Result<PathBuf> {
+    let (path, is_overridden) = config.get_wasi_adapter(WASI_ADAPTER_VERSION, file);
+    if is_overridden {
+        if !path.exists() {
+            bail!(
+                "the WASI adapter at `{}`, set in $WASI_ADAPTER, doesn't exist",
+                path.display()
+            );
+        }
+        return Ok(path);
+    }
+    install_wasi_adapter(&path, config)?;
+    Ok(path)
+}
+
+/// Downloads the WASI adapter to `path`, unless it is there already.
+fn install_wasi_adapter(path: &Path, config: &Config) -> Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    if config.no_auto_download || config.is_offline {
+        let var = if config.no_auto_download {
+            "WASIX_NO_AUTO_DOWNLOAD"
+        } else {
+            "CARGO_WASIX_OFFLINE"
+        };
+        bail!(
+            "the WASI adapter `{}` isn't downloaded because ${var} is set; point \
+             $WASI_ADAPTER at one, run `cargo wasix self fetch-tools` beforehand or \
+             unset ${var}",
+            path.display()
+        );
+    }
+
+    let _flock = utils::flock(&config.tools_root().join("downloading"));
+    if path.exists() {
+        return Ok(());
+    }
+    let file = path.file_name().unwrap().to_string_lossy();
+    let url = format!(
+        "https://github.com/bytecodealliance/wasmtime/releases/download/\
+         {WASI_ADAPTER_VERSION}/{file}"
+    );
+    config.status(
+        "Downloading",
+        &format!("WASI adapter {file} {WASI_ADAPTER_VERSION}"),
+    );
+    config.verbose(|| config.status("Get", &url));
+
+    let bytes = utils::get(&url, crate::DOWNLOAD_TIMEOUT)?
+        .bytes()
+        .with_context(|| format!("failed to download {url}"))?;
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create directory `{}`", dir.display()))?;
+    // Written to a temporary file first, so that an interrupted download
+    // isn't mistaken for the adapter.
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("failed to create a file in `{}`", dir.display()))?;
+    tmp.write_all(&bytes)?;
+    tmp.persist(path)
+        .with_context(|| format!("failed to write `{}`", path.display()))?;
+    Ok(())
+}
+
+/// Downloads `wasm-tools` and both WASI adapters unless they are overridden
+/// or cached already, for `cargo wasix self fetch-tools`, and returns the
+/// paths that were downloaded to.
+pub fn fetch(config: &Config) -> Result<Vec<(&'static str, PathBuf)>> {
+    let mut fetched = Vec::new();
+    let wasm_tools = config.get_wasm_tools(WASM_TOOLS_VERSION);
+    if wasm_tools.is_overridden() {
+        config.info("$WASM_TOOLS is set, so `wasm-tools` isn't downloaded");
+    } else {
+        install_wasm_tools(&wasm_tools, config)?;
+        fetched.push(("wasm-tools", wasm_tools.bin_path().to_path_buf()));
+    }
+    for file in [COMMAND_ADAPTER, REACTOR_ADAPTER] {
+        let (path, is_overridden) = config.get_wasi_adapter(WASI_ADAPTER_VERSION, file);
+        if is_overridden {
+            config.info("$WASI_ADAPTER is set, so the WASI adapters aren't downloaded");
+            break;
+        }
+        install_wasi_adapter(&path, config)?;
+        fetched.push(("WASI adapter", path));
+    }
+    Ok(fetched)
+}
+
+/// Downloads the `wasm-tools` release for this platform to `path`.
+fn install_wasm_tools(path: &ToolPath, config: &Config) -> Result<()> {
+    let target = if cfg!(target_os = "linux") && cfg!(target_arch = "x86_64") {
+        "x86_64-linux.tar.gz"
+    } else if cfg!(target_os = "linux") && cfg!(target_arch = "aarch64") {
+        "aarch64-linux.tar.gz"
+    } else if cfg!(target_os = "macos") && cfg!(target_arch = "x86_64") {
+        "x86_64-macos.tar.gz"
+    } else if cfg!(target_os = "macos") && cfg!(target_arch = "aarch64") {
+        "aarch64-macos.tar.gz"
+    } else if cfg!(target_os = "windows") && cfg!(target_arch = "x86_64") {
+        "x86_64-windows.zip"
+    } else {
+        bail!(
+            "no precompiled binaries of `wasm-tools` are available for this \
+             platform, you'll want to set `$WASM_TOOLS` to a preinstalled \
+             `wasm-tools` command"
+        )
+    };
+    let url = format!(
+        "https://github.com/bytecodealliance/wasm-tools/releases/download/\
+         v{WASM_TOOLS_VERSION}/wasm-tools-{WASM_TOOLS_VERSION}-{target}"
+    );
+    let (base_path, sub_paths) = path.cache_paths().unwrap();
+    crate::download(
+        &url,
+        &format!("precompiled wasm-tools {WASM_TOOLS_VERSION}"),
+        base_path,
+        sub_paths,
+        config,
+    )
+}
@@ -1,29 +1,97 @@
-use std::path::PathBuf;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
 
+use crate::utils::CommandExt;
 use crate::{tool_path::ToolPath, Cache};
 use anyhow::Result;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
+/// What `cargo wasix` is busy with, which determines the exit code if it
+/// fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Finding the toolchain and tools, reading the manifest and so on.
+    Setup,
+    /// Running `cargo`, which includes compile errors.
+    Cargo,
+    /// Post-processing the produced `*.wasm` files.
+    PostProcess,
+    /// Running programs, whose exit codes are passed through.
+    Run,
+}
+
+impl Stage {
+    /// Exit code for errors of this stage, unless the exit code of a program
+    /// is passed through.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Stage::Cargo | Stage::Run => 1,
+            Stage::Setup => 2,
+            Stage::PostProcess => 3,
+        }
+    }
+}
+
 pub struct Config {
     cache: Option<Cache>,
+    stage: Cell<Stage>,
+    tools_dir: OnceCell<PathBuf>,
+    /// Outputs of `cargo metadata` during this run, by their arguments.
+    metadata: RefCell<HashMap<Vec<OsString>, Rc<str>>>,
     verbose: bool,
+    very_verbose: bool,
+    quiet: bool,
     choice: ColorChoice,
     pub is_offline: bool,
+    pub dry_run: bool,
+    pub no_auto_download: bool,
+    pub frozen_toolchain: bool,
+    pub relink_toolchain: bool,
+    pub binaryen_version: Option<String>,
+    pub local_tools: bool,
 }
 
 impl Config {
     pub fn new() -> Config {
         Config {
             cache: None,
+            tools_dir: OnceCell::new(),
+            metadata: RefCell::new(HashMap::new()),
+            stage: Cell::new(Stage::Setup),
             verbose: false,
+            very_verbose: false,
+            quiet: false,
             choice: if atty::is(atty::Stream::Stderr) {
                 ColorChoice::Auto
             } else {
                 ColorChoice::Never
             },
             // Offline env var disables toolchain downloads and update checks.
-            is_offline: std::env::var("CARGO_WASIX_OFFLINE")
-                .map_or(false, |v| v == "1" || v == "true"),
+            is_offline: std::env::var("CARGO_WASIX_OFFLINE").is_ok_and(|v| v == "1" || v == "true"),
+            // Dry runs report what toolchain downloads/builds would do.
+            dry_run: std::env::var("WASIX_DRY_RUN").is_ok_and(|v| v == "1" || v == "true"),
+            // Tools like `wasm-opt` must already be installed.
+            no_auto_download: std::env::var("WASIX_NO_AUTO_DOWNLOAD")
+                .is_ok_and(|v| v == "1" || v == "true"),
+            // The linked toolchain must be the release in
+            // `WASIX_TOOLCHAIN_VERSION`, see `--frozen-toolchain`.
+            frozen_toolchain: std::env::var("WASIX_FROZEN_TOOLCHAIN")
+                .is_ok_and(|v| v == "1" || v == "true"),
+            // The toolchain in `WASIX_TOOLCHAIN_DIR`, a local build, is linked
+            // again on every run.
+            relink_toolchain: std::env::var("WASIX_RELINK_TOOLCHAIN")
+                .is_ok_and(|v| v == "1" || v == "true"),
+            // Release tag of binaryen to download `wasm-opt` from.
+            binaryen_version: std::env::var("WASIX_BINARYEN_VERSION")
+                .ok()
+                .filter(|v| !v.is_empty()),
+            // Tools are downloaded into the target directory instead of the
+            // shared cache, see `--local-tools`.
+            local_tools: std::env::var("WASIX_LOCAL_TOOLS").is_ok_and(|v| v == "1" || v == "true"),
         }
     }
 
@@ -57,8 +125,14 @@ impl Config {
         Ok(dir)
     }
 
+    /// Directory that downloaded toolchains are installed into. They take
+    /// several GiB, so it can be moved with `WASIX_TOOLCHAINS_ROOT`.
     pub fn toolchain_dir() -> Result<PathBuf, anyhow::Error> {
-        Self::data_dir().map(|d| d.join("toolchains"))
+        match std::env::var_os("WASIX_TOOLCHAINS_ROOT").filter(|dir| !dir.is_empty()) {
+            // Made absolute, as it is linked with `rustup`.
+            Some(dir) => Ok(std::env::current_dir()?.join(dir)),
+            None => Self::data_dir().map(|d| d.join("toolchains")),
+        }
     }
 
     fn lockfile_path() -> Result<PathBuf, anyhow::Error> {
@@ -79,6 +153,46 @@ impl Config {
         self.cache.as_ref().expect("cache not loaded yet")
     }
 
+    /// Returns the directory downloaded tools such as `wasm-opt` are stored
+    /// in, which is the cache unless a project-local directory was set.
+    pub fn tools_root(&self) -> &Path {
+        self.tools_dir
+            .get()
+            .map_or_else(|| self.cache().root(), PathBuf::as_path)
+    }
+
+    /// Sets the project-local tools directory, which is only known once the
+    /// manifest was read.
+    pub fn set_tools_dir(&self, dir: PathBuf) {
+        drop(self.tools_dir.set(dir));
+    }
+
+    /// Runs `cmd`, a `cargo metadata` command, unless it already ran with the
+    /// same arguments during this run.
+    pub fn cargo_metadata(&self, cmd: &mut Command) -> Result<Rc<str>> {
+        let key = cmd.get_args().map(|arg| arg.to_os_string()).collect();
+        if let Some(output) = self.metadata.borrow().get(&key) {
+            return Ok(output.clone());
+        }
+        let output = Rc::<str>::from(cmd.capture_stdout()?);
+        self.metadata.borrow_mut().insert(key, output.clone());
+        Ok(output)
+    }
+
+    /// Forgets the outputs of `cargo metadata`, which are outdated once
+    /// `Cargo.lock` changed.
+    pub fn forget_cargo_metadata(&self) {
+        self.metadata.borrow_mut().clear();
+    }
+
+    pub fn stage(&self) -> Stage {
+        self.stage.get()
+    }
+
+    pub fn set_stage(&self, stage: Stage) {
+        self.stage.set(stage);
+    }
+
     pub fn is_verbose(&self) -> bool {
         self.verbose
     }
@@ -93,7 +207,33 @@ impl Config {
         self.verbose = verbose;
     }
 
+    /// Runs `f` only at the `-vv` verbosity level.
+    pub fn very_verbose(&self, f: impl FnOnce()) {
+        if self.very_verbose {
+            f();
+        }
+    }
+
+    pub fn set_very_verbose(&mut self, very_verbose: bool) {
+        self.very_verbose = very_verbose;
+        if very_verbose {
+            self.verbose = true;
+        }
+    }
+
+    /// Suppresses status and info messages, mirroring Cargo's `--quiet`.
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    pub fn is_quiet(&self) -> bool {
+        self.quiet
+    }
+
     pub fn status(&self, name: &str, rest: &str) {
+        if self.quiet {
+            return;
+        }
         let mut shell = StandardStream::stderr(self.choice);
         drop(shell.set_color(ColorSpec::new().set_fg(Some(Color::Green)).set_bold(true)));
         eprint!("{:>12}", name);
@@ -121,6 +261,9 @@ impl Config {
     }
 
     pub fn info(&self, msg: &str) {
+        if self.quiet {
+            return;
+        }
         let mut shell = StandardStream::stderr(self.choice);
         drop(shell.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)).set_bold(true)));
         eprint!("info");
@@ -149,43 +292,117 @@ impl Config {
         if let Some(s) = std::env::var_os(tool.to_uppercase().replace('-', "_")) {
             (s.into(), true)
         } else {
-            let mut cache_path = self.cache().root().join(tool);
-            if let Some(v) = version {
-                cache_path.push(v);
-                cache_path.push(tool)
-            }
-            (cache_path, false)
+            (Self::tool_dir(self.tools_root(), tool, version), false)
+        }
+    }
+
+    /// Returns the directory a `version` of `tool` is stored in below `root`.
+    fn tool_dir(root: &Path, tool: &str, version: Option<&str>) -> PathBuf {
+        let mut path = root.join(tool);
+        if let Some(v) = version {
+            path.push(v);
+            path.push(tool)
         }
+        path
     }
 
     /// Get the path to our `wasm-opt`, which may be the cache path where it
     /// should be download to if missing, and whether the path has been
     /// overridden.
     ///
-    /// Overridable via setting the `WASM_OPT=path/to/wasm-opt` env var.
+    /// Overridable via setting the `WASM_OPT=path/to/wasm-opt` env var. A
+    /// binaryen release other than the default one is cached separately.
+    ///
+    /// Project-local tools can't be downloaded when that is disabled, so the
+    /// `wasm-opt` in the shared cache is used instead if there is one, such as
+    /// one downloaded by `cargo wasix self fetch-tools`.
     pub fn get_wasm_opt(&self) -> ToolPath {
-        let (path, is_overridden) = self.get_tool("wasm-opt", None);
-        if !is_overridden {
-            let mut bin = ["bin", "wasm-opt"].iter().collect::<PathBuf>();
-            bin.set_extension(std::env::consts::EXE_EXTENSION);
-
-            let bin_path = path.join(&bin);
-            let mut sub_paths = vec![bin];
-
-            // wasm-opt on MacOS requires a dylib to execute
-            if cfg!(target_os = "macos") {
-                let mut dylib = ["lib", "libbinaryen"].iter().collect::<PathBuf>();
-                dylib.set_extension(std::env::consts::DLL_EXTENSION);
-                sub_paths.push(dylib);
+        let version = self.binaryen_version.as_deref();
+        let (path, is_overridden) = self.get_tool("wasm-opt", version);
+        if is_overridden {
+            return ToolPath::Overridden(path);
+        }
+        let tool = Self::wasm_opt_in(path);
+        if (self.no_auto_download || self.is_offline) && !tool.bin_path().exists() {
+            let shared =
+                Self::wasm_opt_in(Self::tool_dir(self.cache().root(), "wasm-opt", version));
+            if shared.bin_path().exists() {
+                return shared;
             }
+        }
+        tool
+    }
 
-            ToolPath::Cached {
-                bin_path,
-                base: path,
-                sub_paths,
+    /// Get the path to `wasm-tools`, which builds components, like
+    /// [`Config::get_wasm_opt`]. Overridable via `WASM_TOOLS=path/to/wasm-tools`.
+    pub fn get_wasm_tools(&self, version: &str) -> ToolPath {
+        let (path, is_overridden) = self.get_tool("wasm-tools", Some(version));
+        if is_overridden {
+            return ToolPath::Overridden(path);
+        }
+        let tool = Self::wasm_tools_in(path);
+        if (self.no_auto_download || self.is_offline) && !tool.bin_path().exists() {
+            let shared = Self::wasm_tools_in(Self::tool_dir(
+                self.cache().root(),
+                "wasm-tools",
+                Some(version),
+            ));
+            if shared.bin_path().exists() {
+                return shared;
             }
-        } else {
-            ToolPath::Overridden(path)
+        }
+        tool
+    }
+
+    fn wasm_tools_in(path: PathBuf) -> ToolPath {
+        let mut bin = PathBuf::from("wasm-tools");
+        bin.set_extension(std::env::consts::EXE_EXTENSION);
+        ToolPath::Cached {
+            bin_path: path.join(&bin),
+            base: path,
+            sub_paths: vec![bin],
+        }
+    }
+
+    /// Get the path to the WASI adapter of a `version` of wasmtime that
+    /// components are built with, and whether it has been overridden via
+    /// `WASI_ADAPTER=path/to/adapter.wasm`. `file` is the name of the
+    /// adapter, which differs for commands and reactors.
+    pub fn get_wasi_adapter(&self, version: &str, file: &str) -> (PathBuf, bool) {
+        let (path, is_overridden) = self.get_tool("wasi-adapter", Some(version));
+        if is_overridden {
+            return (path, true);
+        }
+        let path = path.with_file_name(file);
+        if (self.no_auto_download || self.is_offline) && !path.exists() {
+            let shared = Self::tool_dir(self.cache().root(), "wasi-adapter", Some(version))
+                .with_file_name(file);
+            if shared.exists() {
+                return (shared, false);
+            }
+        }
+        (path, false)
+    }
+
+    /// Returns where the files of a `wasm-opt` unpacked to `path` are.
+    fn wasm_opt_in(path: PathBuf) -> ToolPath {
+        let mut bin = ["bin", "wasm-opt"].iter().collect::<PathBuf>();
+        bin.set_extension(std::env::consts::EXE_EXTENSION);
+
+        let bin_path = path.join(&bin);
+        let mut sub_paths = vec![bin];
+
+        // wasm-opt on MacOS requires a dylib to execute
+        if cfg!(target_os = "macos") {
+            let mut dylib = ["lib", "libbinaryen"].iter().collect::<PathBuf>();
+            dylib.set_extension(std::env::consts::DLL_EXTENSION);
+            sub_paths.push(dylib);
+        }
+
+        ToolPath::Cached {
+            bin_path,
+            base: path,
+            sub_paths,
         }
     }
 }
@@ -1,14 +1,14 @@
 //! Module with check related to the dependencies.
 
 use crate::config::Config;
-use crate::utils::{self, CommandExt};
+use crate::utils::{self, CargoFlags, CommandExt};
 use anyhow::{bail, Context, Result};
 use std::collections::hash_map::{self, HashMap};
 use std::fmt::Write as _;
 use std::fs;
 use std::io;
 use std::io::{BufReader, BufWriter, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::Duration;
 
@@ -19,6 +19,11 @@ const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
 const KNOWN_INCOMPATIBLE_CRATES_URL: &str =
     "https://raw.githubusercontent.com/wasix-org/cargo-wasix/main/incompatible_crates/data.json";
 
+/// How often downloading the incompatible crates data is attempted before
+/// giving up, with a pause that doubles after each failed attempt.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
 /// Maximum age before updating the incompatible crate data.
 const MAX_CACHE_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60); // Roughly 30 days.
 
@@ -34,6 +39,25 @@ struct IncompatibleCrate {
     compatible_versions: Option<cargo_metadata::semver::VersionReq>,
     /// Replacement dependency that supports wasix.
     replacements: Vec<Replacement>,
+    /// Short explanation of why the crate doesn't support wasix, shown with
+    /// `--explain`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// Link to more details, such as an upstream issue.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+impl IncompatibleCrate {
+    /// Returns the reason and link of the crate, if there are any.
+    fn explanation(&self) -> Option<String> {
+        match (&self.reason, &self.url) {
+            (Some(reason), Some(url)) => Some(format!("{reason} (see {url})")),
+            (Some(reason), None) => Some(reason.clone()),
+            (None, Some(url)) => Some(format!("see {url}")),
+            (None, None) => None,
+        }
+    }
 }
 
 /// Replacement crate for an `IncompatibleCrate`.
@@ -46,21 +70,92 @@ struct Replacement {
     repo: String,
     /// Git branch to use.
     branch: Option<String>,
+    /// What the replacement changes compared to the original crate, shown
+    /// with `--explain`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+impl Replacement {
+    /// Returns the `[patch.crates-io]` line that replaces the crate `name`.
+    fn patch(&self, name: &str) -> String {
+        match &self.branch {
+            Some(branch) => format!(
+                "{name} = {{ git = \"{}\", branch = \"{branch}\" }}",
+                self.repo
+            ),
+            None => format!("{name} = {{ git = \"{}\" }}", self.repo),
+        }
+    }
 }
 
 fn known_incompatible_crates(config: &Config) -> Vec<IncompatibleCrate> {
     match read_known_incompatible_crates(config) {
         Ok(crates) => crates,
+        // A flaky network shouldn't stop the check, an outdated cached list,
+        // or else the bundled one, is still better than nothing.
         Err(err) => {
-            config.print_error(&err.context("not checking known incompatible crates"));
-            Vec::new()
+            let cached = known_incompatible_crates_path()
+                .and_then(|path| read_cached_incompatible_crates(&path).map(|c| (path, c)));
+            if let Ok((path, crates)) = cached {
+                if !config.is_quiet() {
+                    config.warn(&format!(
+                        "failed to get the list of known incompatible crates, using the \
+                         outdated one cached at {} instead: {err:#}",
+                        path.display()
+                    ));
+                }
+                return crates;
+            }
+            if !config.is_quiet() {
+                config.warn(&format!(
+                    "failed to get the list of known incompatible crates, using the one \
+                     bundled with cargo-wasix (from {}) instead: {err:#}",
+                    bundled_incompatible_crates_date()
+                ));
+            }
+            match bundled_incompatible_crates() {
+                Ok(crates) => crates,
+                Err(err) => {
+                    config.print_error(&err.context("not checking known incompatible crates"));
+                    Vec::new()
+                }
+            }
         }
     }
 }
 
+/// The list of known incompatible crates that was bundled with `cargo-wasix`
+/// when it was built.
+fn bundled_incompatible_crates() -> Result<Vec<IncompatibleCrate>> {
+    static INCLUDED_CRATES: &str = include_str!("../incompatible_crates/data.json");
+    serde_json::from_str(INCLUDED_CRATES).context("failed to deserialize incompatible crates")
+}
+
+fn bundled_incompatible_crates_date() -> &'static str {
+    option_env!("INCOMPATIBLE_CRATES_DATE").unwrap_or("an unknown date")
+}
+
+/// Returns where the downloaded list of known incompatible crates is cached.
+fn known_incompatible_crates_path() -> Result<PathBuf> {
+    Ok(Config::cache_dir()?.join("incompatible_crates.json"))
+}
+
+/// Downloads the list of known incompatible crates unless the cached one is
+/// recent enough, and returns where it is cached.
+pub fn fetch_known_incompatible_crates(config: &Config) -> Result<PathBuf> {
+    let path = known_incompatible_crates_path()?;
+    let is_recent = fs::metadata(&path)
+        .and_then(|m| m.modified())
+        .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age < MAX_CACHE_AGE));
+    if !is_recent {
+        download_known_incompatible_crates(config, &path)?;
+    }
+    Ok(path)
+}
+
 fn read_known_incompatible_crates(config: &Config) -> Result<Vec<IncompatibleCrate>> {
-    let mut path = Config::cache_dir()?;
-    path.push("incompatible_crates.json");
+    let path = known_incompatible_crates_path()?;
 
     let file = match fs::File::open(&path) {
         Ok(file) => file,
@@ -82,15 +177,29 @@ fn read_known_incompatible_crates(config: &Config) -> Result<Vec<IncompatibleCra
         .with_context(|| format!("failed to deserialize '{}'", path.display()))
 }
 
+/// Reads the cached list of known incompatible crates at `path`, however old
+/// it is.
+fn read_cached_incompatible_crates(path: &Path) -> Result<Vec<IncompatibleCrate>> {
+    let file =
+        fs::File::open(path).with_context(|| format!("failed to read '{}'", path.display()))?;
+    serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("failed to deserialize '{}'", path.display()))
+}
+
 fn download_known_incompatible_crates(
     config: &Config,
     path: &Path,
 ) -> Result<Vec<IncompatibleCrate>> {
     if config.is_offline {
-        static INCLUDED_CRATES: &str = include_str!("../incompatible_crates/data.json");
+        if !config.is_quiet() {
+            config.warn(&format!(
+                "CARGO_WASIX_OFFLINE is set, so the incompatible crates list bundled with \
+                 cargo-wasix (from {}) is used, which may be out of date",
+                bundled_incompatible_crates_date()
+            ));
+        }
         // NOTE: we don't cache this file as this may be really outdated.
-        return serde_json::from_str(INCLUDED_CRATES)
-            .with_context(|| format!("failed to deserialize incompatible crates"));
+        return bundled_incompatible_crates();
     }
 
     let url = KNOWN_INCOMPATIBLE_CRATES_URL;
@@ -98,10 +207,34 @@ fn download_known_incompatible_crates(
     config.status("Downloading", "known incompatible crates list");
     config.verbose(|| config.status("Get", url));
 
-    let response = utils::get(url, DOWNLOAD_TIMEOUT)?;
-    let incompatible_crates = response
-        .json()
-        .with_context(|| format!("failed to deserialize incompatible crates"))?;
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut attempt = 1;
+    let incompatible_crates: Vec<IncompatibleCrate> = loop {
+        let result = utils::get(url, DOWNLOAD_TIMEOUT).and_then(|response| {
+            response
+                .json()
+                .context("failed to deserialize incompatible crates")
+        });
+        match result {
+            Ok(crates) => break crates,
+            Err(err) if attempt < DOWNLOAD_ATTEMPTS && is_transient(&err) => {
+                config.verbose(|| {
+                    config.status(
+                        "Retrying",
+                        &format!("in {}s after: {err:#}", delay.as_secs()),
+                    )
+                });
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("failed to download known incompatible crates from {url}")
+                })
+            }
+        }
+    };
 
     let dir = path.parent().unwrap_or(path);
     fs::create_dir_all(dir)
@@ -125,15 +258,39 @@ fn download_known_incompatible_crates(
     Ok(incompatible_crates)
 }
 
+/// Whether a failed download may succeed when it's retried, such as after a
+/// timeout or a dropped connection, as opposed to an error status.
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_timeout() || e.is_connect() || e.is_request() || e.is_body())
+    })
+}
+
 /// Check the dependencies with well-known incompatible crates.
-pub fn check(config: &Config, target: &str) -> Result<()> {
-    let metadata = Command::new("cargo")
-        .arg("metadata")
+///
+/// If `--locked` or `--frozen` is part of `flags`, `Cargo.lock` is never
+/// modified.
+///
+/// Only the dependencies used on `target` are checked, which must be the
+/// target that is built for. With `explain` the reasons why the crates are
+/// incompatible are included.
+pub fn check(config: &Config, target: &str, flags: CargoFlags, explain: bool) -> Result<()> {
+    config.verbose(|| config.status("Checking", &format!("dependencies for `{target}`")));
+    let manifest = workspace_manifest(config, flags)?;
+    // `cargo metadata` may resolve differently, or not at all when offline,
+    // without a lock file, so make sure there is one first.
+    ensure_lockfile(config, flags, &manifest)?;
+
+    let mut cmd = Command::new("cargo");
+    cmd.arg("metadata")
         .arg("--format-version=1")
         // Only resolve dependencies for our target.
         .arg("--filter-platform")
-        .arg(target)
-        .capture_stdout()?;
+        .arg(target);
+    flags.apply(&mut cmd);
+    let metadata = config.cargo_metadata(&mut cmd)?;
     let metadata = serde_json::from_str::<cargo_metadata::Metadata>(&metadata)
         .context("failed to deserialize `cargo metadata`")?;
 
@@ -169,8 +326,23 @@ pub fn check(config: &Config, target: &str) -> Result<()> {
         }
     }
 
-    let mut found_incompatible_crates = Vec::new();
     let known_incompatible_crates = known_incompatible_crates(config);
+    // Cargo rejects a second patch for the same crate, so existing ones have
+    // to be replaced instead.
+    let existing_patches = existing_patches(&manifest).unwrap_or_else(|err| {
+        config.print_error(&err.context("not checking existing patches"));
+        toml::value::Table::new()
+    });
+    warn_ineffective_patches(
+        config,
+        &manifest,
+        &metadata,
+        &dependencies,
+        &existing_patches,
+        &known_incompatible_crates,
+    );
+
+    let mut found_incompatible_crates = Vec::new();
     for incompatible_crate in &known_incompatible_crates {
         if let Some(pkg_id) = dependencies.get(&incompatible_crate.name) {
             let Some(pkg) = metadata.packages.iter().find(|pkg| pkg.id == **pkg_id) else {
@@ -183,6 +355,14 @@ pub fn check(config: &Config, target: &str) -> Result<()> {
                     continue;
                 }
             }
+            // Patched with a replacement that isn't used (yet), which
+            // `warn_ineffective_patches` explains.
+            if existing_patches
+                .get(&incompatible_crate.name)
+                .is_some_and(is_wasix_fork)
+            {
+                continue;
+            }
 
             // Filter out versions that are known to compatible.
             if let Some(versions) = &incompatible_crate.compatible_versions {
@@ -205,43 +385,91 @@ pub fn check(config: &Config, target: &str) -> Result<()> {
         }
         msg.truncate(msg.len() - 2); // Remove last `, `.
 
-        msg.push_str("\n\nTo fix this add the following to 'Cargo.toml':\n");
-        msg.push_str("[patch.crates-io]\n");
+        let mut patches = String::new();
+        let mut conflicts = String::new();
         let mut no_replacements = Vec::new();
-        for (incompatible_crate, need_version) in found_incompatible_crates {
+        let mut explained = false;
+        for (incompatible_crate, need_version) in &found_incompatible_crates {
             let replacement = incompatible_crate
                 .replacements
                 .iter()
                 .find(|replacement| replacement.version.matches(need_version));
+            let explanation = incompatible_crate
+                .explanation()
+                .filter(|_| explain)
+                .into_iter()
+                .chain(
+                    replacement
+                        .and_then(|r| r.note.as_ref())
+                        .filter(|_| explain)
+                        .map(|note| format!("the replacement {note}")),
+                )
+                .collect::<Vec<_>>();
+            explained |= !explanation.is_empty();
 
             match replacement {
                 Some(replacement) => {
-                    write!(
-                        &mut msg,
-                        "{} = {{ git = \"{}\"",
-                        incompatible_crate.name, replacement.repo,
-                    )?;
-                    if let Some(branch) = replacement.branch.as_ref() {
-                        writeln!(&mut msg, ", branch = \"{branch}\" }}")?;
-                    } else {
-                        msg.push_str(" }\n");
-                    };
+                    let patch = replacement.patch(&incompatible_crate.name);
+                    match existing_patches.get(&incompatible_crate.name) {
+                        Some(existing) => {
+                            writeln!(
+                                &mut conflicts,
+                                "* {} is patched with {}, replace it with: {patch}",
+                                incompatible_crate.name,
+                                inline_toml(existing),
+                            )?;
+                            for line in &explanation {
+                                writeln!(&mut conflicts, "  {line}")?;
+                            }
+                        }
+                        None => {
+                            // As comments the lines can be copied along.
+                            for line in &explanation {
+                                writeln!(&mut patches, "# {line}")?;
+                            }
+                            writeln!(&mut patches, "{patch}")?;
+                        }
+                    }
                 }
                 None => no_replacements.push((
                     &incompatible_crate.name,
                     &incompatible_crate.replacements,
                     need_version,
+                    explanation,
                 )),
             }
         }
 
-        msg.push_str(
-            "\nYou might have to run `cargo update` to ensure the dependencies are used properly",
-        );
+        if !patches.is_empty() {
+            msg.push_str("\n\nTo fix this add the following to 'Cargo.toml':\n");
+            msg.push_str("[patch.crates-io]\n");
+            msg.push_str(&patches);
+        }
+        if !conflicts.is_empty() {
+            msg.push_str(
+                "\n\nThe following crates already have a `[patch.crates-io]` entry in \
+                 'Cargo.toml' that doesn't use a replacement supporting wasix:\n",
+            );
+            msg.push_str(&conflicts);
+        }
+        if patches.is_empty() && conflicts.is_empty() {
+            msg.push('\n');
+        }
+
+        match flags.lock {
+            Some(flag) => write!(
+                &mut msg,
+                "\n`Cargo.lock` has to be updated to use the patches, which `{flag}` \
+                 doesn't allow, so run the command without `{flag}` once"
+            )?,
+            None => msg.push_str(
+                "\nYou might have to run `cargo update` to ensure the dependencies are used properly",
+            ),
+        }
 
         if !no_replacements.is_empty() {
             msg.push_str("\nNo replacements found for the following dependencies:\n");
-            for (name, replacements, version) in no_replacements {
+            for (name, replacements, version, explanation) in no_replacements {
                 write!(&mut msg, "* {name} v{version}, ")?;
                 if !replacements.is_empty() {
                     msg.push_str("known replacement versions: ");
@@ -253,13 +481,229 @@ pub fn check(config: &Config, target: &str) -> Result<()> {
                 } else {
                     msg.push_str("no replacements known\n");
                 }
+                for line in explanation {
+                    writeln!(&mut msg, "  {line}")?;
+                }
             }
         }
 
+        let has_explanation = found_incompatible_crates
+            .iter()
+            .any(|(c, _)| c.explanation().is_some());
+        if !explain && has_explanation {
+            msg.push_str("\nRun with `--explain` to see why these crates are incompatible");
+        } else if explain && !explained {
+            msg.push_str("\nThere are no explanations for these crates yet");
+        }
+
         bail!(msg)
     }
 }
 
+/// Returns the path of the workspace `Cargo.toml`.
+fn workspace_manifest(config: &Config, flags: CargoFlags) -> Result<PathBuf> {
+    #[derive(serde::Deserialize)]
+    struct CargoMetadata {
+        workspace_root: PathBuf,
+    }
+
+    let metadata = utils::workspace_metadata(config, flags)?;
+    let metadata = serde_json::from_str::<CargoMetadata>(&metadata)
+        .context("failed to deserialize `cargo metadata`")?;
+    Ok(metadata.workspace_root.join("Cargo.toml"))
+}
+
+/// Returns the `[patch.crates-io]` table of the workspace `manifest`.
+fn existing_patches(manifest: &Path) -> Result<toml::value::Table> {
+    let contents = fs::read_to_string(manifest)
+        .with_context(|| format!("failed to read '{}'", manifest.display()))?;
+    let mut manifest = toml::from_str::<toml::value::Table>(&contents)
+        .with_context(|| format!("failed to deserialize '{}'", manifest.display()))?;
+    let patches = manifest
+        .remove("patch")
+        .and_then(|patch| match patch {
+            toml::Value::Table(mut patch) => patch.remove("crates-io"),
+            _ => None,
+        })
+        .and_then(|patches| match patches {
+            toml::Value::Table(patches) => Some(patches),
+            _ => None,
+        });
+    Ok(patches.unwrap_or_default())
+}
+
+/// Warns about existing patches of known incompatible crates that don't take
+/// effect, which leaves the incompatible crate in place.
+///
+/// Cargo ignores patches whose version doesn't match the requirements of the
+/// dependents, and only mentions it among the rest of its output.
+fn warn_ineffective_patches(
+    config: &Config,
+    manifest: &Path,
+    metadata: &cargo_metadata::Metadata,
+    dependencies: &HashMap<&String, &cargo_metadata::PackageId>,
+    existing_patches: &toml::value::Table,
+    known_incompatible_crates: &[IncompatibleCrate],
+) {
+    let lockfile = manifest.with_file_name("Cargo.lock");
+    let unused = unused_patches(&lockfile).unwrap_or_else(|err| {
+        config.print_error(&err.context("not checking for unused patches"));
+        Vec::new()
+    });
+    for incompatible_crate in known_incompatible_crates {
+        let name = &incompatible_crate.name;
+        let Some(patch) = existing_patches.get(name) else {
+            continue;
+        };
+
+        let resolved = dependencies
+            .get(name)
+            .and_then(|pkg_id| metadata.packages.iter().find(|pkg| pkg.id == **pkg_id));
+
+        if let Some(unused) = unused.iter().find(|unused| unused.name == *name) {
+            let requirements = metadata
+                .packages
+                .iter()
+                .flat_map(|pkg| {
+                    pkg.dependencies
+                        .iter()
+                        .filter(|dep| dep.name == *name)
+                        .map(move |dep| (pkg, &dep.req))
+                })
+                .collect::<Vec<_>>();
+            let mismatches = requirements
+                .iter()
+                .filter(|(_, req)| !req.matches(&unused.version))
+                .map(|(pkg, req)| format!("{} v{} requires {req}", pkg.name, pkg.version))
+                .collect::<Vec<_>>();
+            // Only a replacement for a version that all dependents accept
+            // would take effect, such as the one Cargo resolved without the
+            // patch.
+            let replacement = resolved.and_then(|pkg| {
+                incompatible_crate.replacements.iter().find(|replacement| {
+                    replacement.version.matches(&pkg.version)
+                        && requirements
+                            .iter()
+                            .all(|(_, req)| req.matches(&pkg.version))
+                })
+            });
+            let reason = if mismatches.is_empty() {
+                "check that its version and features match what its dependents require".to_string()
+            } else {
+                let fix = match replacement {
+                    Some(replacement) => format!(
+                        "use a replacement that does instead: {}",
+                        replacement.patch(name)
+                    ),
+                    None => "use a version of the patch that does".to_string(),
+                };
+                format!(
+                    "its version {} doesn't match the requirements ({}), {fix}",
+                    unused.version,
+                    mismatches.join(", ")
+                )
+            };
+            config.warn(&format!(
+                "the `[patch.crates-io]` entry for `{name}` ({}) is ignored by cargo, so the \
+                 incompatible crate is still used: {reason}",
+                inline_toml(patch),
+            ));
+            continue;
+        }
+
+        // A patch that is used but isn't locked yet.
+        let is_fork = is_wasix_fork(patch);
+        if let Some(pkg) = resolved {
+            let uses_fork = pkg
+                .source
+                .as_ref()
+                .is_some_and(|source| source.repr.starts_with("git+https://github.com/wasix-org"));
+            if is_fork && !uses_fork {
+                config.warn(&format!(
+                    "`{name}` is patched with a wasix-org fork, but `Cargo.lock` still uses \
+                     v{}, run `cargo update -p {name}` to use the patch",
+                    pkg.version
+                ));
+            }
+        }
+    }
+}
+
+/// Returns whether the `[patch.crates-io]` entry `patch` points at a
+/// wasix-org fork.
+fn is_wasix_fork(patch: &toml::Value) -> bool {
+    patch
+        .get("git")
+        .and_then(|git| git.as_str())
+        .is_some_and(|git| git.starts_with("https://github.com/wasix-org"))
+}
+
+/// A `[patch]` entry that Cargo didn't use.
+#[derive(serde::Deserialize)]
+struct UnusedPatch {
+    name: String,
+    version: cargo_metadata::semver::Version,
+}
+
+/// Returns the `[[patch.unused]]` entries of `lockfile`.
+fn unused_patches(lockfile: &Path) -> Result<Vec<UnusedPatch>> {
+    #[derive(serde::Deserialize)]
+    struct Lockfile {
+        #[serde(default)]
+        patch: Patches,
+    }
+
+    #[derive(serde::Deserialize, Default)]
+    struct Patches {
+        #[serde(default)]
+        unused: Vec<UnusedPatch>,
+    }
+
+    let contents = fs::read_to_string(lockfile)
+        .with_context(|| format!("failed to read '{}'", lockfile.display()))?;
+    let lockfile = toml::from_str::<Lockfile>(&contents)
+        .with_context(|| format!("failed to deserialize '{}'", lockfile.display()))?;
+    Ok(lockfile.patch.unused)
+}
+
+/// Formats `value` on a single line, as patches are usually written.
+fn inline_toml(value: &toml::Value) -> String {
+    match value {
+        toml::Value::Table(table) => {
+            let entries = table
+                .iter()
+                .map(|(key, value)| format!("{key} = {}", inline_toml(value)))
+                .collect::<Vec<_>>();
+            format!("{{ {} }}", entries.join(", "))
+        }
+        value => value.to_string(),
+    }
+}
+
+/// Generates `Cargo.lock` if the workspace doesn't have one yet, so that the
+/// dependencies can be resolved.
+fn ensure_lockfile(config: &Config, flags: CargoFlags, manifest: &Path) -> Result<()> {
+    let lockfile = manifest.with_file_name("Cargo.lock");
+    if lockfile.exists() {
+        return Ok(());
+    }
+    if let Some(flag) = flags.lock {
+        bail!(
+            "`{}` doesn't exist and `{flag}` was passed",
+            lockfile.display()
+        );
+    }
+
+    config.verbose(|| config.status("Generating", &lockfile.display().to_string()));
+    let mut cmd = Command::new("cargo");
+    cmd.arg("generate-lockfile");
+    flags.apply(&mut cmd);
+    cmd.run()
+        .context("failed to generate `Cargo.lock`, the dependencies could not be resolved")?;
+    config.forget_cargo_metadata();
+    Ok(())
+}
+
 fn is_build_dep(dep_kinds: &[cargo_metadata::DepKindInfo]) -> bool {
     use cargo_metadata::DependencyKind::*;
     !dep_kinds
@@ -0,0 +1,131 @@
+//! Parsing of `.env` files, whose variables are passed to programs run by
+//! `cargo wasix run` and `cargo wasix test`.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// Reads the `.env` file at `path`.
+pub fn read(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents =
+        fs::read_to_string(path).context(format!("failed to read `{}`", path.display()))?;
+    parse(&contents).context(format!("failed to parse `{}`", path.display()))
+}
+
+/// Parses the contents of a `.env` file.
+///
+/// Every non-empty line that isn't a `#` comment must be a `KEY=VALUE` pair,
+/// optionally prefixed with `export`. Values may be wrapped in single quotes,
+/// which are taken literally, or double quotes, in which `\n`, `\"` and `\\`
+/// are unescaped. Unquoted values end at a ` #` comment, and quoted values may
+/// only be followed by one.
+pub fn parse(contents: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("line {}: expected `KEY=VALUE`", i + 1);
+        };
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            bail!("line {}: invalid variable name `{key}`", i + 1);
+        }
+        let value = parse_value(value.trim()).with_context(|| format!("line {}", i + 1))?;
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+fn parse_value(value: &str) -> Result<String> {
+    if let Some(rest) = value.strip_prefix('\'') {
+        let Some(end) = rest.find('\'') else {
+            bail!("unterminated single quote");
+        };
+        check_after_quote(&rest[end + 1..])?;
+        return Ok(rest[..end].to_string());
+    }
+
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    check_after_quote(chars.as_str())?;
+                    return Ok(out);
+                }
+                '\\' => match chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some(c) => out.push(c),
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        bail!("unterminated double quote");
+    }
+
+    let value = match value.find(" #") {
+        Some(comment) => &value[..comment],
+        None => value,
+    };
+    Ok(value.trim_end().to_string())
+}
+
+/// Checks that only whitespace or a comment follows a closing quote, rather
+/// than dropping text such as the `y` in `A="x"y`.
+fn check_after_quote(rest: &str) -> Result<()> {
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        bail!("unexpected `{rest}` after the closing quote");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let vars = parse(
+            r#"
+# a comment
+FOO=bar
+export EMPTY=
+  SPACED = value with spaces # trailing comment
+SINGLE='# literal \n' # comment
+DOUBLE="line\nbreak \"quoted\""
+URL=http://example.com/#anchor
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vars,
+            [
+                ("FOO", "bar"),
+                ("EMPTY", ""),
+                ("SPACED", "value with spaces"),
+                ("SINGLE", "# literal \\n"),
+                ("DOUBLE", "line\nbreak \"quoted\""),
+                ("URL", "http://example.com/#anchor"),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse("FOO").is_err());
+        assert!(parse("=bar").is_err());
+        assert!(parse("FOO BAR=baz").is_err());
+        assert!(parse("FOO=\"bar").is_err());
+        assert!(parse("FOO='bar").is_err());
+        assert!(parse("FOO=\"bar\"baz").is_err());
+        assert!(parse("FOO='it''s'").is_err());
+    }
+}
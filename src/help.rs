@@ -0,0 +1,257 @@
+//! The help of the `cargo wasix` subcommands.
+//!
+//! `cargo wasix <subcommand> --help` lists the flags that `cargo wasix`
+//! handles itself, followed by the help of the `cargo` command that all other
+//! flags are forwarded to.
+
+use crate::utils::CommandExt;
+use crate::Subcommand;
+use anyhow::Result;
+use std::io::Write;
+use std::process::Command;
+
+/// A flag that `cargo wasix` handles itself instead of `cargo`.
+struct Flag {
+    usage: &'static str,
+    about: &'static str,
+    /// Whether the flag has an effect for the subcommand.
+    applies: fn(Subcommand) -> bool,
+}
+
+/// Subcommands that compile the crate.
+fn compiles(subcommand: Subcommand) -> bool {
+    !matches!(
+        subcommand,
+        Subcommand::BuildToolchain
+            | Subcommand::DownloadToolchain
+            | Subcommand::Tree
+            | Subcommand::Update
+    )
+}
+
+/// Subcommands that produce `*.wasm` files, which are post-processed.
+fn produces_wasm(subcommand: Subcommand) -> bool {
+    compiles(subcommand) && !matches!(subcommand, Subcommand::Check | Subcommand::Fix)
+}
+
+/// Subcommands that check the dependencies for known incompatible crates.
+fn checks_dependencies(subcommand: Subcommand) -> bool {
+    (compiles(subcommand) && subcommand != Subcommand::Fix) || subcommand == Subcommand::Update
+}
+
+/// Subcommands that run programs.
+fn runs(subcommand: Subcommand) -> bool {
+    matches!(
+        subcommand,
+        Subcommand::Run | Subcommand::Test | Subcommand::Bench
+    )
+}
+
+/// Subcommands that need the wasix toolchain.
+fn uses_toolchain(subcommand: Subcommand) -> bool {
+    !matches!(
+        subcommand,
+        Subcommand::BuildToolchain | Subcommand::DownloadToolchain
+    )
+}
+
+/// All flags of `cargo wasix`, the help of subcommands is generated from this
+/// list.
+const FLAGS: &[Flag] = &[
+    Flag {
+        usage: "--no-atomics",
+        about: "compile without the `atomics` target feature",
+        applies: compiles,
+    },
+    Flag {
+        usage: "--remap-path",
+        about: "replace absolute source paths with relative ones",
+        applies: compiles,
+    },
+    Flag {
+        usage: "--deny-unshared-memory",
+        about: "fail instead of warning when a module has no shared memory",
+        applies: produces_wasm,
+    },
+    Flag {
+        usage: "--out-dir <DIR>",
+        about: "copy the final `*.wasm` files to a directory",
+        applies: produces_wasm,
+    },
+    Flag {
+        usage: "--compress <FORMAT>",
+        about: "write a compressed copy of the final `*.wasm` files",
+        applies: produces_wasm,
+    },
+    Flag {
+        usage: "--component",
+        about: "also build a WASI Preview 2 component from the final `*.wasm` files",
+        applies: produces_wasm,
+    },
+    Flag {
+        usage: "--cargo-wasix-json",
+        about: "print a JSON summary of the post-processed files",
+        applies: produces_wasm,
+    },
+    Flag {
+        usage: "--print-artifacts",
+        about: "print the paths of the final `*.wasm` files instead of running them",
+        applies: produces_wasm,
+    },
+    Flag {
+        usage: "--local-tools",
+        about: "download tools such as `wasm-opt` into the target directory",
+        applies: produces_wasm,
+    },
+    Flag {
+        usage: "--explain",
+        about: "explain why dependencies are incompatible with wasix",
+        applies: checks_dependencies,
+    },
+    Flag {
+        usage: "--no-preopen",
+        about: "don't give programs access to the current directory",
+        applies: runs,
+    },
+    Flag {
+        usage: "--net",
+        about: "give programs access to the network",
+        applies: runs,
+    },
+    Flag {
+        usage: "--strict",
+        about: "fail instead of warning when the runtime doesn't match `runtime-version`",
+        applies: runs,
+    },
+    Flag {
+        usage: "--env-file <PATH>",
+        about: "pass the variables of a `.env` file to programs",
+        applies: runs,
+    },
+    Flag {
+        usage: "--pass-env <NAME>",
+        about: "pass a variable of the environment to programs",
+        applies: runs,
+    },
+    Flag {
+        usage: "--package-mode",
+        about: "run the program as a package with a generated `wasmer.toml`",
+        applies: |s| s == Subcommand::Run,
+    },
+    Flag {
+        usage: "--list",
+        about: "list the tests or benchmarks instead of running them",
+        applies: |s| matches!(s, Subcommand::Test | Subcommand::Bench),
+    },
+    Flag {
+        usage: "--root <DIR>",
+        about: "directory to install into, defaults to the one of `cargo install`",
+        applies: |s| s == Subcommand::Install,
+    },
+    Flag {
+        usage: "--launcher",
+        about: "also install scripts running the binaries with the runtime",
+        applies: |s| s == Subcommand::Install,
+    },
+    Flag {
+        usage: "--debug",
+        about: "install a debug build instead of a release build",
+        applies: |s| s == Subcommand::Install,
+    },
+    Flag {
+        usage: "--dominators",
+        about: "print the dominator tree instead of the largest functions",
+        applies: |s| s == Subcommand::Size,
+    },
+    Flag {
+        usage: "--top <N>",
+        about: "number of functions to print, defaults to 20",
+        applies: |s| s == Subcommand::Size,
+    },
+    Flag {
+        usage: "--depth <N>",
+        about: "levels of the dominator tree to print, defaults to 4",
+        applies: |s| s == Subcommand::Size,
+    },
+    Flag {
+        usage: "--components <all|libc|rust>",
+        about: "which parts of the toolchain to build",
+        applies: |s| s == Subcommand::BuildToolchain,
+    },
+    Flag {
+        usage: "--continue",
+        about: "build rust against an earlier sysroot if libc fails",
+        applies: |s| s == Subcommand::BuildToolchain,
+    },
+    Flag {
+        usage: "--dry-run",
+        about: "print what would be done without doing it",
+        applies: |s| !uses_toolchain(s),
+    },
+    Flag {
+        usage: "--frozen-toolchain",
+        about: "fail unless the linked toolchain is the pinned release",
+        applies: uses_toolchain,
+    },
+];
+
+/// Prints the help of `subcommand`, which was invoked as `name`: the flags
+/// added by `cargo wasix`, followed by the help of `cargo_command`, whose
+/// flags are forwarded.
+pub fn print_subcommand_help(
+    name: &str,
+    subcommand: Subcommand,
+    cargo_command: &str,
+) -> Result<()> {
+    let arguments = match subcommand {
+        Subcommand::DownloadToolchain => "[VERSION] [OPTIONS]",
+        _ => "[OPTIONS]",
+    };
+    println!("Usage: cargo wasix {name} {arguments}\n");
+    println!("Options added by cargo-wasix:");
+    let flags = FLAGS
+        .iter()
+        .filter(|f| (f.applies)(subcommand))
+        .collect::<Vec<_>>();
+    let width = flags.iter().map(|f| f.usage.len()).max().unwrap_or(0);
+    for flag in flags {
+        println!("    {:width$}  {}", flag.usage, flag.about);
+    }
+    if !uses_toolchain(subcommand) {
+        return Ok(());
+    }
+
+    println!("\nAll other options are forwarded to `cargo {cargo_command}`:\n");
+    std::io::stdout().flush()?;
+    Command::new("cargo").arg(cargo_command).arg("--help").run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_are_documented() {
+        // The arms of the flag match in `rmain` that open a block handle a
+        // flag of `cargo wasix`, the others only take note of `cargo` flags.
+        let source = include_str!("lib.rs");
+        let start = source.find("\"--\" => after_double_dash = true,").unwrap();
+        let end = start + source[start..].find("_ => {}").unwrap();
+        let flags = source[start..end]
+            .lines()
+            .map(str::trim)
+            .filter_map(|line| line.strip_suffix(" => {"))
+            .flat_map(|pattern| pattern.split(" if ").next().unwrap().split('|'))
+            .filter_map(|flag| flag.trim().strip_prefix('"')?.strip_suffix('"'))
+            .collect::<Vec<_>>();
+        assert!(flags.contains(&"--no-atomics"));
+        for flag in flags {
+            assert!(
+                FLAGS
+                    .iter()
+                    .any(|f| f.usage.split(' ').next() == Some(flag)),
+                "`{flag}` is missing from `FLAGS`"
+            );
+        }
+    }
+}
@@ -1,21 +1,91 @@
 use crate::config::Config;
+use crate::toolchain::{self, RustupToolchain, ToolchainSpec};
+use crate::{archive, component, dependencies, utils};
 use anyhow::{bail, Context, Result};
 use semver::Version;
 use std::ffi::OsString;
 use std::fs::{self, File};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::thread;
 use std::time::{Duration, SystemTime};
 
 const UPDATE_TIMEOUT: Duration = Duration::from_secs(30);
 
+/// A `cargo wasix self` subcommand.
+struct SelfCommand {
+    name: &'static str,
+    about: &'static str,
+    /// Runs the command with the arguments following its name.
+    run: fn(&[OsString], &Config) -> Result<()>,
+}
+
+/// All `self` subcommands, the help and error messages are generated from
+/// this list.
+const COMMANDS: &[SelfCommand] = &[
+    SelfCommand {
+        name: "clean",
+        about: "remove the cache of downloaded tools and metadata",
+        run: |_, config| clean(config),
+    },
+    SelfCommand {
+        name: "fetch-tools",
+        about: "download `wasm-opt` and the toolchain for building offline",
+        run: |_, config| fetch_tools(config),
+    },
+    SelfCommand {
+        name: "paths",
+        about: "print where the toolchain, its sysroots and the cache are",
+        run: |_, config| paths(config),
+    },
+    SelfCommand {
+        name: "update-check",
+        about: "check whether a new release of cargo-wasix is available",
+        run: |_, config| update_check(config),
+    },
+    SelfCommand {
+        name: "update-toolchain",
+        about: "download and link the latest (or the given) toolchain release",
+        run: |args, config| update_toolchain(args.first(), config),
+    },
+];
+
 pub fn main(args: &[OsString], config: &Config) -> Result<()> {
-    match args.get(0).and_then(|s| s.to_str()) {
-        Some("clean") => clean(config),
-        Some("update-check") => update_check(config),
-        Some(other) => bail!("unsupported `self` command: {}", other),
-        None => bail!("`self` command must be followed by `clean` or `update-check`"),
+    match args.first().and_then(|s| s.to_str()) {
+        Some("-h" | "--help" | "help") => {
+            print_help();
+            Ok(())
+        }
+        Some(name) => match COMMANDS.iter().find(|c| c.name == name) {
+            Some(command) => (command.run)(&args[1..], config),
+            None => bail!(
+                "unsupported `self` command: {name}, expected {}",
+                command_list()
+            ),
+        },
+        None => bail!("`self` command must be followed by {}", command_list()),
+    }
+}
+
+/// Formats the names of all commands as "`a`, `b` or `c`".
+fn command_list() -> String {
+    let names = COMMANDS
+        .iter()
+        .map(|c| format!("`{}`", c.name))
+        .collect::<Vec<_>>();
+    match names.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {last}", rest.join(", ")),
+        None => String::new(),
+    }
+}
+
+fn print_help() {
+    println!("Usage: cargo wasix self <COMMAND>\n");
+    println!("Commands:");
+    let width = COMMANDS.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    for command in COMMANDS {
+        println!("    {:width$}  {}", command.name, command.about);
     }
 }
 
@@ -28,6 +98,130 @@ fn clean(config: &Config) -> Result<()> {
     Ok(())
 }
 
+/// Downloads everything a build needs into the shared cache, so that later
+/// builds work with `CARGO_WASIX_OFFLINE` or `WASIX_NO_AUTO_DOWNLOAD` set.
+/// What is cached already is kept.
+fn fetch_tools(config: &Config) -> Result<()> {
+    if config.is_offline {
+        bail!("cannot fetch tools because CARGO_WASIX_OFFLINE is set");
+    }
+    let cached = |what: &str, path: &Path| -> Result<()> {
+        let size = utils::format_size(disk_usage(path)?);
+        config.status("Cached", &format!("{what} at {} ({size})", path.display()));
+        Ok(())
+    };
+
+    let chain = toolchain::ensure_toolchain(config, false)?;
+    cached(&format!("toolchain {}", chain.name), &chain.path)?;
+    for is64bit in [false, true] {
+        let target = if is64bit {
+            "wasm64-wasmer-wasi"
+        } else {
+            "wasm32-wasmer-wasi"
+        };
+        match chain.sysroot_dir(is64bit) {
+            Some(sysroot) => cached(&format!("sysroot for `{target}`"), &sysroot)?,
+            None => config.warn(&format!(
+                "the toolchain has no sysroot for `{target}`, which can't be built for"
+            )),
+        }
+    }
+
+    let wasm_opt = config.get_wasm_opt();
+    if wasm_opt.is_overridden() {
+        config.info("$WASM_OPT is set, so `wasm-opt` isn't downloaded");
+    } else {
+        crate::install_wasm_opt(&wasm_opt, config)?;
+        cached("wasm-opt", wasm_opt.bin_path())?;
+    }
+
+    for (what, path) in component::fetch(config)? {
+        cached(what, &path)?;
+    }
+
+    let crates = dependencies::fetch_known_incompatible_crates(config)?;
+    cached("known incompatible crates", &crates)?;
+    Ok(())
+}
+
+/// Prints where the linked toolchain, its sysroots and the caches are as
+/// `KEY=VALUE` lines to stdout, named after the variables that configure
+/// them. Paths that don't exist are printed as empty values.
+fn paths(config: &Config) -> Result<()> {
+    let name = toolchain::toolchain_name();
+    let chain = RustupToolchain::find_by_name(&name)?;
+    if chain.is_none() {
+        config.warn(&format!(
+            "the `{name}` toolchain isn't linked with rustup, install it with \
+             `cargo wasix download-toolchain`"
+        ));
+    }
+    let chain = chain.as_ref();
+    let display = |path: Option<PathBuf>| path.map(|p| p.display().to_string());
+    let paths = [
+        ("CARGO_WASIX_TOOLCHAIN", Some(name.clone())),
+        ("WASIX_TOOLCHAIN", display(chain.map(|c| c.path.clone()))),
+        (
+            "WASIX_SYSROOT32",
+            display(chain.and_then(|c| c.sysroot_dir(false))),
+        ),
+        (
+            "WASIX_SYSROOT64",
+            display(chain.and_then(|c| c.sysroot_dir(true))),
+        ),
+        (
+            "WASIX_TOOLCHAINS_ROOT",
+            display(Some(Config::toolchain_dir()?)),
+        ),
+        ("WASIX_CACHE_DIR", display(Some(Config::cache_dir()?))),
+    ];
+    for (key, value) in paths {
+        println!("{key}={}", value.unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Returns the number of bytes the files at `path` take up, without following
+/// symlinks.
+fn disk_usage(path: &Path) -> Result<u64> {
+    let metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("failed to read `{}`", path.display()))?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for file in archive::files(path)? {
+        size += fs::symlink_metadata(&file)?.len();
+    }
+    Ok(size)
+}
+
+/// Downloads the latest (or the given) toolchain release and links it, even if
+/// a toolchain is already installed.
+fn update_toolchain(version: Option<&OsString>, config: &Config) -> Result<()> {
+    if config.is_offline {
+        bail!("cannot update the wasix toolchain because CARGO_WASIX_OFFLINE is set");
+    }
+    let version = match version {
+        Some(v) => v
+            .to_str()
+            .context("toolchain version must be valid unicode")?
+            .to_string()
+            .into(),
+        None => ToolchainSpec::from_env(),
+    };
+
+    let _lock = Config::acquire_lock()?;
+    let chain = toolchain::install_prebuilt_toolchain(config, &Config::toolchain_dir()?, version)?;
+    toolchain::check_toolchain(&chain, false)?;
+    config.info(&format!(
+        "Toolchain {} updated at path {}.",
+        chain.name,
+        chain.path.display(),
+    ));
+    Ok(())
+}
+
 fn update_check(config: &Config) -> Result<()> {
     config.status("Checking", "for the latest release");
     match update_available()? {
@@ -1,34 +1,49 @@
 use crate::cache::Cache;
-use crate::config::Config;
+use crate::config::{Config, Stage};
 use crate::utils::CommandExt;
 use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
 use std::env;
+use std::ffi::OsString;
 use std::fs;
 use std::io;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tool_path::ToolPath;
 
+mod api;
+mod archive;
 mod cache;
+mod component;
 mod config;
 mod dependencies;
+mod dotenv;
+mod help;
 mod internal;
+mod runtime;
+mod size;
 mod tool_path;
 mod toolchain;
 mod utils;
+mod wasm;
+
+pub use api::{ensure_toolchain, postprocess_wasm, PostprocessOptions};
+pub use runtime::{Runtime, RuntimeSettings, RuntimeSource};
+pub use toolchain::RustupToolchain;
 
 /// Timeout used by [`download`].
 const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub fn main() {
     // See comments in `rmain` around `*_RUNNER` for why this exists here.
-    if env::var("__CARGO_WASIX_RUNNER_SHIM").is_ok() {
+    if let Ok(kind) = env::var("__CARGO_WASIX_RUNNER_SHIM") {
         let args = env::args().skip(1).collect();
+        let kind = RunKind::from_shim_env(&kind);
         println!(
             "{}",
-            serde_json::to_string(&CargoMessage::RunWithArgs { args }).unwrap(),
+            serde_json::to_string(&CargoMessage::RunWithArgs { args, kind }).unwrap(),
         );
         return;
     }
@@ -37,13 +52,19 @@ pub fn main() {
     match rmain(&mut config) {
         Ok(()) => {}
         Err(e) => {
+            let stage = config.stage();
+            // `cargo` already explained why it failed, but unlike programs
+            // that are run its exit code isn't passed through.
+            if stage == Stage::Cargo && utils::normal_process_exit_code(&e).is_some() {
+                std::process::exit(stage.exit_code());
+            }
             config.print_error(&e);
-            std::process::exit(1);
+            std::process::exit(stage.exit_code());
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Subcommand {
     Build,
     BuildToolchain,
@@ -53,7 +74,10 @@ enum Subcommand {
     Bench,
     Check,
     Tree,
+    Update,
     Fix,
+    Install,
+    Size,
 }
 
 fn rmain(config: &mut Config) -> Result<()> {
@@ -63,8 +87,8 @@ fn rmain(config: &mut Config) -> Result<()> {
     let mut is64bit = false;
     let mut no_message_format = false;
     let mut args = env::args_os().skip(2);
-    let subcommand = args.next().and_then(|s| s.into_string().ok());
-    let subcommand = match subcommand.as_deref() {
+    let subcommand_name = args.next().and_then(|s| s.into_string().ok());
+    let subcommand = match subcommand_name.as_deref() {
         Some("build") => Subcommand::Build,
         Some("build64") => {
             is64bit = true;
@@ -101,7 +125,26 @@ fn rmain(config: &mut Config) -> Result<()> {
             no_message_format = true;
             Subcommand::Tree
         }
+        Some("update") => {
+            no_message_format = true;
+            Subcommand::Update
+        }
+        Some("update64") => {
+            is64bit = true;
+            no_message_format = true;
+            Subcommand::Update
+        }
         Some("fix") => Subcommand::Fix,
+        Some("install") => Subcommand::Install,
+        Some("install64") => {
+            is64bit = true;
+            Subcommand::Install
+        }
+        Some("size") => Subcommand::Size,
+        Some("size64") => {
+            is64bit = true;
+            Subcommand::Size
+        }
         Some("self") => return internal::main(&args.collect::<Vec<_>>(), config),
         Some("version") | Some("-V") | Some("--version") => {
             let git_info = match option_env!("GIT_INFO") {
@@ -115,47 +158,286 @@ fn rmain(config: &mut Config) -> Result<()> {
     };
 
     let mut cargo = Command::new("cargo");
-    cargo.arg("+wasix");
-    cargo.arg(match subcommand {
-        Subcommand::Build => "build",
-        Subcommand::BuildToolchain => "build-toolchain",
-        Subcommand::DownloadToolchain => "download-toolchain",
-        Subcommand::Check => "check",
-        Subcommand::Fix => "fix",
-        Subcommand::Test => "test",
-        Subcommand::Tree => "tree",
-        Subcommand::Bench => "bench",
-        Subcommand::Run => "run",
-    });
+    cargo.arg(format!("+{}", toolchain::toolchain_name()));
+    cargo.arg(cargo_command(subcommand));
 
-    // TODO: figure out when these flags are already passed to `cargo` and skip
-    // passing them ourselves.
-    let target = if is64bit {
-        "wasm64-wasmer-wasi"
-    } else {
-        "wasm32-wasmer-wasi"
-    };
+    // Everything specific to the target, such as the runner variable and the
+    // platform the dependencies are checked for, derives from this.
+    let target = target_triple(is64bit);
+
+    // The target is selected by the subcommand (`build` vs `build64`), so an
+    // explicit `--target` is only tolerated if it agrees with that selection.
+    let args = args.collect::<Vec<_>>();
 
-    cargo.arg("--target").arg(target);
+    // Describes the flags added by `cargo wasix`, followed by the help of the
+    // `cargo` command for all others.
+    if args
+        .iter()
+        .take_while(|a| *a != "--")
+        .any(|a| a == "--help" || a == "-h")
+    {
+        let name = subcommand_name.as_deref().unwrap_or_default();
+        return help::print_subcommand_help(name, subcommand, cargo_command(subcommand));
+    }
+
+    // `cargo` builds for every `--target` it's given, so each of them has to
+    // be ours.
+    let explicit_targets = cargo_flag_values(&args, "--target");
+    if let Some(explicit) = explicit_targets.iter().find(|t| *t != target) {
+        let hint = match explicit.as_str() {
+            "wasm32-wasmer-wasi" => "use the 32-bit subcommands (e.g. `cargo wasix build`)",
+            "wasm64-wasmer-wasi" => "use the 64-bit subcommands (e.g. `cargo wasix build64`)",
+            _ => "remove the `--target` argument",
+        };
+        bail!(
+            "`--target {explicit}` conflicts with the `{target}` target selected by \
+             `cargo wasix`, the target is managed automatically; {hint}"
+        );
+    }
+    // `cargo update` resolves for all targets, only the check afterwards is
+    // specific to ours.
+    if explicit_targets.is_empty() && subcommand != Subcommand::Update {
+        cargo.arg("--target").arg(target);
+    }
+
+    // TODO: figure out when `--message-format` is already passed to `cargo`
+    // and skip passing it ourselves.
     if !no_message_format {
         cargo.arg("--message-format").arg("json-render-diagnostics");
     }
 
-    let args = args.collect::<Vec<_>>();
-    for arg in args.clone() {
+    // Flags specific to `cargo wasix` are consumed here and not forwarded to
+    // `cargo`. Anything after `--` belongs to the program being run.
+    let mut no_atomics = false;
+    let mut deny_unshared_memory = false;
+    let mut component_flag = false;
+    let mut strict = false;
+    let mut explain = false;
+    let mut no_preopen = false;
+    let mut enable_net = false;
+    let mut out_dir = None;
+    let mut env_file = None;
+    let mut pass_env = Vec::new();
+    let mut timings_requested = false;
+    let mut lock_flag = None;
+    let mut offline = false;
+    let mut verbosity = 0;
+    let mut no_run = false;
+    let mut keep_going = false;
+    let mut install_root = None;
+    let mut launcher = false;
+    let mut package_mode = false;
+    let mut install_debug = false;
+    let mut list_tests = false;
+    let mut json_summary = false;
+    let mut print_artifacts = false;
+    let mut compress = None;
+    let mut remap_path = false;
+    let mut size_options = size::SizeOptions::default();
+    let mut components = None;
+    let mut continue_components = false;
+    let mut after_double_dash = false;
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
         if let Some(arg) = arg.to_str() {
+            if !after_double_dash {
+                match arg {
+                    "--" => after_double_dash = true,
+                    "--no-atomics" => {
+                        no_atomics = true;
+                        continue;
+                    }
+                    "--deny-unshared-memory" => {
+                        deny_unshared_memory = true;
+                        continue;
+                    }
+                    "--component" => {
+                        component_flag = true;
+                        continue;
+                    }
+                    "--strict" => {
+                        strict = true;
+                        continue;
+                    }
+                    "--explain" => {
+                        explain = true;
+                        continue;
+                    }
+                    "--no-preopen" => {
+                        no_preopen = true;
+                        continue;
+                    }
+                    "--net" => {
+                        enable_net = true;
+                        continue;
+                    }
+                    "--frozen-toolchain" => {
+                        config.frozen_toolchain = true;
+                        continue;
+                    }
+                    "--remap-path" => {
+                        remap_path = true;
+                        continue;
+                    }
+                    "--local-tools" => {
+                        config.local_tools = true;
+                        continue;
+                    }
+                    "--cargo-wasix-json" => {
+                        json_summary = true;
+                        continue;
+                    }
+                    "--print-artifacts" => {
+                        print_artifacts = true;
+                        continue;
+                    }
+                    // `cargo install` flags, which `cargo build` doesn't know.
+                    "--root" if subcommand == Subcommand::Install => {
+                        let dir = args_iter.next().context("`--root` requires a value")?;
+                        install_root = Some(PathBuf::from(dir));
+                        continue;
+                    }
+                    _ if subcommand == Subcommand::Install && arg.starts_with("--root=") => {
+                        install_root = Some(PathBuf::from(&arg["--root=".len()..]));
+                        continue;
+                    }
+                    "--package-mode" if subcommand == Subcommand::Run => {
+                        package_mode = true;
+                        continue;
+                    }
+                    "--launcher" if subcommand == Subcommand::Install => {
+                        launcher = true;
+                        continue;
+                    }
+                    "--debug" if subcommand == Subcommand::Install => {
+                        install_debug = true;
+                        continue;
+                    }
+                    "--continue" if subcommand == Subcommand::BuildToolchain => {
+                        continue_components = true;
+                        continue;
+                    }
+                    "--components" if subcommand == Subcommand::BuildToolchain => {
+                        let value = args_iter
+                            .next()
+                            .context("`--components` requires a value")?;
+                        components = Some(value.to_string_lossy().into_owned());
+                        continue;
+                    }
+                    _ if subcommand == Subcommand::BuildToolchain
+                        && arg.starts_with("--components=") =>
+                    {
+                        components = Some(arg["--components=".len()..].to_string());
+                        continue;
+                    }
+                    // `cargo wasix size` flags.
+                    "--dominators" if subcommand == Subcommand::Size => {
+                        size_options.dominators = true;
+                        continue;
+                    }
+                    "--top" | "--depth" if subcommand == Subcommand::Size => {
+                        let value = args_iter
+                            .next()
+                            .and_then(|v| v.to_str()?.parse().ok())
+                            .with_context(|| format!("`{arg}` requires a number"))?;
+                        if arg == "--top" {
+                            size_options.top = value;
+                        } else {
+                            size_options.depth = value;
+                        }
+                        continue;
+                    }
+                    // A test harness flag, `cargo test` doesn't know it.
+                    "--list" if matches!(subcommand, Subcommand::Test | Subcommand::Bench) => {
+                        list_tests = true;
+                        continue;
+                    }
+                    "--compress" => {
+                        let name = args_iter.next().context("`--compress` requires a value")?;
+                        compress = Some(Compression::from_name(&name.to_string_lossy())?);
+                        continue;
+                    }
+                    _ if arg.starts_with("--compress=") => {
+                        compress = Some(Compression::from_name(&arg["--compress=".len()..])?);
+                        continue;
+                    }
+                    "--out-dir" => {
+                        let dir = args_iter.next().context("`--out-dir` requires a value")?;
+                        out_dir = Some(PathBuf::from(dir));
+                        continue;
+                    }
+                    _ if arg.starts_with("--out-dir=") => {
+                        out_dir = Some(PathBuf::from(&arg["--out-dir=".len()..]));
+                        continue;
+                    }
+                    "--env-file" => {
+                        let file = args_iter.next().context("`--env-file` requires a value")?;
+                        env_file = Some(PathBuf::from(file));
+                        continue;
+                    }
+                    _ if arg.starts_with("--env-file=") => {
+                        env_file = Some(PathBuf::from(&arg["--env-file=".len()..]));
+                        continue;
+                    }
+                    "--pass-env" => {
+                        let key = args_iter.next().context("`--pass-env` requires a value")?;
+                        pass_env.push(key.to_string_lossy().into_owned());
+                        continue;
+                    }
+                    _ if arg.starts_with("--pass-env=") => {
+                        pass_env.push(arg["--pass-env=".len()..].to_string());
+                        continue;
+                    }
+                    // Forwarded to `cargo`, but we also report the time spent
+                    // post-processing each artifact.
+                    _ if arg == "--timings" || arg.starts_with("--timings=") => {
+                        timings_requested = true;
+                    }
+                    // Forwarded to `cargo`, but the dependency check must not
+                    // touch `Cargo.lock` either.
+                    "--locked" | "--frozen" => lock_flag = Some(arg),
+                    "--offline" => offline = true,
+                    "--no-run" => no_run = true,
+                    // Forwarded to `cargo`, and failing to post-process an
+                    // artifact doesn't stop the others either.
+                    "--keep-going" => keep_going = true,
+                    _ => {}
+                }
+            }
             if arg.starts_with("--verbose") || arg.starts_with("-v") {
                 config.set_verbose(true);
+                verbosity += match arg {
+                    "--verbose" => 1,
+                    _ if arg[1..].chars().all(|c| c == 'v') => arg.len() - 1,
+                    _ => 0,
+                };
+            }
+            if !after_double_dash && (arg == "--quiet" || arg == "-q") {
+                config.set_quiet(true);
             }
         }
 
         cargo.arg(arg);
     }
+    if list_tests {
+        if !after_double_dash {
+            cargo.arg("--");
+        }
+        cargo.arg("--list");
+    }
+    if verbosity >= 2 {
+        config.set_very_verbose(true);
+    }
+    // Like `cargo install`, build in release mode unless asked otherwise.
+    if subcommand == Subcommand::Install
+        && !install_debug
+        && !args.iter().any(|a| a == "--release")
+        && cargo_flag_value(&args, "--profile").is_none()
+    {
+        cargo.arg("--release");
+    }
 
-    let runner_env_var = format!(
-        "CARGO_TARGET_{}_RUNNER",
-        target.to_uppercase().replace('-', "_")
-    );
+    let runner_env_var = target_runner_env_var(target);
 
     // If Cargo actually executes a wasm file, we don't want it to. We need to
     // postprocess wasm files (wasm-opt, wasm-bindgen, etc). As a result we will
@@ -169,24 +451,26 @@ fn rmain(config: &mut Config) -> Result<()> {
     // the beginning. We'll slurp up these json messages and then actually
     // execute everything at the end.
     //
-    // Also note that we check here before we actually build that a runtime is
-    // present. We first check the CARGO_TARGET_WASM32_WASMER_WASI_RUNNER environement
-    // variable for a user-supplied runtime (path or executable) and use the
-    // default, namely `wasmer`, if it is not set.
-    let (wasix_runner, using_default) = env::var(&runner_env_var)
-        .map(|runner_override| (runner_override, false))
-        .unwrap_or_else(|_| ("wasmer".to_string(), true));
-
+    // Which runtime executes them is resolved once the manifest was read, see
+    // `Runtime::from_env`.
+    let mut runs_programs = false;
     let mut check_deps = false;
     match subcommand {
         Subcommand::DownloadToolchain => {
+            let dry_run = config.dry_run || args.iter().any(|a| a == "--dry-run");
             let version = args
-                .first()
+                .iter()
+                .find(|a| *a != "--dry-run")
                 .cloned()
                 .map(|v| v.into_string().unwrap().into())
-                .unwrap_or(toolchain::ToolchainSpec::Latest);
+                .unwrap_or_else(toolchain::ToolchainSpec::from_env);
+            if dry_run {
+                toolchain::print_download_plan(&Config::toolchain_dir()?, &version);
+                return Ok(());
+            }
             let _lock = Config::acquire_lock()?;
-            let chain = toolchain::install_prebuilt_toolchain(&Config::toolchain_dir()?, version)?;
+            let chain =
+                toolchain::install_prebuilt_toolchain(config, &Config::toolchain_dir()?, version)?;
             config.info(&format!(
                 "Toolchain {} downloaded and installed to path {}.\nThe wasix toolchain is now ready to use.",
                 chain.name,
@@ -195,40 +479,35 @@ fn rmain(config: &mut Config) -> Result<()> {
             return Ok(());
         }
         Subcommand::BuildToolchain => {
-            let opts = toolchain::BuildToochainOptions::from_env()?;
-            toolchain::build_toolchain(opts)?;
+            let mut opts = toolchain::BuildToochainOptions::from_env(components.as_deref())?;
+            opts.dry_run = config.dry_run || args.iter().any(|a| a == "--dry-run");
+            opts.keep_going = continue_components;
+            toolchain::build_toolchain(config, opts)?;
             return Ok(());
         }
+        // Nothing is run, so there's no need for a runtime either.
+        Subcommand::Test | Subcommand::Bench if no_run => check_deps = true,
         Subcommand::Run | Subcommand::Bench | Subcommand::Test => {
             check_deps = true;
-            if !using_default {
-                // check if the override is either a valid path or command found on $PATH
-                if !(Path::new(&wasix_runner).exists() || which::which(&wasix_runner).is_ok()) {
-                    bail!(
-                        "failed to find `{}` (specified by ${runner_env_var}) \
-                         on the filesytem or in $PATH, you'll want to fix the path or unset \
-                         the ${runner_env_var} environment variable before \
-                         running this command\n",
-                        &wasix_runner
-                    );
-                }
-            } else if which::which(&wasix_runner).is_err() {
-                let mut msg = format!(
-                    "failed to find `{}` in $PATH, you'll want to \
-                     install `{}` before running this command\n",
-                    wasix_runner, wasix_runner
-                );
-                // Because we know what runtime is being used here, we can print
-                // out installation information.
-                msg.push_str("you can also install through a shell:\n\n");
-                msg.push_str("\tcurl https://get.wasmer.io -sSfL | sh\n");
-                bail!("{}", msg);
-            }
-            cargo.env("__CARGO_WASIX_RUNNER_SHIM", "1");
-            cargo.env(runner_env_var, env::current_exe()?);
+            runs_programs = true;
+            // Tells the shim what the programs are run for, see `RunKind`.
+            let kind = match subcommand {
+                Subcommand::Test => "test",
+                Subcommand::Bench => "bench",
+                _ => "run",
+            };
+            cargo.env("__CARGO_WASIX_RUNNER_SHIM", kind);
+            cargo.env(&runner_env_var, env::current_exe()?);
+        }
+        Subcommand::Build | Subcommand::Check | Subcommand::Install | Subcommand::Size => {
+            check_deps = true
         }
-        Subcommand::Build | Subcommand::Check => check_deps = true,
+        // `cargo fix` compiles like `cargo check`, also with `--edition` or
+        // `--broken-code`, so there is never a `*.wasm` file to post-process.
+        // Its own flags such as `--allow-dirty` are forwarded untouched.
         Subcommand::Tree | Subcommand::Fix => {}
+        // The dependencies are checked after they were updated, see below.
+        Subcommand::Update => {}
     }
 
     let update_check_opt = if config.is_offline {
@@ -236,35 +515,316 @@ fn rmain(config: &mut Config) -> Result<()> {
     } else {
         None
     };
+    // `WASIX_DRY_RUN` is about the toolchain, so say that the command itself
+    // didn't run either rather than silently succeeding.
+    if config.dry_run {
+        toolchain::print_ensure_plan(config)?;
+        eprintln!(
+            "Dry run: stopping before `cargo {}`, nothing was built; unset WASIX_DRY_RUN \
+             to run it",
+            cargo_command(subcommand)
+        );
+        return Ok(());
+    }
     let toolchain = toolchain::ensure_toolchain(config, is64bit)?;
 
     std::env::set_var("RUSTUP_TOOLCHAIN", &toolchain.name);
 
-    if let Ok(dir) = std::env::var("WASI_SDK_DIR") {
-        config.verbose(|| config.status("WASI_SDK_DIR=", &dir));
-    } else if let Some(sysroot) = toolchain.sysroot_dir(is64bit) {
-        std::env::set_var("WASI_SDK_DIR", &sysroot);
-        config.verbose(|| config.status("WASI_SDK_DIR={}", &sysroot.display().to_string()));
+    // Prefer the sysroot shipped with the linked toolchain, the `/opt` paths
+    // only exist in some Docker images.
+    let sysroot = toolchain.sysroot_dir(is64bit).or_else(|| {
+        let size = if is64bit { 64 } else { 32 };
+        let path = PathBuf::from(format!("/opt/wasix-libc/sysroot{size}/"));
+        path.is_dir().then_some(path)
+    });
+
+    // `WASIX_SYSROOT` is used by build scripts compiling C code (e.g. through
+    // `cc` or `bindgen`) to find the wasix-libc headers and libraries.
+    for var in ["WASI_SDK_DIR", "WASIX_SYSROOT"] {
+        if let Ok(dir) = std::env::var(var) {
+            config.verbose(|| config.status(&format!("{var}="), &dir));
+        } else if let Some(sysroot) = &sysroot {
+            std::env::set_var(var, sysroot);
+            config.verbose(|| config.status(&format!("{var}="), &sysroot.display().to_string()));
+        }
+    }
+
+    let manifest_path = cargo_flag_value(&args, "--manifest-path").map(PathBuf::from);
+    let cargo_flags = utils::CargoFlags {
+        lock: lock_flag,
+        offline: offline || config.is_offline,
+        manifest_path: manifest_path.as_deref(),
+    };
+
+    // An update can bring in versions of crates that don't support wasix, so
+    // the new `Cargo.lock` is checked right away. Nothing changes with
+    // `--dry-run`, so there's nothing new to check either.
+    if subcommand == Subcommand::Update {
+        config.set_stage(Stage::Cargo);
+        cargo
+            .run()
+            .map_err(|e| utils::hide_normal_process_exit(e, config))?;
+        config.set_stage(Stage::Setup);
+        if !args.iter().any(|a| a == "--dry-run") {
+            config.forget_cargo_metadata();
+            if let Err(err) = dependencies::check(config, target, cargo_flags, explain) {
+                config.warn(&format!("failed to check dependencies: {err}"));
+            }
+        }
+        return Ok(());
     }
 
+    let manifest_config = read_manifest_config(config, cargo_flags)
+        .map_err(|e| explain_cargo_error(e, &toolchain))?;
+    let mvp = manifest_config.wasix.compat == Some(Compat::Mvp);
+    if mvp && manifest_config.wasix.atomics == Some(true) {
+        bail!(
+            "`compat = \"mvp\"` in `[package.metadata.wasix]` can't be combined with \
+             `atomics = true`, the `atomics` target feature isn't part of the MVP"
+        );
+    }
+    let atomics = !no_atomics && !mvp && manifest_config.wasix.atomics.unwrap_or(true);
+
+    // The runtime comes from Cargo's runner variable for the target,
+    // `WASIX_RUNTIME` or the manifest, in that order, and defaults to
+    // `wasmer`. It is checked before anything is built.
+    let runtime = runtime::Runtime::from_env(
+        &runner_env_var,
+        manifest_config.wasix.runtime.as_deref(),
+        &manifest_config.wasix.runtime_args,
+    );
+    if runs_programs {
+        // The runner variable is specific to the target, so a runner
+        // configured for the other width is not picked up.
+        if !matches!(runtime.source, runtime::RuntimeSource::CargoRunner(_)) {
+            let other_target = target_triple(!is64bit);
+            let other_env_var = target_runner_env_var(other_target);
+            if env::var_os(&other_env_var).is_some() {
+                config.warn(&format!(
+                    "${other_env_var} is set but ignored when building for \
+                     `{target}`, set ${runner_env_var} to use a custom runtime"
+                ));
+            }
+        }
+        runtime.ensure_available(&runner_env_var)?;
+        if package_mode && !runtime.is_wasmer() {
+            bail!(
+                "`--package-mode` runs a package with `wasmer run`, so it can't be used \
+                 with `{}`",
+                runtime.program
+            );
+        }
+        if let Some(requirement) = &manifest_config.wasix.runtime_version {
+            match runtime.check_version(requirement) {
+                Err(err) if strict => return Err(err),
+                Err(err) => config.warn(&format!("{err:#}")),
+                Ok(()) => {}
+            }
+        }
+    }
+    let wasix_runner = runtime.program.as_str();
+    if config.local_tools {
+        let target_dir = cargo_flag_value(&args, "--target-dir")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| manifest_config.target_dir.clone());
+        config.set_tools_dir(target_dir.join("wasix-tools"));
+    }
+
+    let mut target_features = Vec::new();
+    if atomics {
+        target_features.push("atomics");
+    }
+    for feature in manifest_config.wasix.target_features() {
+        if wasm_opt_feature(feature).is_none() {
+            config.warn(&format!(
+                "unknown target feature `{feature}` in `[package.metadata.wasix]`"
+            ));
+        }
+        if !target_features.contains(&feature) {
+            target_features.push(feature);
+        }
+    }
+
+    let path_remaps = if remap_path || manifest_config.wasix.remap_path.unwrap_or(false) {
+        path_remaps(&manifest_config.workspace_root, config)
+    } else {
+        Vec::new()
+    };
+
     // Set some flags for rustc (only if RUSTFLAGS is not already set)
     if std::env::var("RUSTFLAGS").is_err() {
-        env::set_var("RUSTFLAGS", "-C target-feature=+atomics");
+        let mut rustflags = Vec::new();
+        if mvp {
+            // Disables all target features that LLVM enables by default.
+            rustflags.push("-C target-cpu=mvp".to_string());
+        }
+        if !target_features.is_empty() {
+            let features = target_features
+                .iter()
+                .map(|f| format!("+{f}"))
+                .collect::<Vec<_>>()
+                .join(",");
+            rustflags.push(format!("-C target-feature={features}"));
+        }
+        for (from, to) in path_remaps.iter() {
+            rustflags.push(format!("--remap-path-prefix={from}={to}"));
+        }
+        if !rustflags.is_empty() {
+            env::set_var("RUSTFLAGS", rustflags.join(" "));
+        }
+    } else {
+        if !manifest_config.wasix.target_features.is_empty() {
+            config.warn(
+                "`target-features` from `[package.metadata.wasix]` are ignored because \
+                 RUSTFLAGS is set",
+            );
+        }
+        if mvp {
+            config.warn(
+                "`compat = \"mvp\"` from `[package.metadata.wasix]` only applies to \
+                 `wasm-opt` because RUSTFLAGS is set, add `-C target-cpu=mvp` to it yourself",
+            );
+        }
+        if !path_remaps.is_empty() {
+            config.warn(
+                "source paths are not remapped by `rustc` because RUSTFLAGS is set, \
+                 add `--remap-path-prefix` to it yourself",
+            );
+        }
     }
 
+    let build_profile = profile_name(subcommand, &args, install_debug);
+
+    // Everything decided before running Cargo, in one place so that it can
+    // be pasted into bug reports.
+    config.verbose(|| {
+        let wasm_opt = config.get_wasm_opt();
+        // The settings of the root package, with the overrides for the
+        // profile.
+        let package = PackageConfig::new(
+            &manifest_config,
+            &manifest_config,
+            &build_profile,
+            &path_remaps,
+        );
+        let mut settings = vec![
+            ("target", target.to_string()),
+            ("profile", build_profile.clone()),
+            (
+                "toolchain",
+                format!("{} ({})", toolchain.name, toolchain.path.display()),
+            ),
+            (
+                "sysroot",
+                sysroot
+                    .as_ref()
+                    .map_or("none".to_string(), |s| s.display().to_string()),
+            ),
+            ("RUSTFLAGS", env::var("RUSTFLAGS").unwrap_or_default()),
+            ("target-features", target_features.join(",")),
+            (
+                "wasm-opt",
+                format!(
+                    "{} ({})",
+                    package.enable_wasm_opt(),
+                    wasm_opt.bin_path().display()
+                ),
+            ),
+            (
+                "wasm-opt-level",
+                manifest_config
+                    .wasix
+                    .wasm_opt_level
+                    .clone()
+                    .unwrap_or_else(|| "from profile".to_string()),
+            ),
+            ("wasm-opt-args", package.wasm_opt_args().join(" ")),
+        ];
+        if runs_programs {
+            let source = match &runtime.source {
+                runtime::RuntimeSource::CargoRunner(name) => format!("${name}"),
+                runtime::RuntimeSource::Env => "$WASIX_RUNTIME".to_string(),
+                runtime::RuntimeSource::Manifest => "[package.metadata.wasix]".to_string(),
+                runtime::RuntimeSource::Default => "default".to_string(),
+            };
+            let mut command = vec![runtime.program.as_str()];
+            command.extend(runtime.args.iter().map(String::as_str));
+            settings.push(("runtime", format!("{} ({source})", command.join(" "))));
+        }
+        for (key, value) in settings {
+            config.status("Config", &format!("{key} = {value}"));
+        }
+    });
+
     // Check the dependencies, if needed, before running cargo.
     if check_deps {
-        if let Err(err) = dependencies::check(config, target) {
+        if let Err(err) = dependencies::check(config, target, cargo_flags, explain) {
             config.warn(&format!("failed to check dependencies: {err}"));
         }
     }
 
+    // Cargo strips the debug info of release builds by default, and the
+    // linker drops the `name` section along with it, so it has to be kept
+    // for `wasm-opt` to have any names left to keep.
+    let strip_env_var = format!(
+        "CARGO_PROFILE_{}_STRIP",
+        build_profile.to_uppercase().replace('-', "_")
+    );
+    if env::var_os(&strip_env_var).is_none()
+        && manifest_config
+            .packages
+            .values()
+            .chain([&manifest_config])
+            .any(|p| p.wants_name_section(&build_profile))
+    {
+        cargo.env(&strip_env_var, "none");
+    }
+
     // Run the cargo commands
-    let build = execute_cargo(&mut cargo, config)?;
+    config.set_stage(Stage::Cargo);
+    let mut build = execute_cargo(&mut cargo, manifest_config, config)
+        .map_err(|e| explain_cargo_error(e, &toolchain))?;
+    config.set_stage(Stage::PostProcess);
+    build.profile_name = build_profile.clone();
+    build.path_remaps = path_remaps;
+
+    // RUSTFLAGS set by the user replace ours, and may not enable `+atomics`,
+    // in which case the memory isn't expected to be shared.
+    let atomics_compiled =
+        atomics && env::var("RUSTFLAGS").is_ok_and(|flags| flags.contains("+atomics"));
+    let shared_memory_check = if !atomics_compiled {
+        SharedMemoryCheck::Off
+    } else if deny_unshared_memory {
+        SharedMemoryCheck::Deny
+    } else {
+        SharedMemoryCheck::Warn
+    };
 
-    config.info("Post-processing WebAssembly files");
+    if !build.wasms.is_empty() {
+        config.info("Post-processing WebAssembly files");
+    } else if !matches!(
+        subcommand,
+        Subcommand::Check | Subcommand::Tree | Subcommand::Fix
+    ) {
+        // Distinguish "built fine, nothing to do" (e.g. a library or
+        // proc-macro crate) from a silently broken build.
+        config.info("no WebAssembly artifacts to post-process");
+    }
 
-    for (wasm, profile, fresh) in build.wasms.iter() {
+    let mut summary = BuildSummary {
+        profile: build_profile,
+        artifacts: Vec::new(),
+    };
+    let process_artifact = |artifact: &WasmArtifact, summary: &mut BuildSummary| -> Result<()> {
+        let WasmArtifact {
+            path: wasm,
+            name,
+            bin,
+            profile,
+            fresh,
+            package_id,
+        } = artifact;
+        let package = build.package_config(package_id);
         // Cargo will always overwrite our `wasm` above with its own internal
         // cache. It's internal cache largely uses hard links.
         //
@@ -280,30 +840,298 @@ fn rmain(config: &mut Config) -> Result<()> {
         let temporary_rustc = wasm.with_extension("rustc.wasm");
         let temporary_wasi = wasm.with_extension("wasi.wasm");
 
-        drop(fs::remove_file(&temporary_rustc));
-        fs::rename(wasm, &temporary_rustc)?;
-        if !*fresh || !temporary_wasi.exists() {
-            let result = process_wasm(&temporary_wasi, &temporary_rustc, profile, &build, config);
-            result.with_context(|| {
-                format!("failed to process wasm at `{}`", temporary_rustc.display())
-            })?;
+        // In a fresh build Cargo has put its own module back at `wasm`, which
+        // is still the `*.rustc.wasm` we processed if nothing changed since.
+        // Then the module isn't processed again, and the copies of the final
+        // module aren't rewritten either, so that file watchers don't see a
+        // change and rebuild in a loop.
+        let in_place = same_file::is_same_file(wasm, &temporary_wasi).unwrap_or(false);
+        let unchanged = *fresh
+            && temporary_wasi.exists()
+            && (in_place
+                || temporary_rustc.exists()
+                    && (same_file::is_same_file(wasm, &temporary_rustc).unwrap_or(false)
+                        || utils::same_contents(wasm, &temporary_rustc).unwrap_or(false)));
+        let mut optimized = false;
+        if !unchanged {
+            drop(fs::remove_file(&temporary_rustc));
+            fs::rename(wasm, &temporary_rustc)?;
+            if !*fresh || !temporary_wasi.exists() {
+                let options = package.postprocess_options(profile, shared_memory_check);
+                let result =
+                    process_wasm(&temporary_wasi, &temporary_rustc, wasm, &options, config);
+                let timings = result.with_context(|| {
+                    format!("failed to process wasm at `{}`", temporary_rustc.display())
+                })?;
+                optimized = timings.optimized;
+                if timings_requested || config.is_verbose() {
+                    config.status(
+                        "Timing",
+                        &format!(
+                            "{}: demangle {:.2}s, wasm-opt {:.2}s",
+                            wasm.display(),
+                            timings.demangle.as_secs_f64(),
+                            timings.wasm_opt.as_secs_f64(),
+                        ),
+                    );
+                }
+            }
+        }
+        if !in_place {
+            drop(fs::remove_file(wasm));
+            // `*.wasi.wasm` has to stay around to restore `wasm` in fresh
+            // builds, so without hard links (e.g. on some overlay filesystems)
+            // this takes twice the disk space.
+            if let Err(err) = fs::hard_link(&temporary_wasi, wasm) {
+                config.verbose(|| {
+                    config.status(
+                        "Copying",
+                        &format!(
+                            "{} to {} as it can't be hard linked ({err})",
+                            temporary_wasi.display(),
+                            wasm.display()
+                        ),
+                    )
+                });
+                fs::copy(&temporary_wasi, wasm)?;
+                // Like a hard link, the copy keeps the mtime of the module.
+                let modified = fs::metadata(&temporary_wasi)?.modified()?;
+                fs::File::options()
+                    .write(true)
+                    .open(wasm)?
+                    .set_modified(modified)?;
+            }
+        }
+
+        if let Some(hook) = &build.manifest_config.wasix.post_build {
+            run_post_build(hook, wasm, name, &summary.profile, target, &build, config)?;
+        }
+
+        if let Some(compression) = compress.or(build.manifest_config.wasix.compress) {
+            if !unchanged || !wasm.with_extension(compression.extension()).exists() {
+                compress_wasm(wasm, compression, config)
+                    .with_context(|| format!("failed to compress `{}`", wasm.display()))?;
+            }
+        }
+
+        // Test harnesses are only ever run as core modules.
+        let component = if !profile.test && (component_flag || package.component()) {
+            let path = component::component_path(wasm);
+            if !*fresh || !path.exists() {
+                component::new_component(wasm, *bin, config).with_context(|| {
+                    format!("failed to create a component from `{}`", wasm.display())
+                })?;
+            }
+            Some(path)
+        } else {
+            None
+        };
+
+        if json_summary {
+            summary.artifacts.push(ArtifactSummary {
+                name: name.clone(),
+                path: wasm.clone(),
+                size_before: fs::metadata(&temporary_rustc)?.len(),
+                size_after: fs::metadata(wasm)?.len(),
+                rustc_path: temporary_rustc,
+                wasm_opt: optimized,
+                fresh: *fresh,
+                component_path: component,
+            });
+        }
+        Ok(())
+    };
+    let mut failed = Vec::new();
+    for artifact in build.wasms.iter() {
+        match process_artifact(artifact, &mut summary) {
+            Ok(()) => {}
+            Err(err) if keep_going => {
+                config.print_error(&err);
+                failed.push(artifact.path.display().to_string());
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    if !failed.is_empty() {
+        bail!(
+            "failed to post-process {} of {} WebAssembly files:\n  {}",
+            failed.len(),
+            build.wasms.len(),
+            failed.join("\n  ")
+        );
+    }
+
+    if let Some(out_dir) = out_dir
+        .as_deref()
+        .or(build.manifest_config.wasix.out_dir.as_deref())
+    {
+        copy_to_out_dir(&build, out_dir, component_flag, config)?;
+    }
+
+    if subcommand == Subcommand::Install {
+        let explicit_bins =
+            args.iter().any(|a| a == "--bins") || cargo_flag_value(&args, "--bin").is_some();
+        let launcher = launcher.then(|| Launcher {
+            runner: wasix_runner,
+            enable_threads: atomics_compiled
+                && runtime.is_wasmer()
+                && runtime_supports_threads(wasix_runner, config),
+        });
+        install_bins(
+            &build,
+            install_root.as_deref(),
+            explicit_bins,
+            launcher,
+            config,
+        )?;
+    }
+
+    if subcommand == Subcommand::Size {
+        for artifact in build.wasms.iter() {
+            print!("{}", size::report(&artifact.path, &size_options)?);
+        }
+    }
+
+    // Printed before anything is run, so wrappers can read it without having
+    // to wait for the programs to exit.
+    if json_summary {
+        println!("{}", serde_json::to_string(&summary)?);
+    }
+
+    // The programs that would be run, or otherwise the final `*.wasm` files.
+    if print_artifacts {
+        let cwd = env::current_dir()?;
+        let paths: Vec<PathBuf> = if build.runs.is_empty() {
+            build.wasms.iter().map(|w| w.path.clone()).collect()
+        } else {
+            build
+                .runs
+                .iter()
+                .filter_map(|run| run.args.first())
+                .map(PathBuf::from)
+                .collect()
+        };
+        for path in paths {
+            println!("{}", cwd.join(path).display());
+        }
+        return Ok(());
+    }
+
+    // Preparing to run the programs, for example reading the `.env` file.
+    config.set_stage(Stage::Setup);
+
+    if enable_net && !runtime.is_wasmer() && !build.runs.is_empty() {
+        config.warn(&format!(
+            "`--net` is only supported with `wasmer`, it is ignored for `{wasix_runner}`"
+        ));
+    }
+    if !pass_env.is_empty() && !runtime.is_wasmer() && !build.runs.is_empty() {
+        config.warn(&format!(
+            "`--pass-env` has no effect for `{wasix_runner}`, it decides itself which \
+             variables of its environment the guest sees"
+        ));
+    }
+
+    let mut guest_env =
+        match env_file
+            .as_deref()
+            .or(build.manifest_config.wasix.env_file.as_deref())
+        {
+            Some(path) if !build.runs.is_empty() => dotenv::read(path)?,
+            _ => Vec::new(),
+        };
+    // Like `cargo run`, forward a few host variables to the guest, unless the
+    // `.env` file already sets them. Other runtimes inherit the environment of
+    // `cargo wasix` anyway, so nothing is forwarded to them.
+    let allowlist = match &build.manifest_config.wasix.pass_env {
+        Some(keys) => keys.iter().map(String::as_str).collect::<Vec<_>>(),
+        None => DEFAULT_PASS_ENV.to_vec(),
+    };
+    let forwarded = allowlist
+        .into_iter()
+        .chain(pass_env.iter().map(String::as_str))
+        .filter(|_| runtime.is_wasmer());
+    for key in forwarded {
+        if guest_env.iter().any(|(k, _)| k == key) {
+            continue;
+        }
+        if let Ok(value) = env::var(key) {
+            guest_env.push((key.to_string(), value));
         }
-        drop(fs::remove_file(wasm));
-        fs::hard_link(&temporary_wasi, wasm)
-            .or_else(|_| fs::copy(&temporary_wasi, wasm).map(|_| ()))?;
     }
 
+    // Modules compiled with `+atomics` need a runtime that supports threads.
+    // Older `wasmer` releases don't know about `--enable-threads` and would
+    // trap on the shared memory in confusing ways, so probe for it once
+    // before running anything. Modules without atomics don't get it.
+    let enable_threads = atomics_compiled
+        && runtime.is_wasmer()
+        && !build.runs.is_empty()
+        && runtime_supports_threads(wasix_runner, config);
+
+    config.set_stage(Stage::Run);
     for run in build.runs.iter() {
+        // Cargo builds targets with `harness = false` in test mode too, but
+        // they don't take libtest's arguments.
+        let harness = build.wasms.iter().any(|w| {
+            w.profile.test
+                && run.args.first().is_some_and(|p| w.path.ends_with(p))
+                && !build
+                    .manifest_config
+                    .packages
+                    .get(&w.package_id)
+                    .is_some_and(|p| p.no_harness.contains(&w.name))
+        });
+        let mut run_args = run.args.clone();
+        run_args.extend(harness_default_args(
+            run.kind,
+            harness,
+            &run.args[1..],
+            runtime.is_wasmer(),
+            enable_threads,
+            &guest_env,
+        ));
+        let run = run_args;
         config.status("Running", &format!("`{}`", run.join(" ")));
-        let mut cmd = Command::new(&wasix_runner);
+        let mut cmd = Command::new(wasix_runner);
+        // Removed once the program exited.
+        let mut package = None;
+        if package_mode {
+            cmd.arg("run");
+            package = Some(wasmer_package(Path::new(&run[0]))?);
+        }
 
-        if wasix_runner == "wasmer" {
+        if enable_threads {
             cmd.arg("--enable-threads");
         }
+        // Mirror `cargo run`, where relative paths resolve against the
+        // directory `cargo` was invoked from.
+        if runtime.is_wasmer() && !no_preopen {
+            cmd.arg("--dir=.");
+        }
+        if runtime.is_wasmer() && enable_net {
+            cmd.arg("--net");
+        }
+        // `wasmer` doesn't pass its own environment to the guest, other
+        // runtimes get the variables in their environment instead.
+        for (key, value) in guest_env.iter() {
+            if runtime.is_wasmer() {
+                cmd.arg("--env").arg(format!("{key}={value}"));
+            } else {
+                cmd.env(key, value);
+            }
+        }
 
-        cmd.arg("--")
-            .args(run.iter())
-            .run()
+        // After the flags for `wasmer`, so that they can be overridden.
+        cmd.args(runtime.args.iter());
+
+        cmd.arg("--");
+        match &package {
+            Some(dir) => cmd.arg(dir.path()).args(&run[1..]),
+            None => cmd.args(run.iter()),
+        };
+        print_env_overrides(&cmd, config);
+        cmd.run()
             .map_err(|e| utils::hide_normal_process_exit(e, config))?;
     }
 
@@ -313,6 +1141,186 @@ fn rmain(config: &mut Config) -> Result<()> {
     Ok(())
 }
 
+/// Creates a temporary directory with the module at `wasm` and a minimal
+/// `wasmer.toml` exposing it as a command, which `wasmer run` runs like a
+/// published package.
+fn wasmer_package(wasm: &Path) -> Result<tempfile::TempDir> {
+    #[derive(serde::Serialize)]
+    struct WasmerToml<'a> {
+        package: Package<'a>,
+        module: [Module<'a>; 1],
+        command: [PackageCommand<'a>; 1],
+    }
+    #[derive(serde::Serialize)]
+    struct Package<'a> {
+        name: &'a str,
+        version: &'a str,
+        description: &'a str,
+    }
+    #[derive(serde::Serialize)]
+    struct Module<'a> {
+        name: &'a str,
+        source: &'a str,
+        abi: &'a str,
+    }
+    #[derive(serde::Serialize)]
+    struct PackageCommand<'a> {
+        name: &'a str,
+        module: &'a str,
+        runner: &'a str,
+    }
+
+    let name = wasm
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .context("the name of the module is not valid unicode")?;
+    let source = format!("{name}.wasm");
+    let manifest = WasmerToml {
+        package: Package {
+            name,
+            version: "0.0.0",
+            description: "Generated by `cargo wasix run --package-mode`",
+        },
+        module: [Module {
+            name,
+            source: &source,
+            abi: "wasi",
+        }],
+        command: [PackageCommand {
+            name,
+            module: name,
+            runner: "wasi",
+        }],
+    };
+
+    let dir = tempfile::Builder::new()
+        .prefix("cargo-wasix-package")
+        .tempdir()
+        .context("failed to create temporary directory")?;
+    fs::copy(wasm, dir.path().join(&source))
+        .context(format!("failed to copy `{}`", wasm.display()))?;
+    fs::write(dir.path().join("wasmer.toml"), toml::to_string(&manifest)?)
+        .context("failed to write `wasmer.toml`")?;
+    Ok(dir)
+}
+
+/// Prints the environment variables injected into `cmd` at the `-vv`
+/// verbosity level.
+fn print_env_overrides(cmd: &Command, config: &Config) {
+    config.very_verbose(|| {
+        for var in utils::env_overrides(cmd) {
+            config.status("Env", &var);
+        }
+    });
+}
+
+/// Checks whether the `wasmer` runtime at `runner` understands
+/// `--enable-threads`.
+///
+/// If the probe itself fails we assume threads are supported, since the
+/// runtime will produce its own error in that case. Otherwise a warning is
+/// printed explaining that atomics-enabled modules need a threads-capable
+/// runtime.
+fn runtime_supports_threads(runner: &str, config: &Config) -> bool {
+    let output = match Command::new(runner).arg("run").arg("--help").output() {
+        Ok(output) if output.status.success() => output,
+        _ => return true,
+    };
+    if String::from_utf8_lossy(&output.stdout).contains("--enable-threads") {
+        return true;
+    }
+    config.warn(&format!(
+        "`{runner}` does not appear to support threads, but WASIX modules are \
+         compiled with the `atomics` target feature and require a \
+         threads-capable runtime; the module may trap when it is run\n\
+         upgrade with `wasmer self-update` or `curl https://get.wasmer.io -sSfL | sh`"
+    ));
+    false
+}
+
+/// Returns the `cargo` command that `subcommand` runs.
+fn cargo_command(subcommand: Subcommand) -> &'static str {
+    match subcommand {
+        Subcommand::Build => "build",
+        Subcommand::BuildToolchain => "build-toolchain",
+        Subcommand::DownloadToolchain => "download-toolchain",
+        Subcommand::Check => "check",
+        Subcommand::Fix => "fix",
+        Subcommand::Test => "test",
+        Subcommand::Tree => "tree",
+        Subcommand::Update => "update",
+        Subcommand::Bench => "bench",
+        Subcommand::Run => "run",
+        // Installing builds in the current workspace, see `install_bins`.
+        Subcommand::Install => "build",
+        // The size is reported for the modules of a regular build.
+        Subcommand::Size => "build",
+    }
+}
+
+/// Returns the target triple that the 32-bit or 64-bit subcommands build for.
+fn target_triple(is64bit: bool) -> &'static str {
+    if is64bit {
+        "wasm64-wasmer-wasi"
+    } else {
+        "wasm32-wasmer-wasi"
+    }
+}
+
+/// Returns the name of the environment variable Cargo reads the runner for
+/// `target` from, e.g. `CARGO_TARGET_WASM64_WASMER_WASI_RUNNER`.
+fn target_runner_env_var(target: &str) -> String {
+    format!(
+        "CARGO_TARGET_{}_RUNNER",
+        target.to_uppercase().replace('-', "_")
+    )
+}
+
+/// Returns the name of the Cargo profile `subcommand` builds with.
+fn profile_name(subcommand: Subcommand, args: &[OsString], install_debug: bool) -> String {
+    if let Some(profile) = cargo_flag_value(args, "--profile") {
+        return profile;
+    }
+    if args.iter().any(|a| a == "--release") {
+        return "release".to_string();
+    }
+    match subcommand {
+        Subcommand::Install if !install_debug => "release",
+        Subcommand::Test => "test",
+        Subcommand::Bench => "bench",
+        _ => "dev",
+    }
+    .to_string()
+}
+
+/// Returns the value of a `cargo` flag such as `--profile` in `args`, given
+/// either as `--flag value` or `--flag=value`. If the flag is repeated, the
+/// last value wins.
+fn cargo_flag_value(args: &[OsString], flag: &str) -> Option<String> {
+    cargo_flag_values(args, flag).pop()
+}
+
+/// Returns all values of a `cargo` flag that may be repeated, such as
+/// `--target`, in the order they are given.
+///
+/// Arguments after `--` are not considered since they belong to the program
+/// being run.
+fn cargo_flag_values(args: &[OsString], flag: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut args = args
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .take_while(|arg| arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            values.extend(args.next().map(|v| v.into_owned()));
+        } else if let Some(value) = arg.strip_prefix(flag).and_then(|v| v.strip_prefix('=')) {
+            values.push(value.to_string());
+        }
+    }
+    values
+}
+
 pub const HELP: &str = include_str!("txt/help.txt");
 
 fn print_help() -> ! {
@@ -320,34 +1328,348 @@ fn print_help() -> ! {
     std::process::exit(0)
 }
 
-#[derive(Default, Debug)]
-struct CargoBuild {
-    // The version of `wasm-bindgen` used in this build, if any.
-    wasm_bindgen: Option<String>,
-    // The `*.wasm` artifacts we found during this build, in addition to the
-    // profile that they were built with and whether or not it was `fresh`
-    // during this build.
-    wasms: Vec<(PathBuf, Profile, bool)>,
-    // executed commands as part of the cargo build
-    runs: Vec<Vec<String>>,
-    // Configuration we found in the `Cargo.toml` workspace manifest for these
-    // builds.
-    manifest_config: ManifestConfig,
+#[derive(Default, Debug)]
+struct CargoBuild {
+    // The version of `wasm-bindgen` used in this build, if any.
+    wasm_bindgen: Option<String>,
+    // The `*.wasm` artifacts we found during this build.
+    wasms: Vec<WasmArtifact>,
+    // executed commands as part of the cargo build
+    runs: Vec<ProgramRun>,
+    // Configuration we found in the `Cargo.toml` workspace manifest for these
+    // builds.
+    manifest_config: ManifestConfig,
+    // The name of the Cargo profile of this build.
+    profile_name: String,
+    // Absolute paths and what they are replaced with, see `path_remaps`.
+    path_remaps: Vec<(String, String)>,
+}
+
+/// A program Cargo asked the runner shim to run.
+#[derive(Debug)]
+struct ProgramRun {
+    kind: RunKind,
+    // The path of the module followed by its arguments.
+    args: Vec<String>,
+}
+
+/// What `cargo` runs a program for, which the runner shim learns from the
+/// value of `__CARGO_WASIX_RUNNER_SHIM`.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum RunKind {
+    #[default]
+    Run,
+    Test,
+    Bench,
+}
+
+impl RunKind {
+    fn from_shim_env(value: &str) -> RunKind {
+        match value {
+            "test" => RunKind::Test,
+            "bench" => RunKind::Bench,
+            _ => RunKind::Run,
+        }
+    }
+}
+
+/// Arguments appended to those Cargo passes to a test or bench `harness`.
+///
+/// libtest runs tests on as many threads as there are cores, which hangs or
+/// traps when `wasmer` doesn't run the module with threads, so tests are run
+/// one at a time then. Whether another runtime supports threads isn't known,
+/// so its arguments are left alone. Passing `--test-threads` or setting
+/// `RUST_TEST_THREADS` for the guest overrides this.
+fn harness_default_args(
+    kind: RunKind,
+    harness: bool,
+    args: &[String],
+    wasmer: bool,
+    threads: bool,
+    guest_env: &[(String, String)],
+) -> Vec<String> {
+    if kind == RunKind::Run || !harness || !wasmer || threads {
+        return Vec::new();
+    }
+    let configured = args
+        .iter()
+        .any(|a| a == "--test-threads" || a.starts_with("--test-threads="))
+        || guest_env.iter().any(|(k, _)| k == "RUST_TEST_THREADS");
+    if configured {
+        return Vec::new();
+    }
+    vec!["--test-threads=1".to_string()]
+}
+
+/// A `*.wasm` file produced by Cargo.
+#[derive(Debug)]
+struct WasmArtifact {
+    path: PathBuf,
+    // The name of the Cargo target (binary, example, test, ...) it belongs to.
+    name: String,
+    // Whether that target is a binary.
+    bin: bool,
+    // The profile it was built with.
+    profile: Profile,
+    // The Cargo package it belongs to, see `CargoBuild::package_config`.
+    package_id: String,
+    // Whether or not it was `fresh` during this build.
+    fresh: bool,
+}
+
+/// Summary of the post-processed artifacts, printed with `--cargo-wasix-json`.
+#[derive(serde::Serialize)]
+struct BuildSummary {
+    // The name of the Cargo profile, such as `dev` or `release`.
+    profile: String,
+    artifacts: Vec<ArtifactSummary>,
+}
+
+#[derive(serde::Serialize)]
+struct ArtifactSummary {
+    name: String,
+    // The final module.
+    path: PathBuf,
+    // The module as produced by `rustc`, before post-processing.
+    rustc_path: PathBuf,
+    size_before: u64,
+    size_after: u64,
+    // Whether `wasm-opt` optimized the module in this build.
+    wasm_opt: bool,
+    // Whether the module was already up to date.
+    fresh: bool,
+    // The component wrapping the module, with `component = true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    component_path: Option<PathBuf>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct Profile {
+    opt_level: String,
+    debuginfo: Option<u32>,
+    test: bool,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct ManifestConfig {
+    wasm_opt: Option<bool>,
+    wasm_name_section: Option<bool>,
+    wasm_producers_section: Option<bool>,
+    // Settings from the `[package.metadata.wasix]` table.
+    #[serde(default)]
+    wasix: WasixConfig,
+    // The root and target directory of the workspace, from `cargo metadata`.
+    #[serde(skip)]
+    workspace_root: PathBuf,
+    #[serde(skip)]
+    target_dir: PathBuf,
+    // The configuration of each package of the workspace by package ID, with
+    // the settings of the root package as defaults.
+    #[serde(skip)]
+    packages: HashMap<String, ManifestConfig>,
+    // The targets of the package that are built with `harness = false`.
+    #[serde(skip)]
+    no_harness: Vec<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct WasixConfig {
+    // Whether to compile with the `atomics` target feature, defaults to `true`.
+    atomics: Option<bool>,
+    // Directory to copy the final `*.wasm` files to, relative to the
+    // workspace root.
+    out_dir: Option<PathBuf>,
+    // Whether to move DWARF debug info into a `*.debug.wasm` sidecar file.
+    split_debuginfo: Option<bool>,
+    // A `.env` file, relative to the workspace root, whose variables are
+    // passed to programs that are run.
+    env_file: Option<PathBuf>,
+    // Host environment variables passed to programs that are run, defaults to
+    // `DEFAULT_PASS_ENV`.
+    pass_env: Option<Vec<String>>,
+    // Additional wasm target features to enable, such as `simd128`.
+    #[serde(default)]
+    target_features: Vec<String>,
+    // A preset of target features for broader runtime support.
+    compat: Option<Compat>,
+    // Additional arguments passed to `wasm-opt`.
+    #[serde(default)]
+    wasm_opt_args: Vec<String>,
+    // The `-O` level of `wasm-opt`, instead of the `opt-level` of the profile.
+    #[serde(default, deserialize_with = "deserialize_opt_level")]
+    wasm_opt_level: Option<String>,
+    // The runtime programs are run with, unless set by the environment.
+    runtime: Option<String>,
+    // Arguments always passed to `runtime`.
+    #[serde(default)]
+    runtime_args: Vec<String>,
+    // The versions of the runtime that programs may be run with.
+    runtime_version: Option<cargo_metadata::semver::VersionReq>,
+    // A program, relative to the workspace root, that is run for every final
+    // `*.wasm` file.
+    post_build: Option<PathBuf>,
+    // Writes a compressed copy of the final `*.wasm` files next to them.
+    compress: Option<Compression>,
+    // Whether absolute source paths are replaced by relative ones.
+    remap_path: Option<bool>,
+    // What the `producers` section contains, if it is kept.
+    #[serde(default)]
+    producers: Producers,
+    // Whether names are demangled before or after running `wasm-opt`.
+    #[serde(default)]
+    process_order: ProcessOrder,
+    // Whether the `asyncify_*` exports added by `wasm-opt` are removed.
+    hide_asyncify_exports: Option<bool>,
+    // Whether a WASI Preview 2 component is built from the final `*.wasm`
+    // files as well.
+    component: Option<bool>,
+    // Overrides for Cargo profiles, from `[package.metadata.wasix.profile.<name>]`.
+    #[serde(default)]
+    profile: HashMap<String, ProfileConfig>,
+}
+
+/// Settings that can be overridden for a single Cargo profile.
+#[derive(serde::Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+struct ProfileConfig {
+    wasm_opt: Option<bool>,
+    wasm_opt_args: Option<Vec<String>>,
+    wasm_name_section: Option<bool>,
+    wasm_producers_section: Option<bool>,
+}
+
+impl ManifestConfig {
+    /// Whether `wasm-name-section = true` is set for `profile`, as opposed
+    /// to being unset.
+    fn wants_name_section(&self, profile: &str) -> bool {
+        self.wasix
+            .profile
+            .get(profile)
+            .and_then(|p| p.wasm_name_section)
+            .or(self.wasm_name_section)
+            == Some(true)
+    }
+}
+
+impl WasixConfig {
+    /// Returns the extra target features, without any leading `+`.
+    fn target_features(&self) -> impl Iterator<Item = &str> {
+        self.target_features
+            .iter()
+            .map(|f| f.trim_start_matches('+'))
+    }
+}
+
+/// Deserializes an optimization level, which like Cargo's `opt-level` can be
+/// a number or a string.
+fn deserialize_opt_level<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OptLevel {
+        Number(u32),
+        String(String),
+    }
+
+    let level = match <OptLevel as serde::Deserialize>::deserialize(deserializer)? {
+        OptLevel::Number(n) => n.to_string(),
+        OptLevel::String(s) => s,
+    };
+    if !["0", "1", "2", "3", "4", "s", "z"].contains(&level.as_str()) {
+        return Err(serde::de::Error::custom(format!(
+            "invalid `wasm-opt-level` `{level}`, expected 0, 1, 2, 3, 4, \"s\" or \"z\""
+        )));
+    }
+    Ok(Some(level))
+}
+
+/// Contents of the `producers` custom section, see `producers`.
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Producers {
+    /// Whatever `rustc`, the linker and other tools put there.
+    #[default]
+    Full,
+    /// Only the language and `cargo-wasix`, without any versions, so that
+    /// builds with other tool versions produce the same bytes.
+    Minimal,
+}
+
+/// The order of the post-processing steps, see `process-order`.
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProcessOrder {
+    /// Names are demangled before `wasm-opt` runs.
+    #[default]
+    DemangleThenOpt,
+    /// `wasm-opt` runs first, and the names it kept are demangled afterwards.
+    OptThenDemangle,
+}
+
+/// A preset of target features, see `compat`.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Compat {
+    /// Only the features of the WebAssembly MVP, plus `target-features`.
+    Mvp,
+}
+
+/// Format of the compressed copy of the final `*.wasm` files, see `compress`.
+#[derive(serde::Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum Compression {
+    Gzip,
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
-struct Profile {
-    opt_level: String,
-    debuginfo: Option<u32>,
-    test: bool,
+impl Compression {
+    fn from_name(name: &str) -> Result<Compression> {
+        match name {
+            "gzip" => Ok(Compression::Gzip),
+            _ => bail!("unsupported compression `{name}`, the only supported one is `gzip`"),
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => "wasm.gz",
+        }
+    }
 }
 
-#[derive(serde::Deserialize, Debug, Default)]
-#[serde(rename_all = "kebab-case")]
-struct ManifestConfig {
-    wasm_opt: Option<bool>,
-    wasm_name_section: Option<bool>,
-    wasm_producers_section: Option<bool>,
+/// Host environment variables passed to programs run through `wasmer` unless
+/// `pass-env` is configured.
+const DEFAULT_PASS_ENV: &[&str] = &["RUST_LOG", "RUST_BACKTRACE"];
+
+/// WebAssembly target features known to `rustc`, along with the name of the
+/// corresponding `--enable-*` flag of `wasm-opt`.
+const TARGET_FEATURES: &[(&str, &str)] = &[
+    ("atomics", "threads"),
+    ("bulk-memory", "bulk-memory"),
+    ("exception-handling", "exception-handling"),
+    ("extended-const", "extended-const"),
+    ("multimemory", "multimemory"),
+    ("multivalue", "multivalue"),
+    ("mutable-globals", "mutable-globals"),
+    ("nontrapping-fptoint", "nontrapping-float-to-int"),
+    ("reference-types", "reference-types"),
+    ("relaxed-simd", "relaxed-simd"),
+    ("sign-ext", "sign-ext"),
+    ("simd128", "simd"),
+    ("tail-call", "tail-call"),
+];
+
+/// Returns the `wasm-opt` feature name for the `rustc` target `feature`, or
+/// `None` if the feature is unknown.
+fn wasm_opt_feature(feature: &str) -> Option<&'static str> {
+    TARGET_FEATURES
+        .iter()
+        .find(|(name, _)| *name == feature)
+        .map(|(_, wasm_opt)| *wasm_opt)
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -356,105 +1678,398 @@ enum CargoMessage {
     CompilerArtifact {
         filenames: Vec<String>,
         package_id: String,
+        target: ArtifactTarget,
         profile: Profile,
         fresh: bool,
     },
     BuildScriptExecuted,
     RunWithArgs {
         args: Vec<String>,
+        #[serde(default)]
+        kind: RunKind,
     },
     BuildFinished,
+    /// Sent with `--future-incompat-report`, Cargo prints the report to
+    /// stderr itself.
+    FutureIncompatReport,
+    /// Messages that newer versions of Cargo may add, which we don't need.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct ArtifactTarget {
+    name: String,
+    #[serde(default)]
+    kind: Vec<String>,
 }
 
 impl CargoBuild {
+    /// Returns the configuration that applies to the artifacts of the package
+    /// `package_id`.
+    fn package_config(&self, package_id: &str) -> PackageConfig<'_> {
+        let manifest = self
+            .manifest_config
+            .packages
+            .get(package_id)
+            .unwrap_or(&self.manifest_config);
+        PackageConfig::new(
+            manifest,
+            &self.manifest_config,
+            &self.profile_name,
+            &self.path_remaps,
+        )
+    }
+}
+
+/// How the artifacts of a single package are post-processed, from its
+/// `[package.metadata]` and that of the workspace root package.
+struct PackageConfig<'a> {
+    manifest: &'a ManifestConfig,
+    // Overrides in `manifest` for the profile of this build.
+    profile: ProfileConfig,
+    // Settings that apply to the whole build, such as the target features
+    // that are passed to `rustc`.
+    workspace: &'a ManifestConfig,
+    path_remaps: &'a [(String, String)],
+}
+
+impl<'a> PackageConfig<'a> {
+    /// Returns the configuration of the package with `manifest` when built
+    /// with the Cargo profile `profile_name`.
+    fn new(
+        manifest: &'a ManifestConfig,
+        workspace: &'a ManifestConfig,
+        profile_name: &str,
+        path_remaps: &'a [(String, String)],
+    ) -> Self {
+        PackageConfig {
+            manifest,
+            profile: manifest
+                .wasix
+                .profile
+                .get(profile_name)
+                .cloned()
+                .unwrap_or_default(),
+            workspace,
+            path_remaps,
+        }
+    }
+}
+
+impl PackageConfig<'_> {
     fn enable_name_section(&self, profile: &Profile) -> bool {
         match profile.debuginfo {
-            Some(0) | None => self.manifest_config.wasm_name_section.unwrap_or(true),
+            Some(0) | None => self
+                .profile
+                .wasm_name_section
+                .or(self.manifest.wasm_name_section)
+                .unwrap_or(true),
             Some(_) => true,
         }
     }
 
+    fn enable_wasm_opt(&self) -> bool {
+        self.profile
+            .wasm_opt
+            .or(self.manifest.wasm_opt)
+            .unwrap_or(true)
+    }
+
+    fn wasm_opt_args(&self) -> &[String] {
+        self.profile
+            .wasm_opt_args
+            .as_deref()
+            .unwrap_or(&self.manifest.wasix.wasm_opt_args)
+    }
+
+    /// Returns the `-O` level `wasm-opt` optimizes with, unless DWARF debug
+    /// information has to be kept.
+    fn wasm_opt_level<'a>(&'a self, profile: &'a Profile) -> &'a str {
+        self.manifest
+            .wasix
+            .wasm_opt_level
+            .as_deref()
+            .unwrap_or(&profile.opt_level)
+    }
+
+    fn component(&self) -> bool {
+        self.manifest.wasix.component.unwrap_or(false)
+    }
+
+    fn split_debuginfo(&self) -> bool {
+        self.manifest.wasix.split_debuginfo.unwrap_or(false)
+    }
+
+    /// Returns how the artifacts built with `profile` are post-processed.
+    fn postprocess_options(
+        &self,
+        profile: &Profile,
+        shared_memory: SharedMemoryCheck,
+    ) -> PostprocessOptions {
+        PostprocessOptions {
+            opt_level: self.wasm_opt_level(profile).to_string(),
+            debuginfo: !matches!(profile.debuginfo, Some(0) | None),
+            split_debuginfo: self.split_debuginfo(),
+            wasm_opt: self.enable_wasm_opt(),
+            wasm_opt_args: self.wasm_opt_args().to_vec(),
+            target_features: self
+                .workspace
+                .wasix
+                .target_features()
+                .map(String::from)
+                .collect(),
+            mvp: self.workspace.wasix.compat == Some(Compat::Mvp),
+            name_section: self.enable_name_section(profile),
+            producers_section: self.enable_producers_section(profile),
+            producers: self.manifest.wasix.producers,
+            process_order: self.manifest.wasix.process_order,
+            hide_asyncify_exports: self.manifest.wasix.hide_asyncify_exports.unwrap_or(false),
+            shared_memory,
+            path_remaps: self.path_remaps.to_vec(),
+            // `--quiet` is already part of the `Config` the module is processed
+            // with, this is only read by `postprocess_wasm`.
+            quiet: false,
+        }
+    }
+
     fn enable_producers_section(&self, profile: &Profile) -> bool {
         match profile.debuginfo {
-            Some(0) | None => self.manifest_config.wasm_producers_section.unwrap_or(true),
+            Some(0) | None => self
+                .profile
+                .wasm_producers_section
+                .or(self.manifest.wasm_producers_section)
+                .unwrap_or(true),
             Some(_) => true,
         }
     }
 }
 
+/// What to do when a module compiled with `+atomics` doesn't have a shared
+/// memory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SharedMemoryCheck {
+    /// The module isn't checked, for modules compiled without `+atomics`.
+    Off,
+    /// A warning is printed.
+    #[default]
+    Warn,
+    /// Post-processing fails.
+    Deny,
+}
+
+/// Wall-clock time spent in each step of [`process_wasm`]. Parsing and
+/// emitting the module isn't counted towards either.
+struct ProcessTimings {
+    demangle: Duration,
+    wasm_opt: Duration,
+    // Whether `wasm-opt` optimized the module, as opposed to being disabled
+    // or only running its `-O0` passes.
+    optimized: bool,
+}
+
 /// Process a wasm file that doesn't use `wasm-bindgen`, using `walrus` instead.
 ///
 /// This will load up the module and do things like:
 ///
 /// * Unconditionally demangle all Rust function names.
-/// * Use `profile` to optionally drop debug information
+/// * Use `options` to optionally drop debug information
+/// * Check that the memory is shared when compiled with `+atomics`
+///
+/// The result is written to `wasm`, which later becomes `artifact`, the module
+/// that is shipped. A debug info sidecar is named after the latter.
 fn process_wasm(
     wasm: &Path,
     temp: &Path,
-    profile: &Profile,
-    build: &CargoBuild,
+    artifact: &Path,
+    options: &PostprocessOptions,
     config: &Config,
-) -> Result<()> {
+) -> Result<ProcessTimings> {
     config.verbose(|| {
         config.status("Processing", &temp.display().to_string());
     });
 
-    let should_generate_dwarf = !matches!(profile.debuginfo, Some(0) | None);
-
-    let mut module = walrus::ModuleConfig::new()
-        // If the `debuginfo` is configured then we leave in the debuginfo
-        // sections.
-        .generate_dwarf(should_generate_dwarf)
-        .generate_name_section(build.enable_name_section(profile))
-        .generate_producers_section(build.enable_producers_section(profile))
-        .strict_validate(false)
+    let should_generate_dwarf = options.debuginfo;
+
+    let module_config = || {
+        let mut module_config = walrus::ModuleConfig::new();
+        module_config
+            // If the `debuginfo` is configured then we leave in the debuginfo
+            // sections.
+            .generate_dwarf(should_generate_dwarf)
+            .generate_name_section(options.name_section)
+            .generate_producers_section(options.producers_section)
+            .strict_validate(false);
+        module_config
+    };
+    let mut module = module_config()
         .parse_file(temp)
         .context("could not parse wasm")?;
 
-    // Demangle everything so it's got a more readable name since there's
-    // no real need to mangle the symbols in wasm.
+    // A module built with `+atomics` whose memory isn't shared will trap in
+    // confusing ways once threads are spawned, which usually means the
+    // linker flags were overridden (for example through RUSTFLAGS).
+    if options.shared_memory != SharedMemoryCheck::Off
+        && module.memories.iter().next().is_some()
+        && !module.memories.iter().any(|memory| memory.shared)
+    {
+        let msg = format!(
+            "`{}` was compiled with atomics but its memory is not shared, \
+             threads will not work; make sure it is linked with \
+             `-C link-arg=--shared-memory` and that RUSTFLAGS enables \
+             `-C target-feature=+atomics`",
+            artifact.display()
+        );
+        if options.shared_memory == SharedMemoryCheck::Deny {
+            bail!("{msg}");
+        }
+        config.warn(&msg);
+    }
+
+    set_producers(&mut module, options.producers);
+
+    let mut demangle = Duration::ZERO;
+    if options.process_order == ProcessOrder::DemangleThenOpt {
+        let start = Instant::now();
+        demangle_names(&mut module, &options.path_remaps);
+        demangle = start.elapsed();
+    }
+
+    let mut bytes = module.emit_wasm();
+
+    // Move the DWARF sections into a `*.debug.wasm` sidecar so the module
+    // that is shipped can be optimized and stays small.
+    let split_debuginfo = should_generate_dwarf && options.split_debuginfo;
+    let sidecar = debuginfo_sidecar(artifact);
+    if split_debuginfo {
+        let (stripped, debug) =
+            wasm::split_custom_sections(&bytes, |name| name.starts_with(".debug_"))
+                .context("failed to split debug info")?;
+        fs::write(&sidecar, debug).context(format!("failed to write `{}`", sidecar.display()))?;
+        bytes = stripped;
+    }
+
+    let start = Instant::now();
+    let optimized = run_wasm_opt(wasm, &bytes, options, config)?;
+    let wasm_opt = start.elapsed();
+
+    // The same sections are kept as before, so only the names that survived
+    // `wasm-opt` and the exports change.
+    let demangle_after_opt = options.process_order == ProcessOrder::OptThenDemangle;
+    if demangle_after_opt || options.hide_asyncify_exports {
+        let mut module = module_config()
+            .parse_file(wasm)
+            .context("could not parse the output of `wasm-opt`")?;
+        if demangle_after_opt {
+            let start = Instant::now();
+            demangle_names(&mut module, &options.path_remaps);
+            demangle = start.elapsed();
+        }
+        if options.hide_asyncify_exports {
+            hide_asyncify_exports(&mut module);
+        }
+        // Parsing added `walrus` to the producers again.
+        set_producers(&mut module, options.producers);
+        module
+            .emit_wasm_file(wasm)
+            .context(format!("failed to write `{}`", wasm.display()))?;
+    }
+
+    if split_debuginfo {
+        // Point debuggers at the sidecar, see
+        // https://yurydelendik.github.io/webassembly-dwarf/#external-DWARF
+        let name = sidecar.file_name().unwrap().to_string_lossy();
+        let section = wasm::custom_section("external_debug_info", &wasm::encode_str(&name));
+        let mut file = fs::OpenOptions::new().append(true).open(wasm)?;
+        io::Write::write_all(&mut file, &section)?;
+        config.verbose(|| {
+            config.status("Split", &format!("debug info into {}", sidecar.display()));
+        });
+    }
+
+    Ok(ProcessTimings {
+        demangle,
+        wasm_opt,
+        optimized,
+    })
+}
+
+/// Returns the `*.debug.wasm` sidecar next to `artifact`, e.g. `foo.debug.wasm`
+/// for `foo.wasm`.
+fn debuginfo_sidecar(artifact: &Path) -> PathBuf {
+    let stem = artifact.file_stem().unwrap_or_default().to_string_lossy();
+    artifact.with_file_name(format!("{stem}.debug.wasm"))
+}
+
+/// Reduces the `producers` section of `module` to the language and
+/// `cargo-wasix` if `producers` is minimal, dropping the toolchain versions.
+fn set_producers(module: &mut walrus::Module, producers: Producers) {
+    if producers == Producers::Minimal {
+        module.producers.clear();
+        module.producers.add_language("Rust", "");
+        module.producers.add_processed_by("cargo-wasix", "");
+    }
+}
+
+/// Demangle everything so it's got a more readable name since there's no real
+/// need to mangle the symbols in wasm.
+fn demangle_names(module: &mut walrus::Module, path_remaps: &[(String, String)]) {
     for func in module.funcs.iter_mut() {
         if let Some(name) = &mut func.name {
             if let Ok(sym) = rustc_demangle::try_demangle(name) {
                 *name = sym.to_string();
             }
+            remap_paths(name, path_remaps);
         }
     }
+    if let Some(name) = &mut module.name {
+        remap_paths(name, path_remaps);
+    }
+}
 
-    run_wasm_opt(wasm, &module.emit_wasm(), profile, build, config)?;
-    Ok(())
+/// Removes the `asyncify_*` exports that `wasm-opt --asyncify` adds for the
+/// host. The functions stay in the module, so the instrumented code that
+/// calls them keeps working.
+fn hide_asyncify_exports(module: &mut walrus::Module) {
+    let exports = module
+        .exports
+        .iter()
+        .filter(|export| export.name.starts_with("asyncify_"))
+        .map(|export| export.id())
+        .collect::<Vec<_>>();
+    for id in exports {
+        module.exports.delete(id);
+    }
 }
 
+/// Runs `wasm-opt` on `bytes`, writing the result to `wasm`. Returns whether
+/// the module was optimized, which it isn't with `wasm-opt = false` or at
+/// `-O0`.
 fn run_wasm_opt(
     wasm: &Path,
     bytes: &[u8],
-    profile: &Profile,
-    build: &CargoBuild,
+    options: &PostprocessOptions,
     config: &Config,
-) -> Result<()> {
-    // If debuginfo is enabled, automatically disable `wasm-opt`. It will mess
-    // up dwarf debug information currently, so we can't run it.
-    //
-    // Additionally if no optimizations are enabled, no need to run `wasm-opt`,
-    // we're not optimizing.
-
-    // we should follow this logic but currently we want to run wasm-opt with asyncify pass for both debug and release builds
-    // match profile.debuginfo {
-    //     Some(0) | None => (),
-    //     _ if profile.opt_level == "0" => {
-    //         fs::write(wasm, bytes)?;
-    //         return Ok(());
-    //     }
-    //     _ => (),
-    // }
-
+) -> Result<bool> {
     // Allow explicitly disabling wasm-opt via `Cargo.toml`.
-    if build.manifest_config.wasm_opt == Some(false) {
+    if !options.wasm_opt {
         fs::write(wasm, bytes)?;
-        return Ok(());
+        return Ok(false);
     }
 
-    config.status("Optimizing", "with wasm-opt");
+    // `wasm-opt` always runs, as the asyncify pass is needed for debug and
+    // release builds alike. Its optimizations mess up DWARF debug information
+    // though, so they are skipped when the DWARF stays in the module.
+    let keep_dwarf = options.debuginfo && !options.split_debuginfo;
+    let opt_level = if keep_dwarf { "0" } else { &options.opt_level };
+    if keep_dwarf && options.opt_level != "0" {
+        config.status(
+            "Skipping",
+            "wasm-opt optimizations because debuginfo is enabled (set `debug = 0` to optimize)",
+        );
+    } else {
+        config.status("Optimizing", "with wasm-opt");
+    }
     let tempdir = tempfile::TempDir::new_in(wasm.parent().unwrap())
         .context("failed to create temporary directory")?;
     let wasm_opt = config.get_wasm_opt();
@@ -463,37 +2078,54 @@ fn run_wasm_opt(
     fs::write(&input, bytes)?;
     let mut cmd = Command::new(wasm_opt.bin_path());
     cmd.arg(&input);
-    cmd.arg(format!("-O{}", profile.opt_level));
+    cmd.arg(format!("-O{opt_level}"));
     cmd.arg("-o").arg(wasm);
-    cmd.arg("--enable-bulk-memory");
-    cmd.arg("--enable-threads");
-    cmd.arg("--enable-reference-types");
-    cmd.arg("--no-validation");
+    let mut enabled = if options.mvp {
+        cmd.arg("--mvp-features");
+        Vec::new()
+    } else {
+        vec!["bulk-memory", "threads", "reference-types"]
+    };
+    for feature in options.target_features.iter() {
+        if let Some(feature) = wasm_opt_feature(feature) {
+            if !enabled.contains(&feature) {
+                enabled.push(feature);
+            }
+        }
+    }
+    for feature in enabled {
+        cmd.arg(format!("--enable-{feature}"));
+    }
+    if options.mvp {
+        // Sign extension is lowered since the standard library may have been
+        // compiled with it. Other features can't be lowered, so the module is
+        // validated to fail on them instead of producing one that won't load.
+        cmd.arg("--signext-lowering");
+    } else {
+        cmd.arg("--no-validation");
+    }
     cmd.arg("--asyncify");
 
-    if !build.enable_producers_section(profile) {
+    if !options.producers_section {
         cmd.arg("--strip-producers");
     }
 
-    match profile.debuginfo {
-        Some(0) | None => {
-            // release build
-            if build.enable_name_section(profile) {
-                cmd.arg("--debuginfo");
-            } else {
-                cmd.arg("--strip-debug");
-            }
-        }
-        Some(_) if profile.opt_level == "0" => {
-            // debug build
-            cmd.arg("--debuginfo");
-        }
-        _ => {
-            // release build
-            cmd.arg("--strip-debug");
-        }
+    // With debuginfo the DWARF is either kept or was moved to a sidecar
+    // already, so names can safely be kept.
+    if options.debuginfo || options.name_section {
+        cmd.arg("--debuginfo");
+    } else {
+        cmd.arg("--strip-debug");
     }
-
+    cmd.args(options.wasm_opt_args.iter());
+
+    // Included in the error so that bug reports show the flags without a
+    // verbose re-run.
+    let command_line = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
     run_or_download(
         wasm_opt.bin_path(),
         wasm_opt.is_overridden(),
@@ -501,14 +2133,111 @@ fn run_wasm_opt(
         config,
         || install_wasm_opt(&wasm_opt, config),
     )
-    .context("`wasm-opt` failed to execute")?;
+    .with_context(|| format!("`wasm-opt` failed to execute: {command_line}"))?;
+
+    // A broken module would otherwise only be noticed once it is run.
+    validate_wasm(wasm, &options.target_features, config).with_context(|| {
+        format!(
+            "`wasm-opt` produced an invalid module at `{}`, this is probably a bug in \
+             binaryen; set `wasm-opt = false` in `[package.metadata]` to skip it\n\
+             the command was: {command_line}",
+            wasm.display()
+        )
+    })?;
+    Ok(opt_level != "0")
+}
+
+/// Returns the absolute paths that end up in the build and the relative ones
+/// they are replaced with for `--remap-path`, in the order `rustc` expects,
+/// where the last matching prefix wins.
+fn path_remaps(workspace_root: &Path, config: &Config) -> Vec<(String, String)> {
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".cargo")));
+    let mut remaps = vec![(workspace_root.to_path_buf(), ".")];
+    // Sources of dependencies from crates.io and git.
+    if let Some(cargo_home) = cargo_home {
+        remaps.push((cargo_home, "/cargo"));
+    }
+    remaps
+        .into_iter()
+        .filter_map(|(from, to)| {
+            let from = from.to_string_lossy().into_owned();
+            // RUSTFLAGS is split on whitespace.
+            if from.contains(char::is_whitespace) {
+                config.warn(&format!(
+                    "`{from}` contains whitespace, so it can't be remapped"
+                ));
+                return None;
+            }
+            Some((from, to.to_string()))
+        })
+        .collect()
+}
+
+/// Applies `remaps` to the paths in `name`, like `--remap-path-prefix` does
+/// for `rustc`.
+fn remap_paths(name: &mut String, remaps: &[(String, String)]) {
+    for (from, to) in remaps.iter().rev() {
+        if name.contains(from.as_str()) {
+            *name = name.replace(from.as_str(), to);
+        }
+    }
+}
+
+/// Checks that `wasm` is a valid module, including the types of all function
+/// bodies, without processing it any further. The extra `target_features` the
+/// module was compiled with are enabled, and modules with features that
+/// `wasmparser` doesn't know aren't checked.
+fn validate_wasm(wasm: &Path, target_features: &[String], config: &Config) -> Result<()> {
+    // The proposals that WASIX modules use, `memory64` for `wasm64`.
+    let mut features = wasmparser::WasmFeatures {
+        threads: true,
+        bulk_memory: true,
+        reference_types: true,
+        simd: true,
+        memory64: true,
+        exceptions: true,
+        ..Default::default()
+    };
+    for feature in target_features {
+        match feature.as_str() {
+            "multimemory" => features.multi_memory = true,
+            "tail-call" => features.tail_call = true,
+            "extended-const" | "relaxed-simd" => {
+                config.verbose(|| {
+                    config.status(
+                        "Skipping",
+                        &format!(
+                            "validation of `{}` because `{feature}` can't be validated",
+                            wasm.display()
+                        ),
+                    )
+                });
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+    let bytes = fs::read(wasm).context(format!("failed to read `{}`", wasm.display()))?;
+    let mut validator = wasmparser::Validator::new();
+    validator.wasm_features(features);
+    validator.validate_all(&bytes)?;
     Ok(())
 }
 
 /// Executes the `cargo` command, reading all of the JSON that pops out and
 /// parsing that into a `CargoBuild`.
-fn execute_cargo(cargo: &mut Command, config: &Config) -> Result<CargoBuild> {
+fn execute_cargo(
+    cargo: &mut Command,
+    manifest_config: ManifestConfig,
+    config: &Config,
+) -> Result<CargoBuild> {
     config.verbose(|| config.status("Running", &format!("{:?}", cargo)));
+    print_env_overrides(cargo, config);
+    // Only Cargo's own messages and those of the runner shim are read here.
+    // Programs are run once Cargo exited, with our stdout, so their output
+    // (for example JSON test results) never ends up in this stream.
     let mut process = cargo
         .stdout(Stdio::piped())
         .spawn()
@@ -524,7 +2253,10 @@ fn execute_cargo(cargo: &mut Command, config: &Config) -> Result<CargoBuild> {
     utils::check_success(cargo, &status, &[], &[])
         .map_err(|e| utils::hide_normal_process_exit(e, config))?;
 
-    let mut build = CargoBuild::default();
+    let mut build = CargoBuild {
+        manifest_config,
+        ..CargoBuild::default()
+    };
 
     for line in json.lines() {
         if !line.starts_with('{') {
@@ -536,6 +2268,7 @@ fn execute_cargo(cargo: &mut Command, config: &Config) -> Result<CargoBuild> {
                 filenames,
                 profile,
                 package_id,
+                target,
                 fresh,
             }) => {
                 let mut parts = package_id.split_whitespace();
@@ -547,53 +2280,403 @@ fn execute_cargo(cargo: &mut Command, config: &Config) -> Result<CargoBuild> {
                 for file in filenames {
                     let file = PathBuf::from(file);
                     if file.extension().and_then(|s| s.to_str()) == Some("wasm") {
-                        build.wasms.push((file, profile.clone(), fresh));
+                        build.wasms.push(WasmArtifact {
+                            path: file,
+                            name: target.name.clone(),
+                            bin: target.kind.iter().any(|kind| kind == "bin"),
+                            profile: profile.clone(),
+                            fresh,
+                            package_id: package_id.clone(),
+                        });
                     }
                 }
             }
-            Ok(CargoMessage::RunWithArgs { args }) => build.runs.push(args),
+            Ok(CargoMessage::RunWithArgs { args, kind }) => {
+                build.runs.push(ProgramRun { kind, args })
+            }
             Ok(CargoMessage::BuildScriptExecuted) => {}
             Ok(CargoMessage::BuildFinished) => {}
+            Ok(CargoMessage::FutureIncompatReport) => {}
+            Ok(CargoMessage::Unknown) => {
+                config.verbose(|| config.status("Ignoring", line));
+            }
+            // Newer versions of Cargo may send messages without a `reason`,
+            // or with one we don't know and fields we don't expect.
+            Err(e) if is_unknown_cargo_message(line) => {
+                config.verbose(|| config.status("Ignoring", &format!("{line} ({e})")));
+            }
             Err(e) => bail!("failed to parse {}: {}", line, e),
         }
     }
 
+    Ok(build)
+}
+
+/// Returns whether `line` is a JSON object without a `reason`, or with one
+/// that isn't a [`CargoMessage`] we handle.
+fn is_unknown_cargo_message(line: &str) -> bool {
+    let Ok(serde_json::Value::Object(message)) = serde_json::from_str(line) else {
+        return false;
+    };
+    let Some(reason) = message.get("reason") else {
+        return true;
+    };
+    // Only the `reason` is given, so known messages with fields fail to
+    // parse here.
+    let reason = serde_json::Value::Object(
+        [("reason".to_string(), reason.clone())]
+            .into_iter()
+            .collect(),
+    );
+    matches!(serde_json::from_value(reason), Ok(CargoMessage::Unknown))
+}
+
+/// Reads the `cargo wasix` configuration from the `[package.metadata]` section
+/// of the workspace `Cargo.toml`, and that of every package of the workspace.
+///
+/// The metadata of all packages comes from a single `cargo metadata`, the
+/// settings of the root package are the defaults for the others.
+fn read_manifest_config(config: &Config, flags: utils::CargoFlags) -> Result<ManifestConfig> {
     #[derive(serde::Deserialize)]
     struct CargoMetadata {
         workspace_root: String,
-    }
-
-    #[derive(serde::Deserialize)]
-    struct CargoManifest {
-        package: Option<CargoPackage>,
+        target_directory: PathBuf,
+        packages: Vec<CargoPackage>,
     }
 
     #[derive(serde::Deserialize)]
     struct CargoPackage {
-        metadata: Option<ManifestConfig>,
+        id: String,
+        name: String,
+        manifest_path: PathBuf,
+        #[serde(default)]
+        metadata: serde_json::Value,
     }
 
-    let metadata = Command::new("cargo")
-        .arg("metadata")
-        .arg("--no-deps")
-        .arg("--format-version=1")
-        .capture_stdout()?;
+    let metadata = utils::workspace_metadata(config, flags)?;
     let metadata = serde_json::from_str::<CargoMetadata>(&metadata)
         .context("failed to deserialize `cargo metadata`")?;
+    let workspace_root = PathBuf::from(&metadata.workspace_root);
+
+    let root_manifest = workspace_root.join("Cargo.toml");
+    let root_metadata = metadata
+        .packages
+        .iter()
+        .find(|p| p.manifest_path == root_manifest)
+        .map(|p| p.metadata.clone())
+        .unwrap_or_default();
+
+    let parse = |value: serde_json::Value, manifest: &Path| -> Result<ManifestConfig> {
+        let mut manifest_config = if value.is_null() {
+            ManifestConfig::default()
+        } else {
+            serde_json::from_value::<ManifestConfig>(value).context(format!(
+                "failed to deserialize `[package.metadata]` of {}",
+                manifest.display()
+            ))?
+        };
+        let wasix = &mut manifest_config.wasix;
+        for path in [
+            &mut wasix.out_dir,
+            &mut wasix.env_file,
+            &mut wasix.post_build,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = workspace_root.join(&path);
+        }
+        manifest_config.workspace_root = workspace_root.clone();
+        manifest_config.target_dir = metadata.target_directory.clone();
+        Ok(manifest_config)
+    };
+
+    let mut manifest_config = parse(root_metadata.clone(), &root_manifest)?;
+    for package in &metadata.packages {
+        let mut value = root_metadata.clone();
+        merge_metadata(&mut value, package.metadata.clone());
+        let mut package_config = parse(value, &package.manifest_path)?;
+        package_config.no_harness = targets_without_harness(&package.name, &package.manifest_path)?;
+        manifest_config
+            .packages
+            .insert(package.id.clone(), package_config);
+    }
+    Ok(manifest_config)
+}
+
+/// Returns the names of the targets in the manifest at `path`, of the package
+/// `package`, that are built with `harness = false`. `cargo metadata` doesn't
+/// say.
+fn targets_without_harness(package: &str, path: &Path) -> Result<Vec<String>> {
+    #[derive(serde::Deserialize)]
+    struct Manifest {
+        lib: Option<Target>,
+        #[serde(default)]
+        bin: Vec<Target>,
+        #[serde(default)]
+        test: Vec<Target>,
+        #[serde(default)]
+        bench: Vec<Target>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Target {
+        name: Option<String>,
+        harness: Option<bool>,
+    }
+
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let manifest = toml::from_str::<Manifest>(&contents)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    // The library is named after the package by default.
+    let lib = manifest.lib.map(|lib| Target {
+        name: Some(lib.name.unwrap_or_else(|| package.replace('-', "_"))),
+        ..lib
+    });
+    Ok(lib
+        .into_iter()
+        .chain(manifest.bin)
+        .chain(manifest.test)
+        .chain(manifest.bench)
+        .filter(|target| target.harness == Some(false))
+        .filter_map(|target| target.name)
+        .collect())
+}
+
+/// Merges the `[package.metadata]` of a package into `base`, that of the root
+/// package. Tables are merged key by key, all other values are replaced.
+fn merge_metadata(base: &mut serde_json::Value, metadata: serde_json::Value) {
+    match (base, metadata) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(metadata)) => {
+            for (key, value) in metadata {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_metadata(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (_, serde_json::Value::Null) => {}
+        (base, metadata) => *base = metadata,
+    }
+}
+
+/// Runs the `post-build` hook for the final `wasm` of the Cargo target `name`.
+///
+/// The hook runs in the workspace root and gets the path as its only argument,
+/// its output is only shown if it fails.
+fn run_post_build(
+    hook: &Path,
+    wasm: &Path,
+    name: &str,
+    profile: &str,
+    target: &str,
+    build: &CargoBuild,
+    config: &Config,
+) -> Result<()> {
+    config.status(
+        "Running",
+        &format!("`{} {}`", hook.display(), wasm.display()),
+    );
+    let mut cmd = Command::new(hook);
+    cmd.arg(wasm)
+        .current_dir(&build.manifest_config.workspace_root)
+        .env("CARGO_WASIX_TARGET_NAME", name)
+        .env("CARGO_WASIX_PROFILE", profile)
+        .env("CARGO_WASIX_TARGET", target)
+        .stdin(Stdio::null());
+    print_env_overrides(&cmd, config);
+    cmd.output_if_success()
+        .with_context(|| format!("`post-build` hook failed for `{}`", wasm.display()))?;
+    Ok(())
+}
+
+/// Writes a compressed copy of `wasm` next to it, keeping `wasm` itself for
+/// running.
+fn compress_wasm(wasm: &Path, compression: Compression, config: &Config) -> Result<()> {
+    let compressed = wasm.with_extension(compression.extension());
+    let mut input = fs::File::open(wasm)?;
+    let output = fs::File::create(&compressed)
+        .with_context(|| format!("failed to create `{}`", compressed.display()))?;
+    match compression {
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::best());
+            io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?;
+        }
+    }
+    config.status(
+        "Compressed",
+        &format!(
+            "{} ({} bytes)",
+            compressed.display(),
+            fs::metadata(&compressed)?.len()
+        ),
+    );
+    Ok(())
+}
+
+/// Copies the final `*.wasm` files of all non-test artifacts into `out_dir`,
+/// named after their Cargo target, along with their components if they are
+/// built.
+fn copy_to_out_dir(
+    build: &CargoBuild,
+    out_dir: &Path,
+    component_flag: bool,
+    config: &Config,
+) -> Result<()> {
+    fs::create_dir_all(out_dir).context(format!(
+        "failed to create directory `{}`",
+        out_dir.display()
+    ))?;
+    for wasm in build.wasms.iter().filter(|w| !w.profile.test) {
+        let mut copies = vec![(wasm.path.clone(), format!("{}.wasm", wasm.name))];
+        if component_flag || build.package_config(&wasm.package_id).component() {
+            copies.push((
+                component::component_path(&wasm.path),
+                format!("{}.component.wasm", wasm.name),
+            ));
+        }
+        for (src, name) in copies {
+            let dest = out_dir.join(name);
+            config.verbose(|| {
+                config.status(
+                    "Copying",
+                    &format!("{} to {}", src.display(), dest.display()),
+                )
+            });
+            // Unchanged files keep their mtime for file watchers.
+            if dest.exists() && utils::same_contents(&src, &dest)? {
+                continue;
+            }
+            fs::copy(&src, &dest).context(format!(
+                "failed to copy `{}` to `{}`",
+                src.display(),
+                dest.display()
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Runtime invocation written to launcher scripts by [`install_bins`].
+struct Launcher<'a> {
+    runner: &'a str,
+    enable_threads: bool,
+}
+
+/// Copies the binaries of `build` into the `bin` directory of `root`, which
+/// defaults to the one `cargo install` uses, optionally along with launcher
+/// scripts running them.
+fn install_bins(
+    build: &CargoBuild,
+    root: Option<&Path>,
+    explicit_bins: bool,
+    launcher: Option<Launcher>,
+    config: &Config,
+) -> Result<()> {
+    let bins = build
+        .wasms
+        .iter()
+        .filter(|w| w.bin && !w.profile.test)
+        .collect::<Vec<_>>();
+    if bins.is_empty() {
+        bail!("there are no binaries to install");
+    }
+    if bins.len() > 1 && !explicit_bins {
+        let mut names = bins
+            .iter()
+            .map(|w| format!("`{}`", w.name))
+            .collect::<Vec<_>>();
+        names.sort();
+        let names = names.join(", ");
+        bail!(
+            "multiple binaries are available: {names}; pass `--bin <name>` to choose one, \
+             or `--bins` to install all of them"
+        );
+    }
 
-    let manifest = Path::new(&metadata.workspace_root).join("Cargo.toml");
-    let toml = fs::read_to_string(&manifest)
-        .context(format!("failed to read manifest: {}", manifest.display()))?;
-    let toml = toml::from_str::<CargoManifest>(&toml).context(format!(
-        "failed to deserialize as TOML: {}",
-        manifest.display()
+    let bin_dir = match root {
+        Some(root) => root.join("bin"),
+        None => default_install_root()?.join("bin"),
+    };
+    fs::create_dir_all(&bin_dir).context(format!(
+        "failed to create directory `{}`",
+        bin_dir.display()
     ))?;
+    for wasm in bins {
+        let dest = bin_dir.join(format!("{}.wasm", wasm.name));
+        fs::copy(&wasm.path, &dest).context(format!(
+            "failed to copy `{}` to `{}`",
+            wasm.path.display(),
+            dest.display()
+        ))?;
+        config.status("Installed", &dest.display().to_string());
+
+        if let Some(launcher) = &launcher {
+            let script = write_launcher(&bin_dir, &wasm.name, &dest, launcher)?;
+            config.status("Installed", &script.display().to_string());
+        }
+    }
+    Ok(())
+}
 
-    if let Some(meta) = toml.package.and_then(|p| p.metadata) {
-        build.manifest_config = meta;
+/// Returns the root directory `cargo install` installs to.
+fn default_install_root() -> Result<PathBuf> {
+    if let Some(root) = env::var_os("CARGO_INSTALL_ROOT") {
+        return Ok(root.into());
     }
+    if let Some(home) = env::var_os("CARGO_HOME") {
+        return Ok(home.into());
+    }
+    let home = dirs::home_dir().context("failed to find the home directory, pass `--root`")?;
+    Ok(home.join(".cargo"))
+}
 
-    Ok(build)
+/// Writes a script next to the installed `wasm` that runs it with the
+/// runtime of `launcher`, and returns its path.
+fn write_launcher(bin_dir: &Path, name: &str, wasm: &Path, launcher: &Launcher) -> Result<PathBuf> {
+    let mut runner_args = vec![launcher.runner.to_string()];
+    if launcher.enable_threads {
+        runner_args.push("--enable-threads".to_string());
+    }
+    if launcher.runner == "wasmer" {
+        runner_args.push("--dir=.".to_string());
+    }
+    runner_args.push("--".to_string());
+    runner_args.push(wasm.display().to_string());
+
+    let (path, script) = if cfg!(windows) {
+        let args = runner_args
+            .iter()
+            .map(|a| format!("\"{a}\""))
+            .collect::<Vec<_>>()
+            .join(" ");
+        (
+            bin_dir.join(format!("{name}.cmd")),
+            format!("@echo off\r\n{args} %*\r\n"),
+        )
+    } else {
+        let args = runner_args
+            .iter()
+            .map(|a| format!("'{}'", a.replace('\'', "'\\''")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        (
+            bin_dir.join(name),
+            format!("#!/bin/sh\nexec {args} \"$@\"\n"),
+        )
+    };
+    fs::write(&path, script).context(format!("failed to write `{}`", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+            .context(format!("failed to make `{}` executable", path.display()))?;
+    }
+    Ok(path)
 }
 
 /// Attempts to execute `cmd` which is executing `requested`.
@@ -624,16 +2707,11 @@ fn run_or_download(
         Ok(()) => return Ok(()),
         Err(e) => e,
     };
-    let rerun_after_download = err.chain().any(|e| {
-        // NotFound means we need to clearly download, PermissionDenied may mean
-        // that we were racing a download and the file wasn't executable, so
-        // fall through and wait for the download to finish to try again.
-        if let Some(err) = e.downcast_ref::<io::Error>() {
-            return err.kind() == io::ErrorKind::NotFound
-                || err.kind() == io::ErrorKind::PermissionDenied;
-        }
-        false
-    });
+    // NotFound means we need to clearly download, PermissionDenied may mean
+    // that we were racing a download and the file wasn't executable, so fall
+    // through and wait for the download to finish to try again.
+    let rerun_after_download = has_io_error(&err, io::ErrorKind::NotFound)
+        || has_io_error(&err, io::ErrorKind::PermissionDenied);
 
     // This may have failed for some reason other than `NotFound`, in which case
     // it's a legitimate error. Additionally `requested` may not actually be a
@@ -642,15 +2720,90 @@ fn run_or_download(
         return Err(err);
     }
 
+    if config.no_auto_download || config.is_offline {
+        let var = if config.no_auto_download {
+            "WASIX_NO_AUTO_DOWNLOAD"
+        } else {
+            "CARGO_WASIX_OFFLINE"
+        };
+        let tool = requested.file_stem().unwrap_or_default().to_string_lossy();
+        return Err(err.context(format!(
+            "`{}` isn't installed and isn't downloaded because ${var} is set; \
+             install it and point ${} at it, run `cargo wasix self fetch-tools` \
+             beforehand or unset ${var}",
+            requested.display(),
+            tool.to_uppercase().replace('-', "_"),
+        )));
+    }
+
     download()?;
     config.verbose(|| {
         config.status("Running", &format!("{:?}", cmd));
     });
-    cmd.run()
+    match cmd.run() {
+        // The download is complete, so the file is there but the filesystem
+        // doesn't let us execute it, e.g. because it's mounted with `noexec`.
+        Err(err) if has_io_error(&err, io::ErrorKind::PermissionDenied) => {
+            Err(err.context(format!(
+                "`{}` was downloaded but can't be executed, the cache directory is \
+                 probably on a filesystem that doesn't allow executing files; set \
+                 $WASIX_CACHE_DIR to a directory on another filesystem",
+                requested.display()
+            )))
+        }
+        result => result,
+    }
+}
+
+/// Distinguishes the two common reasons for `cargo` failing to start: `cargo`
+/// itself isn't installed, or the wasix toolchain doesn't contain it.
+fn explain_cargo_error(
+    err: anyhow::Error,
+    toolchain: &toolchain::RustupToolchain,
+) -> anyhow::Error {
+    if has_io_error(&err, io::ErrorKind::NotFound) {
+        return err.context(
+            "`cargo` was not found in $PATH, install Rust and Cargo (for example through \
+             https://rustup.rs) before using `cargo wasix`",
+        );
+    }
+    // `check_toolchain` already verified this, but the toolchain may have
+    // changed since.
+    if !toolchain.cargo_path().exists() {
+        // Not wrapping `err`, which may be a hidden process error, since
+        // rustup already printed why `cargo` failed.
+        return toolchain.missing_cargo_error();
+    }
+    err
 }
 
+/// Returns whether any error in the chain of `err` is an I/O error of `kind`.
+fn has_io_error(err: &anyhow::Error, kind: io::ErrorKind) -> bool {
+    err.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == kind)
+}
+
+/// The binaryen release `wasm-opt` is downloaded from, unless overridden
+/// through `WASIX_BINARYEN_VERSION`.
+const DEFAULT_BINARYEN_VERSION: &str = "version_113";
+
 fn install_wasm_opt(path: &ToolPath, config: &Config) -> Result<()> {
-    let tag = "version_113";
+    let tag = config
+        .binaryen_version
+        .as_deref()
+        .unwrap_or(DEFAULT_BINARYEN_VERSION);
+    // The tag ends up in the URL and the cache path, and all releases are
+    // named like this.
+    let is_release_tag = tag
+        .strip_prefix("version_")
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+    if !is_release_tag {
+        bail!(
+            "$WASIX_BINARYEN_VERSION is set to `{tag}`, which is not a binaryen \
+             release tag such as `{DEFAULT_BINARYEN_VERSION}`"
+        );
+    }
     let binaryen_url = |target: &str| {
         let mut url = "https://github.com/WebAssembly/binaryen/releases/download/".to_string();
         url.push_str(tag);
@@ -680,13 +2833,24 @@ fn install_wasm_opt(path: &ToolPath, config: &Config) -> Result<()> {
     };
 
     let (base_path, sub_paths) = path.cache_paths().unwrap();
-    download(
+    let result = download(
         &url,
         &format!("precompiled wasm-opt {}", tag),
         base_path,
         sub_paths,
         config,
-    )
+    );
+    if config.binaryen_version.is_some() {
+        return result.with_context(|| {
+            format!(
+                "failed to download `wasm-opt` from binaryen `{tag}` set in \
+                 $WASIX_BINARYEN_VERSION, check that the release exists and has \
+                 an asset for this platform at \
+                 https://github.com/WebAssembly/binaryen/releases/tag/{tag}"
+            )
+        });
+    }
+    result
 }
 
 fn download(
@@ -700,7 +2864,7 @@ fn download(
     // instances of `cargo-wasi` doing a download. This is a bit coarse, but it
     // gets the job done. Additionally if someone else does the download for us
     // then we can simply return.
-    let _flock = utils::flock(&config.cache().root().join("downloading"));
+    let _flock = utils::flock(&config.tools_root().join("downloading"));
     if sub_paths
         .iter()
         .all(|sub_path| parent.join(sub_path).exists())
@@ -712,25 +2876,63 @@ fn download(
     config.status("Downloading", name);
     config.verbose(|| config.status("Get", url));
 
+    let format = archive::ArchiveFormat::from_file_name(url)
+        .with_context(|| format!("unsupported archive format of {url}"))?;
     let response = utils::get(url, DOWNLOAD_TIMEOUT)?;
     (|| -> Result<()> {
         fs::create_dir_all(parent)
             .context(format!("failed to create directory `{}`", parent.display()))?;
 
-        let decompressed = flate2::read::GzDecoder::new(response);
-        let mut tar = tar::Archive::new(decompressed);
-        for entry in tar.entries()? {
-            let mut entry = entry?;
-            let path = entry.path()?.into_owned();
-            for sub_path in sub_paths {
-                if path.ends_with(sub_path) {
-                    let entry_path = parent.join(sub_path);
-                    let dir = entry_path.parent().unwrap();
-                    if !dir.exists() {
-                        fs::create_dir_all(dir)
-                            .context(format!("failed to create directory `{}`", dir.display()))?;
-                    }
-                    entry.unpack(entry_path)?;
+        let unpack_err = |err: io::Error, entry_path: &Path| {
+            let hint = if err.kind() == io::ErrorKind::PermissionDenied {
+                ", set $WASIX_CACHE_DIR to a writable directory that allows \
+                 executing files"
+            } else {
+                ""
+            };
+            anyhow::Error::new(err)
+                .context(format!("failed to unpack `{}`{hint}", entry_path.display()))
+        };
+        let create_parent_dir = |entry_path: &Path| -> Result<()> {
+            let dir = entry_path.parent().unwrap();
+            if !dir.exists() {
+                fs::create_dir_all(dir)
+                    .context(format!("failed to create directory `{}`", dir.display()))?;
+            }
+            Ok(())
+        };
+
+        if format == archive::ArchiveFormat::TarGz {
+            let decompressed = flate2::read::GzDecoder::new(response);
+            let mut tar = tar::Archive::new(decompressed);
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                let path = entry.path()?.into_owned();
+                for sub_path in sub_paths {
+                    if path.ends_with(sub_path) {
+                        let entry_path = parent.join(sub_path);
+                        create_parent_dir(&entry_path)?;
+                        entry
+                            .unpack(&entry_path)
+                            .map_err(|err| unpack_err(err, &entry_path))?;
+                    }
+                }
+            }
+        } else {
+            // The other formats are extracted as a whole by external tools, so
+            // pick the wanted files out of a temporary directory.
+            let tmp =
+                tempfile::tempdir_in(parent).context("failed to create temporary directory")?;
+            archive::extract(response, format, tmp.path())?;
+            for path in archive::files(tmp.path())? {
+                let relative = path.strip_prefix(tmp.path())?;
+                for sub_path in sub_paths {
+                    if relative.ends_with(sub_path) {
+                        let entry_path = parent.join(sub_path);
+                        create_parent_dir(&entry_path)?;
+                        fs::rename(&path, &entry_path)
+                            .map_err(|err| unpack_err(err, &entry_path))?;
+                    }
                 }
             }
         }
@@ -743,5 +2945,5 @@ fn download(
         }
         Ok(())
     })()
-    .context(format!("failed to extract tarball from {}", url))
+    .context(format!("failed to extract archive from {}", url))
 }
@@ -0,0 +1,286 @@
+//! Selecting the runtime that `run`, `test` and `bench` run programs with.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use cargo_metadata::semver::{Version, VersionReq};
+
+use crate::utils::CommandExt;
+
+/// The runtime used unless another one is configured.
+pub const DEFAULT_RUNTIME: &str = "wasmer";
+
+/// Where the runtime was configured, which error messages point to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuntimeSource {
+    /// The runner variable Cargo reads for the target, with its name.
+    CargoRunner(String),
+    /// The `WASIX_RUNTIME` environment variable.
+    Env,
+    /// `runtime` in `[package.metadata.wasix]`.
+    Manifest,
+    /// Nothing was configured.
+    Default,
+}
+
+/// A runtime along with the arguments it is always passed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Runtime {
+    pub program: String,
+    /// Passed after the flags `cargo wasix` adds itself for `wasmer`.
+    pub args: Vec<String>,
+    pub source: RuntimeSource,
+}
+
+/// Everything the runtime is resolved from, with unset values as `None`.
+///
+/// New fields may be added, so start from `RuntimeSettings::default()`.
+#[derive(Default)]
+#[non_exhaustive]
+pub struct RuntimeSettings<'a> {
+    /// The name and value of the runner variable Cargo reads for the target.
+    pub cargo_runner: Option<(&'a str, &'a str)>,
+    /// The value of `WASIX_RUNTIME`.
+    pub wasix_runtime: Option<&'a str>,
+    /// `runtime` and `runtime-args` from `[package.metadata.wasix]`.
+    pub manifest: Option<(&'a str, &'a [String])>,
+    /// The value of `WASIX_RUNNER_ARGS`, which applies to any runtime.
+    pub runner_args: Option<&'a str>,
+}
+
+impl Runtime {
+    /// Resolves the runtime from the environment and the `runtime` settings
+    /// of the manifest.
+    pub fn from_env(
+        runner_env_var: &str,
+        manifest_runtime: Option<&str>,
+        manifest_args: &[String],
+    ) -> Runtime {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let cargo_runner = var(runner_env_var);
+        let wasix_runtime = var("WASIX_RUNTIME");
+        let runner_args = var("WASIX_RUNNER_ARGS");
+        Runtime::resolve(RuntimeSettings {
+            cargo_runner: cargo_runner.as_deref().map(|r| (runner_env_var, r)),
+            wasix_runtime: wasix_runtime.as_deref(),
+            manifest: manifest_runtime.map(|r| (r, manifest_args)),
+            runner_args: runner_args.as_deref(),
+        })
+    }
+
+    /// Picks the first runtime that is set, in the order of the fields of
+    /// `settings`, falling back to `wasmer`.
+    ///
+    /// Like Cargo does for runners, the values of environment variables are
+    /// split at whitespace into the program and its arguments.
+    pub fn resolve(settings: RuntimeSettings) -> Runtime {
+        let split = |value: &str| {
+            value
+                .split_whitespace()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        let (mut words, source) = if let Some((name, value)) = settings.cargo_runner {
+            (split(value), RuntimeSource::CargoRunner(name.to_string()))
+        } else if let Some(value) = settings.wasix_runtime {
+            (split(value), RuntimeSource::Env)
+        } else if let Some((program, args)) = settings.manifest {
+            let mut words = vec![program.to_string()];
+            words.extend(args.iter().cloned());
+            (words, RuntimeSource::Manifest)
+        } else {
+            (vec![DEFAULT_RUNTIME.to_string()], RuntimeSource::Default)
+        };
+        if words.is_empty() {
+            words.push(DEFAULT_RUNTIME.to_string());
+        }
+        let program = words.remove(0);
+        let mut args = words;
+        args.extend(settings.runner_args.into_iter().flat_map(split));
+        Runtime {
+            program,
+            args,
+            source,
+        }
+    }
+
+    /// Whether this is `wasmer`, which gets flags for threads, preopened
+    /// directories, networking and the environment.
+    pub fn is_wasmer(&self) -> bool {
+        self.program == DEFAULT_RUNTIME
+    }
+
+    /// Checks that the runtime can be found before anything is built.
+    /// `runner_env_var` is suggested for configuring another runtime.
+    pub fn ensure_available(&self, runner_env_var: &str) -> Result<()> {
+        let found = Path::new(&self.program).exists() || which::which(&self.program).is_ok();
+        if found {
+            return Ok(());
+        }
+        let setting = match &self.source {
+            RuntimeSource::CargoRunner(name) => format!("${name}"),
+            RuntimeSource::Env => "$WASIX_RUNTIME".to_string(),
+            RuntimeSource::Manifest => "`runtime` in `[package.metadata.wasix]`".to_string(),
+            RuntimeSource::Default => {
+                let mut msg = format!(
+                    "failed to find `{0}` in $PATH, you'll want to install `{0}` or set \
+                     ${runner_env_var} or $WASIX_RUNTIME before running this command\n",
+                    self.program
+                );
+                // Because we know what runtime is being used here, we can
+                // print out installation information.
+                msg.push_str("you can also install through a shell:\n\n");
+                msg.push_str("\tcurl https://get.wasmer.io -sSfL | sh\n");
+                bail!("{}", msg);
+            }
+        };
+        bail!(
+            "failed to find `{}` (specified by {setting}) on the filesytem or in $PATH, \
+             you'll want to fix the path or unset {setting} before running this command\n",
+            self.program
+        )
+    }
+
+    /// Runs the runtime with `--version` and checks that the version it
+    /// prints satisfies `requirement`, from `runtime-version`.
+    pub fn check_version(&self, requirement: &VersionReq) -> Result<()> {
+        let output = Command::new(&self.program)
+            .arg("--version")
+            .capture_stdout()
+            .with_context(|| format!("failed to get the version of `{}`", self.program))?;
+        let version = parse_version(&output).with_context(|| {
+            format!(
+                "failed to find a version in the output of `{} --version`: {}",
+                self.program,
+                output.trim()
+            )
+        })?;
+        if requirement.matches(&version) {
+            return Ok(());
+        }
+        let upgrade = if self.is_wasmer() {
+            ", upgrade it with `wasmer self-update`"
+        } else {
+            ""
+        };
+        bail!(
+            "`{}` is version {version}, which doesn't satisfy `runtime-version = \"{requirement}\"` \
+             in `[package.metadata.wasix]`{upgrade}",
+            self.program
+        )
+    }
+}
+
+/// Finds the version in the output of `--version`, such as `wasmer 4.2.5`.
+fn parse_version(output: &str) -> Option<Version> {
+    output
+        .split_whitespace()
+        .find_map(|word| Version::parse(word.trim_start_matches('v')).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUNNER_VAR: &str = "CARGO_TARGET_WASM32_WASMER_WASI_RUNNER";
+
+    #[test]
+    fn test_resolve_default() {
+        let runtime = Runtime::resolve(RuntimeSettings::default());
+        assert_eq!(runtime.program, "wasmer");
+        assert!(runtime.args.is_empty());
+        assert_eq!(runtime.source, RuntimeSource::Default);
+        assert!(runtime.is_wasmer());
+    }
+
+    #[test]
+    fn test_resolve_precedence() {
+        let manifest_args = vec!["--dir=.".to_string()];
+        let mut settings = RuntimeSettings {
+            cargo_runner: Some((RUNNER_VAR, "my-runner --fuel 10")),
+            wasix_runtime: Some("wasmtime"),
+            manifest: Some(("wasmedge", &manifest_args)),
+            runner_args: None,
+        };
+        let runtime = Runtime::resolve(settings);
+        assert_eq!(runtime.program, "my-runner");
+        assert_eq!(runtime.args, ["--fuel", "10"]);
+        assert_eq!(
+            runtime.source,
+            RuntimeSource::CargoRunner(RUNNER_VAR.to_string())
+        );
+
+        settings = RuntimeSettings {
+            wasix_runtime: Some("wasmtime"),
+            manifest: Some(("wasmedge", &manifest_args)),
+            ..Default::default()
+        };
+        let runtime = Runtime::resolve(settings);
+        assert_eq!(runtime.program, "wasmtime");
+        assert!(runtime.args.is_empty());
+        assert_eq!(runtime.source, RuntimeSource::Env);
+        assert!(!runtime.is_wasmer());
+
+        settings = RuntimeSettings {
+            manifest: Some(("wasmedge", &manifest_args)),
+            ..Default::default()
+        };
+        let runtime = Runtime::resolve(settings);
+        assert_eq!(runtime.program, "wasmedge");
+        assert_eq!(runtime.args, ["--dir=."]);
+        assert_eq!(runtime.source, RuntimeSource::Manifest);
+    }
+
+    #[test]
+    fn test_resolve_runner_args() {
+        // `WASIX_RUNNER_ARGS` come after the base arguments of any runtime.
+        let runtime = Runtime::resolve(RuntimeSettings {
+            wasix_runtime: Some("wasmtime -S threads"),
+            runner_args: Some("  --fuel  1000 "),
+            ..Default::default()
+        });
+        assert_eq!(runtime.args, ["-S", "threads", "--fuel", "1000"]);
+
+        let runtime = Runtime::resolve(RuntimeSettings {
+            runner_args: Some("--verbose"),
+            ..Default::default()
+        });
+        assert_eq!(runtime.program, "wasmer");
+        assert_eq!(runtime.args, ["--verbose"]);
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("wasmer 4.2.5\n"), Some(Version::new(4, 2, 5)));
+        assert_eq!(
+            parse_version("wasmtime-cli v14.0.4 (abcdef 2023-11-01)"),
+            Some(Version::new(14, 0, 4))
+        );
+        assert_eq!(
+            parse_version("wasmer 4.3.0-beta.1"),
+            Some(Version::parse("4.3.0-beta.1").unwrap())
+        );
+        assert_eq!(parse_version("my-runtime (unreleased)"), None);
+    }
+
+    #[test]
+    fn test_ensure_available() {
+        let runtime = Runtime::resolve(RuntimeSettings {
+            wasix_runtime: Some("does-not-exist-3b1d0f"),
+            ..Default::default()
+        });
+        let err = runtime.ensure_available(RUNNER_VAR).unwrap_err();
+        assert!(err.to_string().contains("specified by $WASIX_RUNTIME"));
+
+        let runtime = Runtime::resolve(RuntimeSettings {
+            manifest: Some(("does-not-exist-3b1d0f", &[])),
+            ..Default::default()
+        });
+        let err = runtime.ensure_available(RUNNER_VAR).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("`runtime` in `[package.metadata.wasix]`"));
+    }
+}
@@ -0,0 +1,336 @@
+//! `cargo wasix size`: reports which functions make up the size of a module.
+//!
+//! Besides a flat list of the largest functions, the dominator tree of the
+//! call graph shows what each function keeps alive: a function dominates
+//! another one if every path from the exports to the latter goes through it,
+//! so removing it would remove everything it dominates as well.
+
+use crate::wasm;
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// What `cargo wasix size` reports.
+pub struct SizeOptions {
+    /// Print the dominator tree instead of the flat list.
+    pub dominators: bool,
+    /// Number of entries listed, per level of the dominator tree.
+    pub top: usize,
+    /// Number of levels of the dominator tree that are printed.
+    pub depth: usize,
+}
+
+impl Default for SizeOptions {
+    fn default() -> SizeOptions {
+        SizeOptions {
+            dominators: false,
+            top: 20,
+            depth: 4,
+        }
+    }
+}
+
+/// The call graph of a module, where node `0` is a synthetic root calling
+/// everything reachable from outside the module and node `i + 1` is the
+/// function with index `i`.
+struct CallGraph {
+    names: Vec<String>,
+    sizes: Vec<u64>,
+    edges: Vec<Vec<usize>>,
+}
+
+/// Returns the report for the module at `wasm`, which is only read.
+pub fn report(wasm: &Path, options: &SizeOptions) -> Result<String> {
+    let bytes = fs::read(wasm).with_context(|| format!("failed to read `{}`", wasm.display()))?;
+    let graph = CallGraph::new(&bytes)?;
+    let total = bytes.len() as u64;
+
+    let mut out = String::new();
+    writeln!(out, "{} ({total} bytes)", wasm.display())?;
+    if options.dominators {
+        let retained = graph.retained_sizes();
+        writeln!(out, "{:>10} {:>8}  Dominator tree", "Retained", "Percent")?;
+        let children = graph.dominator_children();
+        write_tree(&mut out, &graph, &children, &retained, 0, 0, total, options)?;
+    } else {
+        writeln!(out, "{:>10} {:>8}  Function", "Size", "Percent")?;
+        let mut functions = (1..graph.names.len()).collect::<Vec<_>>();
+        functions.sort_by_key(|&node| std::cmp::Reverse(graph.sizes[node]));
+        for node in functions.into_iter().take(options.top) {
+            write_row(&mut out, graph.sizes[node], total, "", &graph.names[node])?;
+        }
+    }
+    Ok(out)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_tree(
+    out: &mut String,
+    graph: &CallGraph,
+    children: &[Vec<usize>],
+    retained: &[u64],
+    node: usize,
+    level: usize,
+    total: u64,
+    options: &SizeOptions,
+) -> Result<()> {
+    if level == options.depth {
+        return Ok(());
+    }
+    let mut sorted = children[node].clone();
+    sorted.sort_by_key(|&child| std::cmp::Reverse(retained[child]));
+    for child in sorted.into_iter().take(options.top) {
+        let indent = "  ".repeat(level);
+        write_row(out, retained[child], total, &indent, &graph.names[child])?;
+        write_tree(
+            out,
+            graph,
+            children,
+            retained,
+            child,
+            level + 1,
+            total,
+            options,
+        )?;
+    }
+    Ok(())
+}
+
+fn write_row(out: &mut String, size: u64, total: u64, indent: &str, name: &str) -> Result<()> {
+    let percent = if total == 0 {
+        0.0
+    } else {
+        size as f64 * 100.0 / total as f64
+    };
+    writeln!(out, "{size:>10} {percent:>7.2}%  {indent}{name}")?;
+    Ok(())
+}
+
+impl CallGraph {
+    fn new(bytes: &[u8]) -> Result<CallGraph> {
+        let module = walrus::ModuleConfig::new()
+            .generate_producers_section(false)
+            .strict_validate(false)
+            .parse(bytes)
+            .context("could not parse wasm")?;
+        let body_sizes = wasm::code_body_sizes(bytes)?;
+
+        // Imported functions come first, followed by the local ones in the
+        // order of the code section, which is also the order of their ids.
+        let count = module.funcs.iter().count();
+        let imports = count - body_sizes.len();
+        let mut names = vec!["<root>".to_string(); count + 1];
+        let mut sizes = vec![0; count + 1];
+        let mut edges = vec![Vec::new(); count + 1];
+        for func in module.funcs.iter() {
+            let node = func.id().index() + 1;
+            let name = func
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("func[{}]", func.id().index()));
+            names[node] = match rustc_demangle::try_demangle(&name) {
+                // Without the hash suffix.
+                Ok(sym) => format!("{sym:#}"),
+                Err(_) => name,
+            };
+            if let walrus::FunctionKind::Local(local) = &func.kind {
+                sizes[node] = body_sizes
+                    .get(func.id().index() - imports)
+                    .copied()
+                    .unwrap_or(0);
+                let mut calls = Calls(Vec::new());
+                walrus::ir::dfs_in_order(&mut calls, local, local.entry_block());
+                edges[node] = calls.0;
+            }
+        }
+
+        // Everything that can be called from outside the module or through a
+        // table is a root.
+        let exports = module
+            .exports
+            .iter()
+            .filter_map(|export| match export.item {
+                walrus::ExportItem::Function(func) => Some(func),
+                _ => None,
+            });
+        let tables = module
+            .elements
+            .iter()
+            .flat_map(|element| element.members.iter().flatten().copied());
+        edges[0] = exports
+            .chain(tables)
+            .chain(module.start)
+            .map(|func| func.index() + 1)
+            .collect();
+
+        let mut graph = CallGraph {
+            names,
+            sizes,
+            edges,
+        };
+        graph.add_unreachable_roots();
+        Ok(graph)
+    }
+
+    /// Makes the root call the functions that aren't reachable from it, so
+    /// that they show up in the dominator tree as well.
+    fn add_unreachable_roots(&mut self) {
+        let mut visited = vec![false; self.edges.len()];
+        self.visit(0, &mut visited, &mut Vec::new());
+        for node in 1..self.edges.len() {
+            if !visited[node] {
+                self.edges[0].push(node);
+                self.visit(node, &mut visited, &mut Vec::new());
+            }
+        }
+    }
+
+    /// Depth-first traversal from `start`, appending the newly visited nodes
+    /// to `postorder`.
+    fn visit(&self, start: usize, visited: &mut [bool], postorder: &mut Vec<usize>) {
+        // The stack remembers which edge of each node is next.
+        let mut stack = vec![(start, 0)];
+        visited[start] = true;
+        while let Some((node, edge)) = stack.last_mut() {
+            if let Some(&next) = self.edges[*node].get(*edge) {
+                *edge += 1;
+                if !visited[next] {
+                    visited[next] = true;
+                    stack.push((next, 0));
+                }
+            } else {
+                postorder.push(*node);
+                stack.pop();
+            }
+        }
+    }
+
+    fn reverse_postorder(&self) -> Vec<usize> {
+        let mut postorder = Vec::with_capacity(self.edges.len());
+        self.visit(0, &mut vec![false; self.edges.len()], &mut postorder);
+        postorder.reverse();
+        postorder
+    }
+
+    /// Computes the immediate dominator of every node, following "A Simple,
+    /// Fast Dominance Algorithm" by Cooper, Harvey and Kennedy.
+    fn immediate_dominators(&self, rpo: &[usize]) -> Vec<usize> {
+        let mut order = vec![0; self.edges.len()];
+        for (i, &node) in rpo.iter().enumerate() {
+            order[node] = i;
+        }
+        let mut preds = vec![Vec::new(); self.edges.len()];
+        for (node, edges) in self.edges.iter().enumerate() {
+            for &next in edges {
+                preds[next].push(node);
+            }
+        }
+
+        const UNDEFINED: usize = usize::MAX;
+        let mut idom = vec![UNDEFINED; self.edges.len()];
+        idom[0] = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &node in rpo.iter().skip(1) {
+                let mut new_idom = UNDEFINED;
+                for &pred in preds[node].iter() {
+                    if idom[pred] == UNDEFINED {
+                        continue;
+                    }
+                    new_idom = if new_idom == UNDEFINED {
+                        pred
+                    } else {
+                        let (mut a, mut b) = (pred, new_idom);
+                        while a != b {
+                            while order[a] > order[b] {
+                                a = idom[a];
+                            }
+                            while order[b] > order[a] {
+                                b = idom[b];
+                            }
+                        }
+                        a
+                    };
+                }
+                if idom[node] != new_idom {
+                    idom[node] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom
+    }
+
+    fn dominator_children(&self) -> Vec<Vec<usize>> {
+        let rpo = self.reverse_postorder();
+        let idom = self.immediate_dominators(&rpo);
+        let mut children = vec![Vec::new(); self.edges.len()];
+        for &node in rpo.iter().skip(1) {
+            children[idom[node]].push(node);
+        }
+        for children in children.iter_mut() {
+            children.sort_unstable();
+        }
+        children
+    }
+
+    /// Returns the size of every node plus that of all nodes it dominates.
+    fn retained_sizes(&self) -> Vec<u64> {
+        let rpo = self.reverse_postorder();
+        let idom = self.immediate_dominators(&rpo);
+        let mut retained = self.sizes.clone();
+        // Dominators come before the nodes they dominate in reverse postorder.
+        for &node in rpo.iter().skip(1).rev() {
+            retained[idom[node]] += retained[node];
+        }
+        retained
+    }
+}
+
+/// Collects the functions referenced by the instructions of a function, both
+/// through calls and `ref.func`.
+struct Calls(Vec<usize>);
+
+impl<'instr> walrus::ir::Visitor<'instr> for Calls {
+    fn visit_function_id(&mut self, function: &walrus::FunctionId) {
+        self.0.push(function.index() + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(sizes: &[u64], edges: &[&[usize]]) -> CallGraph {
+        let mut graph = CallGraph {
+            names: (0..sizes.len()).map(|i| i.to_string()).collect(),
+            sizes: sizes.to_vec(),
+            edges: edges.iter().map(|edges| edges.to_vec()).collect(),
+        };
+        graph.add_unreachable_roots();
+        graph
+    }
+
+    #[test]
+    fn test_retained_sizes() {
+        // 1 and 2 are exported, both call 3 which calls 4, 5 is only called by 2.
+        let graph = graph(
+            &[0, 10, 20, 30, 40, 50],
+            &[&[1, 2], &[3], &[3, 5], &[4], &[], &[]],
+        );
+        assert_eq!(graph.retained_sizes(), [150, 10, 70, 70, 40, 50]);
+        assert_eq!(
+            graph.dominator_children(),
+            [vec![1, 2, 3], vec![], vec![5], vec![4], vec![], vec![]]
+        );
+    }
+
+    #[test]
+    fn test_retained_sizes_unreachable_and_cycles() {
+        // 1 and 2 call each other, 3 isn't reachable and calls 2.
+        let graph = graph(&[0, 1, 2, 4], &[&[1], &[2], &[1], &[2]]);
+        assert_eq!(graph.retained_sizes(), [7, 1, 2, 4]);
+    }
+}
@@ -7,16 +7,22 @@
 
 use std::{
     fmt::Display,
+    io::{Read, Write},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{bail, Context};
-use reqwest::header::HeaderMap;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, RANGE},
+    StatusCode,
+};
 
 use crate::{
+    archive::{self, ArchiveFormat},
     config::Config,
-    utils::{ensure_binary, CommandExt},
+    utils::{check_success, copy_path, ensure_binary, CommandExt, ExistingFiles},
 };
 
 /// Custom rust repository.
@@ -24,8 +30,21 @@ const RUST_REPO: &str = "https://github.com/wasix-org/rust.git";
 /// Branch to use in the custom Rust repo.
 const RUST_BRANCH: &str = "wasix";
 
+/// Default name of the rustup toolchain, see [`toolchain_name`].
 const RUSTUP_TOOLCHAIN_NAME: &str = "wasix";
 
+/// File in downloaded toolchains that records their release tag.
+const RELEASE_TAG_FILE: &str = "wasix-release";
+
+/// Name of the rustup toolchain to build with, `wasix` unless overridden by
+/// the `CARGO_WASIX_TOOLCHAIN` environment variable.
+pub fn toolchain_name() -> String {
+    std::env::var("CARGO_WASIX_TOOLCHAIN")
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| RUSTUP_TOOLCHAIN_NAME.to_string())
+}
+
 #[cfg(target_os = "linux")]
 const LIBC_REPO: &str = "https://github.com/wasix-org/wasix-libc.git";
 
@@ -41,21 +60,44 @@ pub struct BuildToochainOptions {
     rust_host_triple: Option<String>,
 
     update_repos: bool,
+    /// Only print what would be done, without cloning, downloading or
+    /// building anything.
+    pub dry_run: bool,
+    /// Build rust even if libc failed, against the sysroot of an earlier
+    /// libc build.
+    pub keep_going: bool,
+}
+
+/// Returns whether libc and rust are built, respectively, for a
+/// `WASIX_COMPONENTS` or `--components` value.
+fn parse_components(value: &str) -> Option<(bool, bool)> {
+    match value {
+        "" | "all" => Some((true, true)),
+        "libc" => Some((true, false)),
+        "rust" => Some((false, true)),
+        _ => None,
+    }
 }
 
 impl BuildToochainOptions {
-    pub fn from_env() -> Result<Self, anyhow::Error> {
-        // Read components to build from env var.
-        let (build_libc, build_rust) = match std::env::var("WASIX_COMPONENTS")
-            .unwrap_or_default()
-            .as_str()
-        {
-            "" | "all" => (true, true),
-            "libc" => (true, false),
-            "rust" => (false, true),
-            other => {
-                bail!("Invalid env var WASIX_COMPONENTS with value '{other}' - expected 'all' or 'libc'");
-            }
+    /// Reads the options from the environment, with the components to build
+    /// from the `--components` flag if it was passed.
+    pub fn from_env(components: Option<&str>) -> Result<Self, anyhow::Error> {
+        let (build_libc, build_rust) = if let Some(components) = components {
+            parse_components(components).with_context(|| {
+                format!(
+                    "invalid value `{components}` for `--components`, expected `all`, \
+                     `libc` or `rust`"
+                )
+            })?
+        } else {
+            let components = std::env::var("WASIX_COMPONENTS").unwrap_or_default();
+            parse_components(&components).with_context(|| {
+                format!(
+                    "Invalid env var WASIX_COMPONENTS with value '{components}' - expected \
+                     'all', 'libc' or 'rust'"
+                )
+            })?
         };
 
         let root = if let Ok(dir) = std::env::var("WASIX_BUILD_DIR") {
@@ -76,6 +118,8 @@ impl BuildToochainOptions {
             build_libc,
             rust_host_triple,
             update_repos,
+            dry_run: false,
+            keep_going: false,
         })
     }
 }
@@ -117,27 +161,130 @@ fn ensure_libc_dir_valid(dir: &Path) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Free disk space needed to build the toolchain, in GiB.
+const BUILD_DISK_SPACE_GIB: u64 = 30;
+/// Free disk space needed to download and extract a toolchain, in GiB.
+const DOWNLOAD_DISK_SPACE_GIB: u64 = 3;
+
+/// Fails early if the volume of `dir` has less than `required_gib` GiB of free
+/// space, or the amount in `WASIX_MIN_DISK_SPACE` if that is set. That single
+/// value replaces both [`BUILD_DISK_SPACE_GIB`] and
+/// [`DOWNLOAD_DISK_SPACE_GIB`], as it is mostly used to disable the check.
+fn ensure_disk_space(
+    config: &Config,
+    dir: &Path,
+    required_gib: u64,
+    what: &str,
+) -> Result<(), anyhow::Error> {
+    let required_gib = match std::env::var("WASIX_MIN_DISK_SPACE") {
+        Ok(value) if !value.trim().is_empty() => value.trim().parse().with_context(|| {
+            format!("$WASIX_MIN_DISK_SPACE is set to `{value}`, which is not a number of GiB")
+        })?,
+        _ => required_gib,
+    };
+    if required_gib == 0 {
+        return Ok(());
+    }
+
+    // `dir` is only created later on.
+    let existing = dir
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    let available = match fs2::available_space(existing) {
+        Ok(available) => available,
+        Err(err) => {
+            config.warn(&format!(
+                "could not determine the free disk space at {}: {err}",
+                existing.display()
+            ));
+            return Ok(());
+        }
+    };
+    const GIB: u64 = 1 << 30;
+    if available < required_gib.saturating_mul(GIB) {
+        bail!(
+            "{what} needs about {required_gib} GiB of free disk space, but only {:.1} GiB \
+             are available at {}; free up some space, or set WASIX_MIN_DISK_SPACE to the \
+             number of GiB to require instead (0 disables this check)",
+            available as f64 / GIB as f64,
+            existing.display(),
+        );
+    }
+    Ok(())
+}
+
 /// Build the wasix toolchain.
 ///
 /// Returns the toolchain directory path.
 pub fn build_toolchain(
+    config: &Config,
     options: BuildToochainOptions,
 ) -> Result<Option<RustBuildOutput>, anyhow::Error> {
-    eprintln!("Building the wasix toolchain...");
-    eprintln!("WARNING: this could take a long time and use a lot of disk space!");
+    if options.dry_run {
+        print_build_plan(&options);
+        return Ok(None);
+    }
 
-    if ensure_binary("apt-get", &["--version"]).is_ok() {
-        setup_apt()?;
+    config.info("Building the wasix toolchain...");
+    config.warn("this could take a long time and use a lot of disk space!");
+    ensure_disk_space(
+        config,
+        &options.root,
+        BUILD_DISK_SPACE_GIB,
+        "Building the toolchain",
+    )?;
+
+    if use_apt() {
+        setup_apt(config)?;
     }
 
     let libc_dir = options.root.join("wasix-libc");
+    // `build_libc` cleans and rebuilds the sysroots in place, so a failed
+    // build leaves them half-built. Keep a copy of the last good ones to
+    // continue against.
+    let last_good = options.root.join("wasix-libc-last-good");
+    let keep_last_good = options.build_libc && options.keep_going && options.build_rust;
+    if keep_last_good && ensure_libc_dir_valid(&libc_dir).is_ok() {
+        copy_sysroots(&libc_dir, &last_good).context("could not save the current sysroot")?;
+    }
+    let mut libc_failed = false;
     if options.build_libc {
-        build_libc(&options.root, None, options.update_repos)?;
-        ensure_libc_dir_valid(&libc_dir).context("libc build failed")?;
+        let result = build_libc(config, &options.root, None, options.update_repos)
+            .and_then(|()| ensure_libc_dir_valid(&libc_dir).context("libc build failed"))
+            .context("failed to build the `libc` component");
+        match result {
+            Ok(()) => {}
+            Err(err) if options.keep_going && options.build_rust => {
+                config.print_error(&err);
+                libc_failed = true;
+            }
+            Err(err) => return Err(err),
+        }
     } else {
-        eprintln!("Skipping libc build!");
+        config.info("Skipping libc build!");
+    }
+
+    // Rust is only ever built against a complete sysroot, which after a
+    // failed libc build is the one of an earlier build.
+    if libc_failed {
+        ensure_libc_dir_valid(&last_good).context(
+            "cannot continue with the `rust` component because there is no sysroot of an \
+             earlier libc build",
+        )?;
+        copy_sysroots(&last_good, &libc_dir)
+            .context("could not restore the sysroot of an earlier libc build")?;
+        config.warn(&format!(
+            "continuing with the `rust` component, using the sysroot of an earlier libc \
+             build in {}",
+            libc_dir.display()
+        ));
+    } else if !options.build_libc {
         ensure_libc_dir_valid(&libc_dir)
             .context("libc build skipped, but specified path invalid")?;
+    } else if keep_last_good && last_good.exists() {
+        std::fs::remove_dir_all(&last_good)
+            .with_context(|| format!("could not remove {}", last_good.display()))?;
     }
 
     if !options.build_rust {
@@ -145,92 +292,393 @@ pub fn build_toolchain(
     }
 
     let out = build_rust(
+        config,
         &options.root,
         None,
         options.rust_host_triple.as_deref(),
         options.update_repos,
-    )?;
+    )
+    .context("failed to build the `rust` component")?;
 
-    RustupToolchain::link(RUSTUP_TOOLCHAIN_NAME, &out.toolchain_dir)?;
+    RustupToolchain::link(config, &toolchain_name(), &out.toolchain_dir)?;
 
+    if libc_failed {
+        bail!(
+            "the `libc` component failed to build, `rust` was built and linked against \
+             the sysroot of an earlier libc build"
+        );
+    }
     Ok(Some(out))
 }
 
+/// Replaces the sysroots in the libc directory `to` with copies of those in
+/// `from`.
+fn copy_sysroots(from: &Path, to: &Path) -> Result<(), anyhow::Error> {
+    for sysroot in ["sysroot32", "sysroot64"] {
+        let target = to.join(sysroot);
+        if target.exists() {
+            std::fs::remove_dir_all(&target)
+                .with_context(|| format!("could not remove {}", target.display()))?;
+        }
+        copy_path(
+            &from.join(sysroot),
+            &target,
+            ExistingFiles::Overwrite,
+            false,
+        )?;
+    }
+    Ok(())
+}
+
+/// Prints the resolved options of a toolchain build and the commands it would
+/// run.
+fn print_build_plan(options: &BuildToochainOptions) {
+    let root = &options.root;
+    let rust_dir = root.join("wasix-rust");
+    let components = match (options.build_libc, options.build_rust) {
+        (true, true) => "libc, rust",
+        (true, false) => "libc",
+        _ => "rust",
+    };
+
+    eprintln!("Dry run: nothing will be cloned, downloaded or built.");
+    eprintln!("  build dir:  {}", root.display());
+    eprintln!("  components: {components}");
+    eprintln!("  targets:    wasm32-wasmer-wasi, wasm64-wasmer-wasi");
+    #[cfg(target_os = "linux")]
+    eprintln!("  libc repo:  {LIBC_REPO} (main)");
+    eprintln!("  rust repo:  {RUST_REPO} ({RUST_BRANCH})");
+    eprintln!(
+        "  rust host:  {}",
+        options
+            .rust_host_triple
+            .as_deref()
+            .unwrap_or("(detected by x.py)")
+    );
+    eprintln!("  update repos: {}", options.update_repos);
+    if options.keep_going {
+        eprintln!("  continue:   build rust even if libc fails");
+    }
+
+    // The same commands as those of the build, so the plan can't drift from
+    // what is actually run.
+    let mut commands = Vec::new();
+    if use_apt() {
+        let missing = missing_apt_packages(|binary| ensure_binary(binary, &["--version"]).is_ok());
+        if !missing.is_empty() {
+            if let Some(cmd) = apt_install_command(&missing) {
+                commands.push(describe_command(&cmd));
+            }
+        }
+    }
+    if options.build_libc {
+        #[cfg(not(target_os = "linux"))]
+        commands.push("(libc builds are only supported on Linux)".to_string());
+        #[cfg(target_os = "linux")]
+        {
+            let libc_dir = root.join("wasix-libc");
+            if options.update_repos {
+                for (cmd, _) in git_commands(LIBC_REPO, "main", &libc_dir, true) {
+                    commands.push(describe_command(&cmd));
+                }
+            }
+            let llvm_dir = root.join("llvm-15");
+            if !llvm_dir.join("bin").join("clang").is_file() {
+                let archive_path = libc_dir.join("llvm.tar.xz");
+                for cmd in llvm_commands(&archive_path, &llvm_dir) {
+                    commands.push(describe_command(&cmd));
+                }
+            }
+            for script in ["build32.sh", "build64.sh"] {
+                for cmd in libc_commands(&libc_dir, &llvm_dir, script) {
+                    commands.push(describe_command(&cmd));
+                }
+            }
+        }
+    }
+    if options.build_rust {
+        if options.update_repos {
+            for (cmd, _) in git_commands(RUST_REPO, RUST_BRANCH, &rust_dir, true) {
+                commands.push(describe_command(&cmd));
+            }
+        }
+        let host = options.rust_host_triple.as_deref();
+        for cmd in rust_build_commands(&rust_dir, host) {
+            commands.push(describe_command(&cmd));
+        }
+        let toolchain_dir = rust_dir
+            .join("build")
+            .join(host.unwrap_or("<host>"))
+            .join("stage2");
+        commands.push(describe_command(&RustupToolchain::link_command(
+            &toolchain_name(),
+            &toolchain_dir,
+        )));
+    }
+
+    eprintln!("Commands that would be run:");
+    for command in commands {
+        eprintln!("  {command}");
+    }
+}
+
+/// Formats `cmd` for the plan of a dry run, with the directory it runs in.
+fn describe_command(cmd: &Command) -> String {
+    let mut line = cmd.get_program().to_string_lossy().into_owned();
+    for arg in cmd.get_args() {
+        line.push(' ');
+        line.push_str(&arg.to_string_lossy());
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        line.push_str(&format!("  (in {})", dir.display()));
+    }
+    line
+}
+
+/// Packages needed to build the toolchain on Debian based systems, with the
+/// binaries that show they are installed.
+const APT_PACKAGES: &[(&str, &[&str])] = &[
+    ("curl", &["curl"]),
+    ("xz-utils", &["xz"]),
+    ("build-essential", &["cc", "c++", "make"]),
+    ("git", &["git"]),
+    ("python3", &["python3"]),
+];
+
+/// Returns the packages of [`APT_PACKAGES`] for which `has_binary` doesn't
+/// find all binaries.
+fn missing_apt_packages(has_binary: impl Fn(&str) -> bool) -> Vec<&'static str> {
+    APT_PACKAGES
+        .iter()
+        .filter(|(_, binaries)| !binaries.iter().all(|binary| has_binary(binary)))
+        .map(|(package, _)| *package)
+        .collect()
+}
+
+/// Returns whether `apt-get` should install packages, i.e. it exists and
+/// `WASIX_SKIP_APT` isn't set.
+fn use_apt() -> bool {
+    !std::env::var("WASIX_SKIP_APT").is_ok_and(|v| v == "1" || v == "true")
+        && ensure_binary("apt-get", &["--version"]).is_ok()
+}
+
 /// Install basic required packages on Debian based systems.
-fn setup_apt() -> Result<(), anyhow::Error> {
-    let have_sudo = ensure_binary("sudo", &["--version"]).is_ok();
-
-    let args = &[
-        "install",
-        "-y",
-        // Packages.
-        "curl",
-        "xz-utils",
-        "build-essential",
-        "git",
-        "python3",
-    ];
-
-    if have_sudo {
-        Command::new("sudo")
-            .arg("apt-get")
-            .args(args)
-            .run_verbose()?;
-    } else {
-        Command::new("apt-get").args(args).run_verbose()?;
+///
+/// Nothing is installed if all packages are present already, and without
+/// root or `sudo` the missing packages are only listed.
+fn setup_apt(config: &Config) -> Result<(), anyhow::Error> {
+    let missing = missing_apt_packages(|binary| ensure_binary(binary, &["--version"]).is_ok());
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let Some(mut cmd) = apt_install_command(&missing) else {
+        config.warn(&format!(
+            "these packages are needed, but can't be installed without root or \
+             sudo: {}. Install them yourself, or set WASIX_SKIP_APT=1 to skip this check.",
+            missing.join(" ")
+        ));
+        return Ok(());
+    };
+    if let Err(err) = cmd.run_verbose() {
+        config.warn(&format!(
+            "failed to install {}: {err:#}. Install them yourself, or set \
+             WASIX_SKIP_APT=1 to skip this step.",
+            missing.join(" ")
+        ));
     }
 
     Ok(())
 }
 
+/// Returns the command that installs the `missing` packages with `apt-get`,
+/// using `sudo` unless running as root, or `None` if neither is available.
+fn apt_install_command(missing: &[&str]) -> Option<Command> {
+    let is_root = Command::new("id")
+        .arg("-u")
+        .capture_stdout()
+        .is_ok_and(|uid| uid.trim() == "0");
+    let mut cmd = if is_root {
+        Command::new("apt-get")
+    } else if ensure_binary("sudo", &["--version"]).is_ok() {
+        let mut cmd = Command::new("sudo");
+        cmd.arg("apt-get");
+        cmd
+    } else {
+        return None;
+    };
+    cmd.args(["install", "-y"]).args(missing);
+    Some(cmd)
+}
+
 /// Initialize a Git repo.
 ///
 /// Clone if it doesn't exist yet, otherwise update the branch/tag.
 fn prepare_git_repo(
+    config: &Config,
     source: &str,
     tag: &str,
     path: &Path,
     all_submodules: bool,
 ) -> Result<(), anyhow::Error> {
-    eprintln!("Preparing git repo {source} with tag/branch {tag}");
+    config.info(&format!(
+        "Preparing git repo {source} with tag/branch {tag}"
+    ));
     ensure_binary("git", &["--version"])?;
 
+    for (mut cmd, failure) in git_commands(source, tag, path, all_submodules) {
+        run_git(config, &mut cmd, path).context(failure)?;
+    }
+
+    config.info(&format!("Git repo ready at {}", path.display()));
+
+    Ok(())
+}
+
+/// Returns the git commands of [`prepare_git_repo`], each with the message of
+/// the error if it fails.
+fn git_commands(
+    source: &str,
+    tag: &str,
+    path: &Path,
+    all_submodules: bool,
+) -> Vec<(Command, String)> {
+    let mut commands = Vec::new();
     if !path.join(".git").is_dir() {
-        Command::new("git")
-            .args([
-                "clone",
-                // "--depth",
-                // "1",
-                // "--recurse-submodules",
-                // "--shallow-submodules",
-                source,
-            ])
-            .arg(path)
-            .run_verbose()?;
-    }
-    Command::new("git")
-        .args(["fetch", "origin", tag])
-        .current_dir(path)
-        .run_verbose()?;
-    Command::new("git")
-        .args(["reset", "--hard", tag])
-        .current_dir(path)
-        .run_verbose()?;
+        let mut cmd = Command::new("git");
+        cmd.args(["clone", "--progress", source]).arg(path);
+        commands.push((cmd, format!("failed to clone {source}")));
+    }
+    let mut cmd = Command::new("git");
+    cmd.args(["fetch", "--progress", "origin", tag])
+        .current_dir(path);
+    commands.push((cmd, format!("failed to fetch `{tag}` from {source}")));
+    let mut cmd = Command::new("git");
+    cmd.args(["reset", "--hard", tag]).current_dir(path);
+    commands.push((
+        cmd,
+        format!("failed to check out `{tag}` in {}", path.display()),
+    ));
 
     if all_submodules {
-        Command::new("git")
-            .args(["submodule", "update", "--init", "--recursive", "--progress"]) // added progress as llvm takes a very long time
-            .current_dir(path)
-            .run_verbose()?;
+        let mut cmd = Command::new("git");
+        // `--progress` because LLVM takes a very long time.
+        cmd.args(["submodule", "update", "--init", "--recursive", "--progress"])
+            .current_dir(path);
+        let failure = if source == RUST_REPO {
+            format!(
+                "failed to update the submodules of {source}; the LLVM submodule is \
+                 several gigabytes and its download often times out, run the build again \
+                 to resume it"
+            )
+        } else {
+            format!("failed to update the submodules of {source}")
+        };
+        commands.push((cmd, failure));
     }
+    commands
+}
 
-    eprintln!("Git repo ready at {}", path.display());
+/// Runs a git command of [`prepare_git_repo`] for the repository at `path`.
+///
+/// The output of git is passed through, but also kept to add a hint about how
+/// to recover to the error if it fails.
+fn run_git(config: &Config, cmd: &mut Command, path: &Path) -> Result<(), anyhow::Error> {
+    config.info(&format!(
+        "Running git {}:",
+        cmd.get_args()
+            .map(|x| x.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ")
+    ));
+    let mut child = cmd
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to create process {cmd:?}"))?;
+    let mut pipe = child.stderr.take().expect("stderr is piped");
+    let mut stderr = Vec::new();
+    let mut buf = [0; 8192];
+    loop {
+        let n = pipe.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        std::io::stderr().write_all(&buf[..n])?;
+        stderr.extend_from_slice(&buf[..n]);
+        // Only the end is of interest, progress output can get long.
+        if stderr.len() > 64 * 1024 {
+            stderr.drain(..stderr.len() - 16 * 1024);
+        }
+    }
+    let status = child.wait()?;
 
-    Ok(())
+    // The output was shown already, so it's not repeated in the error.
+    let err = match check_success(cmd, &status, &[], &[]) {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    match git_failure_hint(&String::from_utf8_lossy(&stderr), path) {
+        Some(hint) => Err(err.context(hint)),
+        None => Err(err),
+    }
+}
+
+/// Explains how to recover from common git failures, based on the `stderr`
+/// of git for the repository at `path`.
+fn git_failure_hint(stderr: &str, path: &Path) -> Option<String> {
+    let matches = |patterns: &[&str]| patterns.iter().any(|p| stderr.contains(p));
+    if matches(&[
+        "Authentication failed",
+        "could not read Username",
+        "Permission denied (publickey)",
+        "terminal prompts disabled",
+    ]) {
+        Some(
+            "git could not authenticate, but the wasix repositories are public: check that \
+             no `url.<base>.insteadOf` setting or credential helper in your git \
+             configuration rewrites them to a private or SSH URL"
+                .to_string(),
+        )
+    } else if matches(&[
+        "Could not resolve host",
+        "Failed to connect",
+        "Connection timed out",
+        "Connection reset",
+        "early EOF",
+        "RPC failed",
+        "the remote end hung up unexpectedly",
+    ]) {
+        Some(
+            "the connection to the git server failed or was interrupted: check your network \
+             and proxy settings, and run the build again to continue where it stopped"
+                .to_string(),
+        )
+    } else if matches(&[
+        "would be overwritten",
+        "Please commit your changes or stash them",
+        "untracked working tree files",
+    ]) {
+        Some(format!(
+            "the checkout at {} has local changes: commit or stash them, or delete the \
+             directory to start from a fresh clone",
+            path.display()
+        ))
+    } else if matches(&["shallow"]) {
+        Some(format!(
+            "the checkout at {} is a shallow clone that lacks the requested commit: run \
+             `git fetch --unshallow` in it, or delete the directory to clone it again",
+            path.display()
+        ))
+    } else {
+        None
+    }
 }
 
 #[cfg(not(target_os = "linux"))]
 fn build_libc(
+    _config: &Config,
     _build_root: &Path,
     _git_tag: Option<String>,
     _update_repo: bool,
@@ -242,13 +690,12 @@ fn build_libc(
 // Currently only works on Linux.
 #[cfg(target_os = "linux")]
 fn build_libc(
+    config: &Config,
     build_root: &Path,
     git_tag: Option<String>,
     update_repo: bool,
 ) -> Result<(), anyhow::Error> {
-    use crate::utils::copy_path;
-
-    eprintln!("Building wasix-libc...");
+    config.info("Building wasix-libc...");
 
     ensure_binary("git", &["--version"])?;
 
@@ -259,48 +706,31 @@ fn build_libc(
     let libc_dir = build_root.join("wasix-libc");
 
     if update_repo {
-        prepare_git_repo(LIBC_REPO, git_tag, &libc_dir, true)?;
+        prepare_git_repo(config, LIBC_REPO, git_tag, &libc_dir, true)?;
     }
 
-    eprintln!("Ensuring LLVM...");
+    config.info("Ensuring LLVM...");
     let llvm_dir = build_root.join("llvm-15");
     if !llvm_dir.join("bin").join("clang").is_file() {
-        eprintln!("Downloading LLVM...");
+        config.info("Downloading LLVM...");
         std::fs::create_dir_all(&llvm_dir)?;
 
         let archive_path = libc_dir.join("llvm.tar.xz");
 
-        Command::new("curl")
-            .args(["-L", "-o"])
-            .arg(&archive_path)
-            .arg(LLVM_LINUX_SOURCE)
-            .run_verbose()?;
-
-        eprintln!("Extracting LLVM...");
-        Command::new("tar")
-            .args(["xJf"])
-            .arg(&archive_path)
-            .arg("-C")
-            .arg(&llvm_dir)
-            .args(["--strip-components", "1"])
-            .run_verbose()?;
+        let [mut download, mut extract] = llvm_commands(&archive_path, &llvm_dir);
+        download.run_verbose()?;
+        config.info("Extracting LLVM...");
+        extract.run_verbose()?;
 
         std::fs::remove_file(&archive_path).ok();
 
-        eprintln!("Downloaded LLVM to {}", llvm_dir.display());
+        config.info(&format!("Downloaded LLVM to {}", llvm_dir.display()));
     }
     // Sanity check for clang.
     Command::new(llvm_dir.join("bin").join("clang"))
         .arg("--version")
         .run_verbose()?;
 
-    let original_path_env = std::env::var("PATH")?;
-    let path = format!(
-        "{}:{}",
-        llvm_dir.join("bin").to_str().unwrap(),
-        original_path_env
-    );
-
     // Now run the build.
 
     // TODO: Should we run make clean? (prevents caching...)
@@ -323,32 +753,18 @@ fn build_libc(
         std::fs::remove_dir_all(&dir64_tmp)?;
     }
 
-    eprintln!("Building wasm32...");
-    Command::new("make")
-        .arg("clean")
-        .current_dir(&libc_dir)
-        .run_verbose()?;
-    Command::new("bash")
-        .arg("./build32.sh")
-        .current_dir(&libc_dir)
-        .env("PATH", &path)
-        .run_verbose()
-        .context("could not build sysroot32")?;
-
-    copy_path(&dir32, &dir32_tmp, false, true)?;
-
-    eprintln!("Building wasm64...");
-    Command::new("make")
-        .arg("clean")
-        .current_dir(&libc_dir)
-        .run_verbose()?;
-    Command::new("bash")
-        .arg("./build64.sh")
-        .current_dir(&libc_dir)
-        .env("PATH", &path)
-        .run_verbose()
-        .context("could not build sysroot64")?;
-    // copy_path(&dir64, &dir64_tmp, false, true)?;
+    config.info("Building wasm32...");
+    let [mut clean, mut build] = libc_commands(&libc_dir, &llvm_dir, "build32.sh");
+    clean.run_verbose()?;
+    build.run_verbose().context("could not build sysroot32")?;
+
+    copy_path(&dir32, &dir32_tmp, ExistingFiles::Overwrite, true)?;
+
+    config.info("Building wasm64...");
+    let [mut clean, mut build] = libc_commands(&libc_dir, &llvm_dir, "build64.sh");
+    clean.run_verbose()?;
+    build.run_verbose().context("could not build sysroot64")?;
+    // copy_path(&dir64, &dir64_tmp, ExistingFiles::Overwrite, true)?;
 
     // Command::new("make")
     //     .arg("clean")
@@ -362,16 +778,55 @@ fn build_libc(
     std::fs::rename(&dir32_tmp, &dir32).context("could not copy temp dir")?;
     // std::fs::rename(&dir64_tmp, &dir64)?;
 
-    eprintln!(
+    config.info(&format!(
         "wasix-libc build complete!\n{}\n{}",
         dir32.display(),
         dir64.display(),
-    );
+    ));
 
     Ok(())
 }
 
+/// Returns the commands that download LLVM to `archive_path` and extract it
+/// into `llvm_dir`.
+#[cfg(target_os = "linux")]
+fn llvm_commands(archive_path: &Path, llvm_dir: &Path) -> [Command; 2] {
+    let mut download = Command::new("curl");
+    download
+        .args(["-L", "-o"])
+        .arg(archive_path)
+        .arg(LLVM_LINUX_SOURCE);
+    let mut extract = Command::new("tar");
+    extract
+        .args(["xJf"])
+        .arg(archive_path)
+        .arg("-C")
+        .arg(llvm_dir)
+        .args(["--strip-components", "1"]);
+    [download, extract]
+}
+
+/// Returns the commands that clean the checkout at `libc_dir` and then build
+/// one sysroot with `script`, using the clang in `llvm_dir`.
+#[cfg(target_os = "linux")]
+fn libc_commands(libc_dir: &Path, llvm_dir: &Path, script: &str) -> [Command; 2] {
+    let path = format!(
+        "{}:{}",
+        llvm_dir.join("bin").display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+    let mut clean = Command::new("make");
+    clean.arg("clean").current_dir(libc_dir);
+    let mut build = Command::new("bash");
+    build
+        .arg(format!("./{script}"))
+        .current_dir(libc_dir)
+        .env("PATH", path);
+    [clean, build]
+}
+
 /// Output info of a successful rust toolchain build.
+#[allow(dead_code)]
 pub struct RustBuildOutput {
     pub target: String,
     pub toolchain_dir: PathBuf,
@@ -379,6 +834,7 @@ pub struct RustBuildOutput {
 
 /// Build the Rust toolchain for wasm{32,64}-wasmer-wasi
 fn build_rust(
+    config: &Config,
     build_root: &Path,
     tag: Option<&str>,
     host_triple: Option<&str>,
@@ -391,7 +847,7 @@ fn build_rust(
     ensure_libc_dir_valid(&libc_dir)?;
 
     if update_repo {
-        prepare_git_repo(RUST_REPO, git_tag, &rust_dir, true)?;
+        prepare_git_repo(config, RUST_REPO, git_tag, &rust_dir, true)?;
     }
 
     let sysroot32 = libc_dir.join("sysroot32");
@@ -415,7 +871,7 @@ download-ci-llvm = false
 [build]
 target = ["wasm32-wasmer-wasi", "wasm64-wasmer-wasi"]
 extended = true
-tools = [ "clippy", "rustfmt" ]
+tools = [ "cargo", "clippy", "rustfmt" ]
 configure-args = []
 
 [rust]
@@ -430,7 +886,7 @@ wasi-root = "{sysroot64}"
 "#;
 
     // Note: need to replace \ with \\ for Windows paths.
-    let config = config_tpl
+    let config_toml = config_tpl
         .replace(
             "{sysroot32}",
             &sysroot32.to_str().unwrap().replace('\\', "\\\\"),
@@ -440,36 +896,13 @@ wasi-root = "{sysroot64}"
             &sysroot64.to_str().unwrap().replace('\\', "\\\\"),
         );
 
-    std::fs::write(rust_dir.join("config.toml"), config)?;
+    std::fs::write(rust_dir.join("config.toml"), config_toml)?;
 
-    // Stage 1.
+    let [mut stage1, mut stage2] = rust_build_commands(&rust_dir, host_triple);
+    stage1.run_verbose()?;
+    stage2.run_verbose()?;
 
-    let has_python3 = Command::new("python3").arg("--version").run().is_ok();
-    let python_cmd = if has_python3 { "python3" } else { "python" };
-
-    let mut cmd = Command::new(python_cmd);
-    // Added because x.py checks for GITHUB_ACTIONS env var and does some weird
-    // things that break the build.
-    cmd.env("GITHUB_ACTIONS", "false");
-    cmd.args(["x.py", "build"]);
-    if let Some(triple) = host_triple {
-        cmd.args(["--host", triple]);
-    }
-    cmd.current_dir(&rust_dir).run_verbose()?;
-
-    // Stage 2.
-    let mut cmd = Command::new("python3");
-    // Added because x.py checks for GITHUB_ACTIONS env var and does some weird
-    // things that break the build.
-    cmd.env("GITHUB_ACTIONS", "false");
-    cmd.arg(rust_dir.join("x.py"))
-        .args(["build", "--stage", "2"]);
-    if let Some(triple) = host_triple {
-        cmd.args(["--host", triple]);
-    }
-    cmd.current_dir(&rust_dir).run_verbose()?;
-
-    eprintln!("Rust build complete!");
+    config.info("Rust build complete!");
 
     if let Some(triple) = host_triple {
         let dir = rust_dir.join("build").join(triple).join("stage2");
@@ -497,6 +930,35 @@ wasi-root = "{sysroot64}"
     }
 }
 
+/// Returns the commands of the stage 1 and stage 2 builds of the checkout at
+/// `rust_dir`.
+fn rust_build_commands(rust_dir: &Path, host_triple: Option<&str>) -> [Command; 2] {
+    let has_python3 = Command::new("python3")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success());
+    let python_cmd = if has_python3 { "python3" } else { "python" };
+
+    let mut stage1 = Command::new(python_cmd);
+    stage1.args(["x.py", "build"]);
+
+    let mut stage2 = Command::new("python3");
+    stage2
+        .arg(rust_dir.join("x.py"))
+        .args(["build", "--stage", "2"]);
+
+    for cmd in [&mut stage1, &mut stage2] {
+        // Added because x.py checks for GITHUB_ACTIONS env var and does some
+        // weird things that break the build.
+        cmd.env("GITHUB_ACTIONS", "false");
+        if let Some(triple) = host_triple {
+            cmd.args(["--host", triple]);
+        }
+        cmd.current_dir(rust_dir);
+    }
+    [stage1, stage2]
+}
+
 /// Try to get the host target triple.
 ///
 /// Only checks for targets that have pre-built toolchains.
@@ -524,11 +986,28 @@ struct GithubReleaseData {
     tag_name: String,
 }
 
+impl GithubReleaseData {
+    /// Finds the asset named `stem` followed by the extension of a supported
+    /// archive format, preferring the formats in [`ArchiveFormat::ALL`] order.
+    fn find_archive_asset(&self, stem: &str) -> Option<(&GithubAsset, ArchiveFormat)> {
+        ArchiveFormat::ALL.into_iter().find_map(|format| {
+            let name = format!("{stem}{}", format.extension());
+            self.assets
+                .iter()
+                .find(|asset| asset.name == name)
+                .map(|asset| (asset, format))
+        })
+    }
+}
+
 /// Release asset returned by Github API.
 #[derive(serde::Deserialize)]
 struct GithubAsset {
     browser_download_url: String,
     name: String,
+    /// Size in bytes.
+    #[serde(default)]
+    size: Option<u64>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -557,28 +1036,171 @@ impl From<String> for ToolchainSpec {
 }
 
 impl ToolchainSpec {
+    /// The release in `WASIX_TOOLCHAIN_VERSION`, or the latest one if it isn't
+    /// set. Used whenever no release is passed explicitly.
+    pub fn from_env() -> ToolchainSpec {
+        match std::env::var("WASIX_TOOLCHAIN_VERSION") {
+            Ok(version) if !version.trim().is_empty() => version.trim().to_string().into(),
+            _ => ToolchainSpec::Latest,
+        }
+    }
+
     pub fn is_latest(&self) -> bool {
         *self == ToolchainSpec::Latest
     }
 }
 
+/// Github API url with the information about the release `toolchain_spec`.
+fn release_url(toolchain_spec: &ToolchainSpec) -> String {
+    let repo = RUST_REPO
+        .trim_start_matches("https://github.com/")
+        .trim_end_matches(".git");
+
+    let postfix = if toolchain_spec.is_latest() {
+        format!("{toolchain_spec}")
+    } else {
+        format!("tags/{toolchain_spec}")
+    };
+
+    format!("https://api.github.com/repos/{repo}/releases/{postfix}")
+}
+
+/// Longest `Retry-After` of the GitHub API that is waited for before retrying
+/// a request once, instead of failing right away.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// A request that the GitHub API refused because of its rate limit.
+#[derive(Debug, PartialEq)]
+struct RateLimited {
+    /// How long GitHub asks to wait before retrying, from `Retry-After`.
+    retry_after: Option<Duration>,
+    /// How long until the limit resets, from `X-RateLimit-Reset`.
+    reset_in: Option<Duration>,
+}
+
+impl RateLimited {
+    /// Detects a rate limit from a response, which GitHub reports with a 403
+    /// or 429 status and either no remaining requests or a `Retry-After`.
+    fn from_response(status: StatusCode, headers: &HeaderMap, now: SystemTime) -> Option<Self> {
+        if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+            return None;
+        }
+        let header =
+            |name: &str| -> Option<u64> { headers.get(name)?.to_str().ok()?.trim().parse().ok() };
+        let retry_after = header("retry-after").map(Duration::from_secs);
+        if header("x-ratelimit-remaining") != Some(0) && retry_after.is_none() {
+            return None;
+        }
+        let reset_in = header("x-ratelimit-reset").map(|reset| {
+            let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+            Duration::from_secs(reset).saturating_sub(now)
+        });
+        Some(RateLimited {
+            retry_after,
+            reset_in,
+        })
+    }
+
+    fn message(&self) -> String {
+        let mut msg = "the GitHub API rate limit was exceeded".to_string();
+        if let Some(wait) = self.retry_after.or(self.reset_in) {
+            let minutes = wait.as_secs().div_ceil(60);
+            let unit = if minutes == 1 { "minute" } else { "minutes" };
+            msg.push_str(&format!(", it resets in {minutes} {unit}"));
+        }
+        msg.push_str(
+            "; set GITHUB_TOKEN to a GitHub token, or GITHUB_TOKEN_FILE to a file containing \
+             one, to get a higher limit",
+        );
+        msg
+    }
+}
+
+/// Sends a GET request to the GitHub API. If it is refused because of the
+/// rate limit, it is retried once if GitHub asks to wait for no longer than
+/// `MAX_RETRY_AFTER`, and otherwise fails with an explanation.
+fn send_github_request(
+    config: &Config,
+    client: &reqwest::blocking::Client,
+    url: &str,
+) -> Result<reqwest::blocking::Response, anyhow::Error> {
+    let mut retried = false;
+    loop {
+        let response = client.get(url).send()?;
+        let Some(limited) =
+            RateLimited::from_response(response.status(), response.headers(), SystemTime::now())
+        else {
+            return Ok(response);
+        };
+        match limited.retry_after {
+            Some(wait) if !retried && wait <= MAX_RETRY_AFTER => {
+                config.warn(&format!(
+                    "the GitHub API rate limit was exceeded, retrying in {}s",
+                    wait.as_secs()
+                ));
+                std::thread::sleep(wait);
+                retried = true;
+            }
+            _ => bail!("{}", limited.message()),
+        }
+    }
+}
+
+/// Returns the GitHub token from the value of `GITHUB_TOKEN`, or else from the
+/// file `GITHUB_TOKEN_FILE` points to, as CI systems often mount secrets as
+/// files.
+fn github_token(
+    env_token: Option<String>,
+    token_file: Option<PathBuf>,
+) -> Result<Option<String>, anyhow::Error> {
+    let non_empty = |token: &str| Some(token.trim().to_string()).filter(|t| !t.is_empty());
+    if let Some(token) = env_token.as_deref().and_then(non_empty) {
+        return Ok(Some(token));
+    }
+    match token_file.filter(|path| !path.as_os_str().is_empty()) {
+        // The error never contains the contents of the file.
+        Some(path) => {
+            let token = std::fs::read_to_string(&path).with_context(|| {
+                format!(
+                    "failed to read the GitHub token from `{}`, which $GITHUB_TOKEN_FILE \
+                     is set to",
+                    path.display()
+                )
+            })?;
+            Ok(non_empty(&token))
+        }
+        None => Ok(None),
+    }
+}
+
 /// Download a pre-built toolchain from Github releases.
 fn download_toolchain(
+    config: &Config,
     target: &str,
     toolchains_root_dir: &Path,
     toolchain_spec: ToolchainSpec,
 ) -> Result<PathBuf, anyhow::Error> {
+    ensure_disk_space(
+        config,
+        toolchains_root_dir,
+        DOWNLOAD_DISK_SPACE_GIB,
+        "Downloading the toolchain",
+    )?;
+
     let mut headers = HeaderMap::new();
 
     // Use api token if specified via env var.
     // Prevents 403 errors when IP is throttled by Github API.
-    let gh_token = std::env::var("GITHUB_TOKEN")
-        .ok()
-        .map(|x| x.trim().to_string())
-        .filter(|x| !x.is_empty());
+    let gh_token = github_token(
+        std::env::var("GITHUB_TOKEN").ok(),
+        std::env::var_os("GITHUB_TOKEN_FILE").map(PathBuf::from),
+    )?;
 
     if let Some(token) = gh_token {
-        headers.insert("authorization", format!("Bearer {token}").parse()?);
+        let mut value: HeaderValue = format!("Bearer {token}").parse()?;
+        // Keeps the token out of `Debug` output, such as in errors.
+        value.set_sensitive(true);
+        headers.insert("authorization", value);
     }
 
     let client = reqwest::blocking::Client::builder()
@@ -586,34 +1208,21 @@ fn download_toolchain(
         .user_agent("cargo-wasix")
         .build()?;
 
-    let repo = RUST_REPO
-        .trim_start_matches("https://github.com/")
-        .trim_end_matches(".git");
+    let release_url = release_url(&toolchain_spec);
 
-    let postfix = if toolchain_spec.is_latest() {
-        format!("{toolchain_spec}")
-    } else {
-        format!("tags/{toolchain_spec}")
-    };
+    config.info(&format!(
+        "Finding {toolchain_spec} release... ({release_url})..."
+    ));
 
-    let release_url = format!("https://api.github.com/repos/{repo}/releases/{postfix}");
-
-    eprintln!("Finding {toolchain_spec} release... ({release_url})...");
-
-    let release: GithubReleaseData = client
-        .get(&release_url)
-        .send()?
+    let release: GithubReleaseData = send_github_request(config, &client, &release_url)?
         .error_for_status()
         .context("Could not download release info")?
         .json()
         .context("Could not deserialize release info")?;
 
     // Try to find the asset for the wanted target triple.
-    let rust_asset_name = format!("rust-toolchain-{target}.tar.gz");
-    let rust_asset = release
-        .assets
-        .iter()
-        .find(|asset| asset.name == rust_asset_name)
+    let (rust_asset, rust_format) = release
+        .find_archive_asset(&format!("rust-toolchain-{target}"))
         .with_context(|| {
             format!(
                 "Release {} does not have a prebuilt toolchain for host {}",
@@ -622,42 +1231,41 @@ fn download_toolchain(
         })?;
 
     // Find sysroot asset.
-    let sysroot_asset = release
-        .assets
-        .iter()
-        .find(|asset| asset.name == "wasix-libc.tar.gz")
-        .with_context(|| {
+    let (sysroot_asset, sysroot_format) =
+        release.find_archive_asset("wasix-libc").with_context(|| {
             format!(
                 "Release {} does not have the sysroot asset",
                 release.tag_name,
             )
         })?;
 
-    let toolchain_dir = toolchains_root_dir.join(format!("{target}_{}", release.tag_name));
-    if toolchain_dir.is_dir() {
-        eprintln!(
-            "Toolchain path {} already exists - deleting existing files!",
-            toolchain_dir.display()
-        );
-        std::fs::remove_dir_all(&toolchain_dir)?;
+    // Archives are downloaded to `downloads`, so that an interrupted download
+    // can be resumed, and extracted into a staging directory that only
+    // replaces the toolchain directory once everything succeeded.
+    let dir_name = format!("{target}_{}", release.tag_name);
+    let toolchain_dir = toolchains_root_dir.join(&dir_name);
+    let download_dir = toolchains_root_dir.join("downloads").join(&dir_name);
+    let staging_dir = toolchains_root_dir.join(format!("{dir_name}.partial"));
+    std::fs::create_dir_all(&download_dir).with_context(|| {
+        format!(
+            "Could not create download directory {}",
+            download_dir.display()
+        )
+    })?;
+    if staging_dir.is_dir() {
+        std::fs::remove_dir_all(&staging_dir)?;
     }
 
     // Download and extract sysroot.
-    eprintln!(
-        "Downloading sysroot from url '{}'...",
-        &sysroot_asset.browser_download_url
-    );
-    let res = client
-        .get(&sysroot_asset.browser_download_url)
-        .send()?
-        .error_for_status()?;
-
-    eprintln!("Extracting...");
-    let decoder = flate2::read::GzDecoder::new(res);
-    let mut archive = tar::Archive::new(decoder);
-
-    let out_dir = toolchain_dir.join("sysroot");
-    archive.unpack(&out_dir)?;
+    let sysroot_archive = download_asset(config, &client, sysroot_asset, &download_dir)?;
+
+    config.info("Extracting...");
+    let out_dir = staging_dir.join("sysroot");
+    archive::extract(
+        std::fs::File::open(&sysroot_archive)?,
+        sysroot_format,
+        &out_dir,
+    )?;
 
     // The archive contains a redundant additional directory. Strip it.
     let wrapper = out_dir.join("wasix-libc");
@@ -671,21 +1279,13 @@ fn download_toolchain(
     }
 
     // Download.
-    eprintln!(
-        "Downloading Rust toolchain from url '{}'...",
-        &rust_asset.browser_download_url
-    );
-    let res = client
-        .get(&rust_asset.browser_download_url)
-        .send()?
-        .error_for_status()?;
-
-    eprintln!("Extracting...");
-    let decoder = flate2::read::GzDecoder::new(res);
-    let mut archive = tar::Archive::new(decoder);
+    let rust_archive = download_asset(config, &client, rust_asset, &download_dir)?;
 
-    let rust_dir = toolchain_dir.join("rust");
-    archive.unpack(&rust_dir)?;
+    config.info("Extracting...");
+    let rust_dir = staging_dir.join("rust");
+    archive::extract(std::fs::File::open(&rust_archive)?, rust_format, &rust_dir)?;
+    std::fs::write(rust_dir.join(RELEASE_TAG_FILE), &release.tag_name)
+        .context("Could not record the toolchain release")?;
 
     // Ensure permissions.
     #[cfg(target_family = "unix")]
@@ -701,35 +1301,129 @@ fn download_toolchain(
             if entry.file_type()?.is_file() {
                 let mut perms = entry.metadata()?.permissions();
                 perms.set_mode(0o755);
-                std::fs::set_permissions(entry.path(), perms)?;
+                std::fs::set_permissions(entry.path(), perms).with_context(|| {
+                    format!(
+                        "Could not make {} executable, set WASIX_TOOLCHAINS_ROOT to a directory \
+                         on a filesystem that allows executing files",
+                        entry.path().display()
+                    )
+                })?;
             }
         }
     }
 
-    eprintln!("Downloaded toolchain {} to {}", target, rust_dir.display());
+    if toolchain_dir.is_dir() {
+        config.info(&format!(
+            "Toolchain path {} already exists - deleting existing files!",
+            toolchain_dir.display()
+        ));
+        std::fs::remove_dir_all(&toolchain_dir)?;
+    }
+    std::fs::rename(&staging_dir, &toolchain_dir).with_context(|| {
+        format!(
+            "Could not move {} to {}",
+            staging_dir.display(),
+            toolchain_dir.display()
+        )
+    })?;
+    // The archives aren't needed anymore.
+    std::fs::remove_dir_all(&download_dir).ok();
+
+    config.info(&format!(
+        "Downloaded toolchain {} to {}",
+        target,
+        toolchain_dir.join("rust").display()
+    ));
 
     Ok(toolchain_dir)
 }
 
+/// Downloads `asset` into `dir` and returns the path of the downloaded file.
+///
+/// The data is written to a `.part` file that is renamed once the download
+/// completed. A previous partial download is resumed, and a complete one is
+/// reused as is.
+fn download_asset(
+    config: &Config,
+    client: &reqwest::blocking::Client,
+    asset: &GithubAsset,
+    dir: &Path,
+) -> Result<PathBuf, anyhow::Error> {
+    let path = dir.join(&asset.name);
+    if path.is_file() {
+        config.info(&format!("Using previously downloaded {}", path.display()));
+        return Ok(path);
+    }
+
+    let part_path = dir.join(format!("{}.part", asset.name));
+    let offset = part_path.metadata().map(|meta| meta.len()).unwrap_or(0);
+    config.info(&format!(
+        "Downloading {} from url '{}'...",
+        asset.name, asset.browser_download_url
+    ));
+    let mut request = client.get(&asset.browser_download_url);
+    if offset > 0 {
+        config.info(&format!(
+            "Resuming previous download after {offset} bytes..."
+        ));
+        request = request.header(RANGE, format!("bytes={offset}-"));
+    }
+    let res = request.send()?;
+    if offset > 0 && res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // The partial file is unusable, start over.
+        std::fs::remove_file(&part_path)?;
+        return download_asset(config, client, asset, dir);
+    }
+    let mut res = res.error_for_status()?;
+
+    let mut file = if res.status() == StatusCode::PARTIAL_CONTENT {
+        std::fs::OpenOptions::new().append(true).open(&part_path)?
+    } else {
+        // The server sent the whole file.
+        std::fs::File::create(&part_path)?
+    };
+    std::io::copy(&mut res, &mut file).with_context(|| {
+        format!(
+            "Could not download {}, run the command again to resume the download",
+            asset.name
+        )
+    })?;
+    drop(file);
+
+    let len = part_path.metadata()?.len();
+    if asset.size.is_some_and(|size| size != len) {
+        bail!(
+            "Download of {} is incomplete ({len} of {} bytes), run the command again to resume \
+             the download",
+            asset.name,
+            asset.size.unwrap_or_default(),
+        );
+    }
+    std::fs::rename(&part_path, &path)?;
+    Ok(path)
+}
+
 /// Tries to download a pre-built toolchain if possible, and builds the
 /// toolchain locally otherwise.
 ///
 /// Returns the path to the toolchain.
 pub fn install_prebuilt_toolchain(
+    config: &Config,
     toolchain_dir: &Path,
     toolchain_spec: ToolchainSpec,
 ) -> Result<RustupToolchain, anyhow::Error> {
+    ensure_writable(toolchain_dir)?;
     if let Some(target) = guess_host_target() {
-        match download_toolchain(target, toolchain_dir, toolchain_spec) {
-            Ok(path) => RustupToolchain::link(RUSTUP_TOOLCHAIN_NAME, &path.join("rust")),
+        match download_toolchain(config, target, toolchain_dir, toolchain_spec) {
+            Ok(path) => RustupToolchain::link(config, &toolchain_name(), &path.join("rust")),
             Err(err) => {
-                eprintln!("Could not download pre-built toolchain: {err:?}");
+                config.warn(&format!("Could not download pre-built toolchain: {err:?}"));
 
                 let root_cause = err.root_cause();
                 let root_description = format!("{root_cause:?}");
                 if root_description.contains("HTTP") && root_description.contains("api.github.com")
                 {
-                    eprintln!("\nHint: You can pass in a Github token via the GITHUB_TOKEN environment variable to avoid rate limits");
+                    config.info("Hint: You can pass in a Github token via the GITHUB_TOKEN environment variable, or a file containing it via GITHUB_TOKEN_FILE, to avoid rate limits");
                 }
 
                 Err(err.context("Download of pre-built toolchain failed"))
@@ -742,6 +1436,45 @@ pub fn install_prebuilt_toolchain(
     }
 }
 
+/// Makes sure that toolchains can be installed into `dir`, before anything is
+/// downloaded.
+fn ensure_writable(dir: &Path) -> Result<(), anyhow::Error> {
+    let probe = dir.join(".cargo-wasix-write-test");
+    std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::write(&probe, b""))
+        .and_then(|()| std::fs::remove_file(&probe))
+        .with_context(|| {
+            let hint = if std::env::var_os("WASIX_TOOLCHAINS_ROOT").is_some_and(|d| !d.is_empty()) {
+                " (set by $WASIX_TOOLCHAINS_ROOT)"
+            } else {
+                ", set $WASIX_TOOLCHAINS_ROOT to install them into another directory"
+            };
+            format!("cannot install toolchains into {}{hint}", dir.display())
+        })
+}
+
+/// Prints what [`install_prebuilt_toolchain`] would download, without
+/// downloading anything.
+pub fn print_download_plan(toolchain_dir: &Path, toolchain_spec: &ToolchainSpec) {
+    match guess_host_target() {
+        Some(target) => {
+            eprintln!(
+                "Dry run: would download the {toolchain_spec} release ({}) for host {target} into {}",
+                release_url(toolchain_spec),
+                toolchain_dir.display(),
+            );
+            eprintln!(
+                "  and link it with `rustup toolchain link {}`",
+                toolchain_name()
+            );
+        }
+        None => eprintln!(
+            "Dry run: no pre-built toolchain is available for this platform, \
+             it would have to be built with 'cargo wasix build-toolchain'"
+        ),
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct RustupToolchain {
     pub name: String,
@@ -752,23 +1485,13 @@ impl RustupToolchain {
     /// Verify if the "wasix" toolchain is present in rustup.
     ///
     /// Returns the path to the toolchain.
-    fn find_by_name(name: &str) -> Result<Option<Self>, anyhow::Error> {
-        let name_prefix = format!("{name}\t");
+    pub fn find_by_name(name: &str) -> Result<Option<Self>, anyhow::Error> {
         let out = Command::new("rustup")
             .args(["toolchain", "list", "--verbose"])
             .capture_stdout()?;
         let path_raw = out
             .lines()
-            .find(|line| line.trim().starts_with(&name_prefix))
-            .and_then(|line| line.strip_prefix(name))
-            .map(|line| {
-                let default_toolchain = "(default)";
-
-                let line = line.trim();
-                line.strip_prefix(default_toolchain)
-                    .map(|line| line.trim())
-                    .unwrap_or(line)
-            });
+            .find_map(|line| parse_toolchain_line(line, name));
 
         if let Some(path) = path_raw {
             Ok(Some(Self {
@@ -781,12 +1504,12 @@ impl RustupToolchain {
     }
 
     /// Link the "wasix" toolchain to a local directory via rustup.
-    fn link(name: &str, dir: &Path) -> Result<Self, anyhow::Error> {
-        eprintln!(
+    fn link(config: &Config, name: &str, dir: &Path) -> Result<Self, anyhow::Error> {
+        config.info(&format!(
             "Activating rustup toolchain {} at {}...",
             name,
             dir.display()
-        );
+        ));
 
         // Small sanity check.
         #[cfg(not(target_os = "windows"))]
@@ -811,13 +1534,13 @@ impl RustupToolchain {
                 .context("Could not remove wasix toolchain")?;
         }
 
-        Command::new("rustup")
-            .args(["toolchain", "link", name])
-            .arg(dir)
+        Self::link_command(name, dir)
             .run_verbose()
             .context("Could not link toolchain: rustup not installed?")?;
 
-        eprintln!("rustup toolchain {name} was linked and is now available!");
+        config.info(&format!(
+            "rustup toolchain {name} was linked and is now available!"
+        ));
 
         Ok(Self {
             name: name.to_string(),
@@ -825,6 +1548,38 @@ impl RustupToolchain {
         })
     }
 
+    /// Returns the command that links `dir` as the rustup toolchain `name`.
+    fn link_command(name: &str, dir: &Path) -> Command {
+        let mut cmd = Command::new("rustup");
+        cmd.args(["toolchain", "link", name]).arg(dir);
+        cmd
+    }
+
+    /// Path of the `cargo` binary of this toolchain.
+    pub fn cargo_path(&self) -> PathBuf {
+        let mut path = self.path.join("bin").join("cargo");
+        path.set_extension(std::env::consts::EXE_EXTENSION);
+        path
+    }
+
+    /// Error for a toolchain that doesn't contain a `cargo` binary.
+    pub fn missing_cargo_error(&self) -> anyhow::Error {
+        anyhow::anyhow!(
+            "the `{}` toolchain at {} doesn't contain `cargo` ({} is missing), \
+             reinstall it with `cargo wasix self update-toolchain`",
+            self.name,
+            self.path.display(),
+            self.cargo_path().display(),
+        )
+    }
+
+    /// Release tag of a downloaded toolchain, `None` for toolchains that were
+    /// built locally or downloaded by older versions of `cargo wasix`.
+    pub fn release_tag(&self) -> Option<String> {
+        let tag = std::fs::read_to_string(self.path.join(RELEASE_TAG_FILE)).ok()?;
+        Some(tag.trim().to_string())
+    }
+
     pub fn sysroot_dir(&self, is64bit: bool) -> Option<PathBuf> {
         let size = if is64bit { 64 } else { 32 };
         let path = self.path.parent()?.join(format!("sysroot{size}"));
@@ -836,6 +1591,24 @@ impl RustupToolchain {
     }
 }
 
+/// Returns the path of toolchain `name` if `line` of the output of `rustup
+/// toolchain list --verbose` describes it.
+///
+/// Depending on the rustup version the name and path are separated by a tab
+/// or spaces, with an optional annotation like `(default)` in between.
+fn parse_toolchain_line<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let rest = line.trim().strip_prefix(name)?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let path = match rest.strip_prefix('(') {
+        Some(annotated) => annotated.split_once(')')?.1.trim(),
+        None => rest.trim_end(),
+    };
+    (!path.is_empty()).then_some(path)
+}
+
 /// Makes sure that the wasix toolchain is available.
 ///
 /// Tries to download a pre-built toolchain if possible, and builds the toolchain
@@ -847,10 +1620,33 @@ impl RustupToolchain {
 pub fn ensure_toolchain(config: &Config, is64bit: bool) -> Result<RustupToolchain, anyhow::Error> {
     let _lock = Config::acquire_lock()?;
 
-    let toolchain = if let Some(chain) = RustupToolchain::find_by_name(RUSTUP_TOOLCHAIN_NAME)? {
+    if config.relink_toolchain {
+        if config.frozen_toolchain {
+            bail!("$WASIX_RELINK_TOOLCHAIN can't be combined with `--frozen-toolchain`");
+        }
+        let toolchain = relink_local_toolchain(config, is64bit)?;
+        check_toolchain(&toolchain, is64bit)?;
+        return Ok(toolchain);
+    }
+
+    if config.frozen_toolchain {
+        let toolchain = find_frozen_toolchain()?;
+        check_toolchain(&toolchain, is64bit)?;
+        return Ok(toolchain);
+    }
+
+    // A linked toolchain is always used as is, even if it turns out to be
+    // broken, so that a failed detection never silently re-downloads it.
+    let toolchain = if let Some(chain) = RustupToolchain::find_by_name(&toolchain_name())? {
+        config.verbose(|| {
+            config.status(
+                "Using",
+                &format!("existing wasix toolchain at {}", chain.path.display()),
+            )
+        });
         chain
     } else if !config.is_offline {
-        install_prebuilt_toolchain(&Config::toolchain_dir()?, ToolchainSpec::Latest)?
+        install_prebuilt_toolchain(config, &Config::toolchain_dir()?, ToolchainSpec::from_env())?
     } else {
         bail!(
             r#"
@@ -860,7 +1656,126 @@ WARNING: building takes a long time!"#
         );
     };
 
-    // Sanity check the toolchain.
+    check_toolchain(&toolchain, is64bit)?;
+    Ok(toolchain)
+}
+
+/// Returns the directory of a local toolchain build from `WASIX_TOOLCHAIN_DIR`.
+fn local_toolchain_dir() -> Result<PathBuf, anyhow::Error> {
+    let dir = std::env::var_os("WASIX_TOOLCHAIN_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .context(
+            "$WASIX_RELINK_TOOLCHAIN requires $WASIX_TOOLCHAIN_DIR to be set to the toolchain \
+             to link, such as `build/<host>/stage2` in a checkout of the wasix rust repository",
+        )?;
+    dir.canonicalize().with_context(|| {
+        format!(
+            "$WASIX_TOOLCHAIN_DIR is set to {}, which doesn't exist",
+            dir.display()
+        )
+    })
+}
+
+/// Links the local toolchain build in `WASIX_TOOLCHAIN_DIR`, even if a
+/// toolchain is linked already, so changes to it are always picked up.
+fn relink_local_toolchain(
+    config: &Config,
+    is64bit: bool,
+) -> Result<RustupToolchain, anyhow::Error> {
+    let dir = local_toolchain_dir()?;
+    // Checked before linking, so that an incomplete build doesn't replace the
+    // toolchain that is linked now.
+    let toolchain = RustupToolchain {
+        name: toolchain_name(),
+        path: dir.clone(),
+    };
+    let target = if is64bit {
+        "wasm64-wasmer-wasi"
+    } else {
+        "wasm32-wasmer-wasi"
+    };
+    let mut rustc = dir.join("bin").join("rustc");
+    rustc.set_extension(std::env::consts::EXE_EXTENSION);
+    let missing = [
+        rustc,
+        toolchain.cargo_path(),
+        dir.join("lib").join("rustlib").join(target),
+    ]
+    .into_iter()
+    .filter(|path| !path.exists())
+    .map(|path| path.display().to_string())
+    .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        bail!(
+            "the toolchain in $WASIX_TOOLCHAIN_DIR ({}) is incomplete, so it was not linked: \
+             {} missing; finish the build (`rustc`, `cargo` and the {target} target are \
+             needed) or copy the missing parts there",
+            dir.display(),
+            missing.join(", "),
+        );
+    }
+    RustupToolchain::link(config, &toolchain.name, &dir)
+}
+
+/// Returns the linked toolchain if it is the release in
+/// `WASIX_TOOLCHAIN_VERSION`, never downloading anything.
+fn find_frozen_toolchain() -> Result<RustupToolchain, anyhow::Error> {
+    let expected = std::env::var("WASIX_TOOLCHAIN_VERSION")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .context(
+            "`--frozen-toolchain` requires $WASIX_TOOLCHAIN_VERSION to be set to the \
+             expected toolchain release",
+        )?;
+    let expected = expected.trim();
+
+    let Some(toolchain) = RustupToolchain::find_by_name(&toolchain_name())? else {
+        bail!(
+            "no wasix toolchain is linked, expected release {expected}; `--frozen-toolchain` \
+             doesn't allow downloading it, install it with \
+             `cargo wasix self update-toolchain {expected}`"
+        );
+    };
+    let actual = toolchain.release_tag();
+    if actual.as_deref() != Some(expected) {
+        bail!(
+            "the wasix toolchain at {} is release {}, but {expected} is expected by \
+             $WASIX_TOOLCHAIN_VERSION; install it with `cargo wasix self update-toolchain {expected}`",
+            toolchain.path.display(),
+            actual.as_deref().unwrap_or("unknown"),
+        );
+    }
+    Ok(toolchain)
+}
+
+/// Dry run counterpart of [`ensure_toolchain`]: reports whether the toolchain
+/// would be downloaded, and from which release.
+pub fn print_ensure_plan(config: &Config) -> Result<(), anyhow::Error> {
+    if config.relink_toolchain {
+        eprintln!(
+            "Dry run: the wasix toolchain would be linked to {}",
+            local_toolchain_dir()?.display()
+        );
+    } else if let Some(chain) = RustupToolchain::find_by_name(&toolchain_name())? {
+        eprintln!(
+            "Dry run: the wasix toolchain is already linked at {}, nothing would be downloaded",
+            chain.path.display()
+        );
+    } else if config.is_offline {
+        eprintln!(
+            "Dry run: no wasix toolchain is linked, and none would be downloaded \
+             because CARGO_WASIX_OFFLINE is set"
+        );
+    } else {
+        print_download_plan(&Config::toolchain_dir()?, &ToolchainSpec::from_env());
+    }
+    Ok(())
+}
+
+/// Sanity checks that `toolchain` is usable for building the 32-bit or 64-bit
+/// wasix target.
+pub fn check_toolchain(toolchain: &RustupToolchain, is64bit: bool) -> Result<(), anyhow::Error> {
     #[cfg(not(target_os = "windows"))]
     let rust_cmd = "rustc";
     #[cfg(target_os = "windows")]
@@ -875,12 +1790,33 @@ WARNING: building takes a long time!"#
         .context("Could not execute rustc")?;
     assert_eq!(toolchain.path, rust_sysroot);
 
+    // Without `cargo` every build fails with a rather cryptic rustup error.
+    if !toolchain.cargo_path().exists() {
+        return Err(toolchain.missing_cargo_error());
+    }
+
     let lib_name = if is64bit {
         "lib/rustlib/wasm64-wasmer-wasi"
     } else {
         "lib/rustlib/wasm32-wasmer-wasi"
     };
     let lib_dir = rust_sysroot.join(lib_name);
+    // Some releases only ship the 32-bit target.
+    if is64bit && !lib_dir.exists() && rust_sysroot.join("lib/rustlib/wasm32-wasmer-wasi").exists()
+    {
+        bail!(
+            "the `{}` toolchain at {} (release {}) doesn't support the 64-bit \
+             wasm64-wasmer-wasi target, only wasm32-wasmer-wasi ({} does not exist); \
+             use the 32-bit commands such as `cargo wasix build` instead, or build a \
+             toolchain with both targets with `cargo wasix build-toolchain`. If this is \
+             a downloaded release, please report the incomplete release at \
+             https://github.com/wasix-org/cargo-wasix/issues",
+            toolchain.name,
+            toolchain.path.display(),
+            toolchain.release_tag().as_deref().unwrap_or("unknown"),
+            lib_dir.display(),
+        );
+    }
     if !lib_dir.exists() {
         bail!(
             "Invalid wasix rustup toolchain {} at {}: {} does not exist",
@@ -889,21 +1825,274 @@ WARNING: building takes a long time!"#
             lib_dir.display()
         );
     }
-    Ok(toolchain)
+
+    // The libraries alone don't mean that `rustc` was built with the target,
+    // which would otherwise only fail once Cargo compiles the first crate.
+    let target = crate::target_triple(is64bit);
+    let targets = Command::new(rust_cmd)
+        .arg(format!("+{}", toolchain.name))
+        .arg("--print")
+        .arg("target-list")
+        .capture_stdout()
+        .context("Could not execute rustc")?;
+    if !targets.lines().any(|t| t.trim() == target) {
+        bail!(
+            "the `{}` toolchain at {} (release {}) has the libraries for {target}, but \
+             its `rustc` doesn't support that target (it's missing from `rustc +{} \
+             --print target-list`); reinstall the toolchain with `cargo wasix \
+             download-toolchain` or build one with `cargo wasix build-toolchain`",
+            toolchain.name,
+            toolchain.path.display(),
+            toolchain.release_tag().as_deref().unwrap_or("unknown"),
+            toolchain.name,
+        );
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_rate_limited() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        let exhausted = headers(&[
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "1000600"),
+        ]);
+        let limited = RateLimited::from_response(StatusCode::FORBIDDEN, &exhausted, now).unwrap();
+        assert_eq!(
+            limited,
+            RateLimited {
+                retry_after: None,
+                reset_in: Some(Duration::from_secs(600)),
+            }
+        );
+        assert!(limited.message().contains("it resets in 10 minutes"));
+        assert!(limited.message().contains("GITHUB_TOKEN"));
+
+        let retry = headers(&[("retry-after", "30")]);
+        let limited =
+            RateLimited::from_response(StatusCode::TOO_MANY_REQUESTS, &retry, now).unwrap();
+        assert_eq!(limited.retry_after, Some(Duration::from_secs(30)));
+        assert!(limited.message().contains("it resets in 1 minute;"));
+
+        // Other errors, such as a forbidden repository, are not rate limits.
+        let remaining = headers(&[("x-ratelimit-remaining", "42")]);
+        assert_eq!(
+            RateLimited::from_response(StatusCode::FORBIDDEN, &remaining, now),
+            None
+        );
+        assert_eq!(
+            RateLimited::from_response(StatusCode::NOT_FOUND, &exhausted, now),
+            None
+        );
+    }
+
+    #[test]
+    fn test_github_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("token");
+        std::fs::write(&file, "  from-file\n").unwrap();
+
+        assert_eq!(github_token(None, None).unwrap(), None);
+        assert_eq!(
+            github_token(Some(" from-env ".into()), Some(file.clone())).unwrap(),
+            Some("from-env".to_string())
+        );
+        // An empty variable doesn't hide the file.
+        assert_eq!(
+            github_token(Some("".into()), Some(file.clone())).unwrap(),
+            Some("from-file".to_string())
+        );
+        std::fs::write(&file, "\n").unwrap();
+        assert_eq!(github_token(None, Some(file)).unwrap(), None);
+
+        let missing = dir.path().join("missing");
+        let err = github_token(None, Some(missing)).unwrap_err();
+        assert!(err.to_string().contains("$GITHUB_TOKEN_FILE"), "{err}");
+    }
+
+    #[test]
+    fn test_parse_components() {
+        assert_eq!(parse_components(""), Some((true, true)));
+        assert_eq!(parse_components("all"), Some((true, true)));
+        assert_eq!(parse_components("libc"), Some((true, false)));
+        assert_eq!(parse_components("rust"), Some((false, true)));
+        assert_eq!(parse_components("llvm"), None);
+    }
+
+    #[test]
+    fn test_missing_apt_packages() {
+        let all = ["curl", "xz", "cc", "c++", "make", "git", "python3"];
+        assert!(missing_apt_packages(|binary| all.contains(&binary)).is_empty());
+        // `build-essential` is needed if any of its binaries is missing.
+        assert_eq!(
+            missing_apt_packages(|binary| binary != "c++" && binary != "python3"),
+            ["build-essential", "python3"]
+        );
+        assert_eq!(
+            missing_apt_packages(|_| false),
+            ["curl", "xz-utils", "build-essential", "git", "python3"]
+        );
+    }
+
+    #[test]
+    fn test_git_failure_hint() {
+        let path = Path::new("/build/rust");
+        let hint = |stderr| git_failure_hint(stderr, path);
+        assert!(
+            hint("fatal: Authentication failed for 'https://github.com/wasix-org/rust.git/'")
+                .unwrap()
+                .contains("could not authenticate")
+        );
+        assert!(
+            hint("fatal: unable to access '...': Could not resolve host: github.com")
+                .unwrap()
+                .contains("check your network")
+        );
+        assert!(
+            hint("error: RPC failed; curl 18 transfer closed\nfatal: early EOF")
+                .unwrap()
+                .contains("continue where it stopped")
+        );
+        assert!(hint(
+            "error: Your local changes to the following files would be overwritten by checkout"
+        )
+        .unwrap()
+        .contains("/build/rust has local changes"));
+        assert!(hint("fatal: error processing shallow info: 4")
+            .unwrap()
+            .contains("git fetch --unshallow"));
+        assert_eq!(hint("fatal: couldn't find remote ref wasix-nope"), None);
+    }
+
+    #[test]
+    fn test_parse_toolchain_line() {
+        let path = Some("/home/user/.wasix/rust");
+        assert_eq!(
+            parse_toolchain_line("wasix\t/home/user/.wasix/rust", "wasix"),
+            path
+        );
+        assert_eq!(
+            parse_toolchain_line("wasix /home/user/.wasix/rust", "wasix"),
+            path
+        );
+        assert_eq!(
+            parse_toolchain_line("wasix (default)\t/home/user/.wasix/rust", "wasix"),
+            path
+        );
+        assert_eq!(
+            parse_toolchain_line("wasix (active, default) /home/user/.wasix/rust", "wasix"),
+            path
+        );
+        assert_eq!(
+            parse_toolchain_line("wasix-internal /home/user/.wasix/rust", "wasix"),
+            None
+        );
+        assert_eq!(
+            parse_toolchain_line("stable-x86_64-unknown-linux-gnu /rust", "wasix"),
+            None
+        );
+    }
+
+    /// Serves `body` once, honoring a `Range: bytes=<start>-` header, and
+    /// returns the URL and a handle resolving to the received request.
+    fn serve_once(body: &'static [u8]) -> (String, std::thread::JoinHandle<String>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/asset.tar.gz", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                request.push_str(&line);
+            }
+            let start = request
+                .lines()
+                .find_map(|line| {
+                    line.to_lowercase()
+                        .strip_prefix("range: bytes=")
+                        .map(String::from)
+                })
+                .map(|range| range.trim_end_matches('-').parse::<usize>().unwrap());
+            let (status, body) = match start {
+                Some(start) => ("206 Partial Content", &body[start..]),
+                None => ("200 OK", body),
+            };
+            let mut stream = stream;
+            write!(
+                stream,
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(body).unwrap();
+            request
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_download_asset_resumes() {
+        let body = b"0123456789";
+        let (url, server) = serve_once(body);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("asset.tar.gz.part"), &body[..4]).unwrap();
+
+        let asset = GithubAsset {
+            browser_download_url: url,
+            name: "asset.tar.gz".to_string(),
+            size: Some(body.len() as u64),
+        };
+        let client = reqwest::blocking::Client::new();
+        let config = Config::new();
+        let path = download_asset(&config, &client, &asset, dir.path()).unwrap();
+
+        assert!(server
+            .join()
+            .unwrap()
+            .to_lowercase()
+            .contains("range: bytes=4-"));
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert!(!dir.path().join("asset.tar.gz.part").exists());
+
+        // A complete download is reused without a request.
+        assert_eq!(
+            download_asset(&config, &client, &asset, dir.path()).unwrap(),
+            path
+        );
+    }
+
     #[test]
     fn test_download_toolchain() {
         let tmp_dir = std::env::temp_dir().join("cargo-wasix").join("download");
         if tmp_dir.is_dir() {
             std::fs::remove_dir_all(&tmp_dir).unwrap_or_default();
         }
-        let root = download_toolchain("x86_64-unknown-linux-gnu", &tmp_dir, ToolchainSpec::Latest)
-            .unwrap();
+        let root = download_toolchain(
+            &Config::new(),
+            "x86_64-unknown-linux-gnu",
+            &tmp_dir,
+            ToolchainSpec::Latest,
+        )
+        .unwrap();
         let dir = root.join("rust");
 
         #[cfg(not(target_os = "windows"))]
@@ -6,19 +6,108 @@ use reqwest::header::USER_AGENT;
 use reqwest::Proxy;
 use std::fs;
 use std::fs::{File, OpenOptions};
+use std::io;
 use std::path::Path;
 use std::process::{Command, ExitStatus, Output, Stdio};
+use std::rc::Rc;
 use std::time::Duration;
 use std::{env, fmt};
 
 /// Make sure a binary exists and runs with the given arguments.
 pub fn ensure_binary(command: &str, args: &[&str]) -> Result<(), anyhow::Error> {
-    Command::new(command)
-        .args(args)
-        .stdout(std::process::Stdio::piped())
-        .run_verbose()
-        .with_context(|| format!("Could not find or execute binary: {command}"))?;
-    Ok(())
+    let probe = std::iter::once(command)
+        .chain(args.iter().copied())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut cmd = Command::new(command);
+    cmd.args(args).stdin(Stdio::null()).stdout(Stdio::null());
+    match cmd.output() {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            bail!("Could not find binary `{command}` in $PATH, is it installed?")
+        }
+        Err(err) => {
+            Err(err).with_context(|| format!("Could not execute binary `{command}` (`{probe}`)"))
+        }
+        Ok(output) => check_success(&cmd, &output.status, &[], &output.stderr)
+            .with_context(|| format!("Binary `{command}` was found, but `{probe}` failed")),
+    }
+}
+
+/// Flags passed to `cargo wasix` that the `cargo` commands it runs internally
+/// (e.g. `cargo metadata`) have to respect as well.
+#[derive(Clone, Copy, Default)]
+pub struct CargoFlags<'a> {
+    /// `--locked` or `--frozen`, if either was passed.
+    pub lock: Option<&'a str>,
+    /// Whether the network must not be used.
+    pub offline: bool,
+    /// The `--manifest-path`, if one was passed.
+    pub manifest_path: Option<&'a Path>,
+}
+
+impl CargoFlags<'_> {
+    /// Passes the `--manifest-path` on to `cmd`.
+    pub fn apply_manifest_path(&self, cmd: &mut Command) {
+        if let Some(path) = self.manifest_path {
+            cmd.arg("--manifest-path").arg(path);
+        }
+    }
+
+    /// Passes all flags on to `cmd`.
+    pub fn apply(&self, cmd: &mut Command) {
+        if let Some(flag) = self.lock {
+            cmd.arg(flag);
+        }
+        if self.offline {
+            cmd.arg("--offline");
+        }
+        self.apply_manifest_path(cmd);
+    }
+}
+
+/// Returns `cargo metadata` for the workspace members only, which everything
+/// that needs the workspace root or target directory shares.
+pub fn workspace_metadata(config: &Config, flags: CargoFlags) -> Result<Rc<str>> {
+    let mut cmd = Command::new("cargo");
+    cmd.arg("metadata")
+        .arg("--no-deps")
+        .arg("--format-version=1");
+    flags.apply_manifest_path(&mut cmd);
+    config.cargo_metadata(&mut cmd)
+}
+
+/// Environment variables `cargo wasix` sets for itself and every process it
+/// spawns.
+const INJECTED_ENV: &[&str] = &[
+    "RUSTUP_TOOLCHAIN",
+    "RUSTFLAGS",
+    "WASI_SDK_DIR",
+    "WASIX_SYSROOT",
+];
+
+/// Returns `KEY=VALUE` lines for the environment variables injected by
+/// `cargo wasix` that `cmd` will see, for debugging with `-vv`.
+///
+/// Only the value of `GITHUB_TOKEN` is redacted.
+pub fn env_overrides(cmd: &Command) -> Vec<String> {
+    let injected = INJECTED_ENV
+        .iter()
+        .filter(|key| !cmd.get_envs().any(|(k, _)| k == **key))
+        .filter_map(|key| Some((key.to_string(), env::var_os(key)?)));
+    let overrides = cmd.get_envs().map(|(key, value)| {
+        let value = value.map(|v| v.to_os_string()).unwrap_or_default();
+        (key.to_string_lossy().into_owned(), value)
+    });
+    injected
+        .chain(overrides)
+        .map(|(key, value)| {
+            if key == "GITHUB_TOKEN" {
+                format!("{key}=<redacted>")
+            } else {
+                format!("{key}={}", value.to_string_lossy())
+            }
+        })
+        .collect()
 }
 
 pub trait CommandExt {
@@ -104,11 +193,12 @@ pub fn flock(path: &Path) -> Result<FileLock> {
         .context(format!("failed to create directory `{}`", parent.display()))?;
     let file = OpenOptions::new()
         .create(true)
+        .truncate(false)
         .read(true)
         .write(true)
         .open(path)?;
     file.lock_exclusive()?;
-    return Ok(FileLock(file));
+    Ok(FileLock(file))
 }
 
 /// If `Error` is a `ProcessError` and it looks like a "normal exit", then it
@@ -216,47 +306,77 @@ pub fn get(url: &str, timeout: Duration) -> Result<Response> {
     Ok(response)
 }
 
-/// Recursively copy one filesystem path to another.
+/// What [`copy_path`] does with destination files that already exist with
+/// different contents. Identical files are always skipped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExistingFiles {
+    /// Keep the destination file.
+    #[cfg(test)]
+    Keep,
+    /// Replace the destination file with the source.
+    Overwrite,
+    /// Fail the copy.
+    #[cfg(test)]
+    Error,
+}
+
+/// Recursively copy one filesystem path to another, merging directories
+/// with existing ones.
 ///
 // Hand-written to prevent an extra dependency.
-#[allow(dead_code)]
 pub fn copy_path(
     src: &Path,
     target: &Path,
-    ignore_existing: bool,
+    existing: ExistingFiles,
     verbose: bool,
 ) -> Result<(), anyhow::Error> {
+    #[cfg(unix)]
+    {
+        let meta = src.symlink_metadata().with_context(|| {
+            format!("Could not determine metadata for path '{}'", src.display())
+        })?;
+        if meta.is_symlink() {
+            return copy_symlink(src, target, existing, verbose);
+        }
+    }
+
+    // Creating symlinks on Windows requires extra privileges, so there they
+    // are followed and the contents of their target are copied instead.
     let meta = src
         .metadata()
         .with_context(|| format!("Could not determine metadata for path '{}'", src.display()))?;
+    let target_meta = target.symlink_metadata().ok();
     if meta.is_file() {
-        if target.is_file() {
-            if ignore_existing {
-                Ok(())
-            } else {
-                bail!(
-                    "Could not copy from '{}' to '{}': destination already exists",
-                    src.display(),
-                    target.display()
-                );
+        if let Some(target_meta) = target_meta {
+            if target_meta.is_file() && same_contents(src, target)? {
+                return Ok(());
             }
-        } else {
-            if let Some(parent) = target.parent() {
-                std::fs::create_dir_all(parent).with_context(|| {
-                    format!("Could not create directory '{}'", parent.display())
-                })?;
+            if !replace_existing(src, target, existing)? {
+                return Ok(());
             }
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Could not create directory '{}'", parent.display()))?;
+        }
 
-            let mut input = std::fs::File::open(src)?;
-            let mut output = std::fs::File::create(target)?;
-            std::io::copy(&mut input, &mut output)?;
+        let mut input = std::fs::File::open(src)?;
+        let mut output = std::fs::File::create(target)?;
+        std::io::copy(&mut input, &mut output)?;
 
-            if verbose {
-                eprintln!("Copied '{}' to '{}'", src.display(), target.display());
-            }
-            Ok(())
+        if verbose {
+            eprintln!("Copied '{}' to '{}'", src.display(), target.display());
         }
+        Ok(())
     } else if meta.is_dir() {
+        if target_meta.is_some_and(|meta| !meta.is_dir())
+            && !replace_existing(src, target, existing)?
+        {
+            return Ok(());
+        }
+        std::fs::create_dir_all(target)
+            .with_context(|| format!("Could not create directory '{}'", target.display()))?;
+
         let iter = std::fs::read_dir(src)
             .with_context(|| format!("Could not list directory '{}'", src.display()))?;
         for res in iter {
@@ -264,14 +384,12 @@ pub fn copy_path(
             copy_path(
                 &entry.path(),
                 &target.join(entry.file_name()),
-                ignore_existing,
+                existing,
                 verbose,
             )?;
         }
 
         Ok(())
-    } else if meta.is_symlink() {
-        todo!()
     } else {
         bail!(
             "Could not copy from '{}' to '{}': unknown file type",
@@ -280,3 +398,256 @@ pub fn copy_path(
         );
     }
 }
+
+/// Recreates the symlink `src` at `target`, pointing to the same path.
+#[cfg(unix)]
+fn copy_symlink(
+    src: &Path,
+    target: &Path,
+    existing: ExistingFiles,
+    verbose: bool,
+) -> Result<(), anyhow::Error> {
+    let link = std::fs::read_link(src)
+        .with_context(|| format!("Could not read symlink '{}'", src.display()))?;
+    if target.symlink_metadata().is_ok() {
+        if std::fs::read_link(target).is_ok_and(|target_link| target_link == link) {
+            return Ok(());
+        }
+        if !replace_existing(src, target, existing)? {
+            return Ok(());
+        }
+    }
+
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create directory '{}'", parent.display()))?;
+    }
+    std::os::unix::fs::symlink(&link, target)
+        .with_context(|| format!("Could not create symlink '{}'", target.display()))?;
+
+    if verbose {
+        eprintln!(
+            "Copied symlink '{}' to '{}' (-> '{}')",
+            src.display(),
+            target.display(),
+            link.display()
+        );
+    }
+    Ok(())
+}
+
+/// Handles an existing `target` that differs from `src`, returns whether
+/// `src` should still be copied.
+fn replace_existing(
+    src: &Path,
+    target: &Path,
+    existing: ExistingFiles,
+) -> Result<bool, anyhow::Error> {
+    match existing {
+        #[cfg(test)]
+        ExistingFiles::Keep => Ok(false),
+        #[cfg(test)]
+        ExistingFiles::Error => bail!(
+            "Could not copy from '{}' to '{}': destination already exists with different contents",
+            src.display(),
+            target.display()
+        ),
+        ExistingFiles::Overwrite => {
+            let is_dir = target.symlink_metadata().is_ok_and(|meta| meta.is_dir());
+            if is_dir {
+                std::fs::remove_dir_all(target)
+            } else {
+                std::fs::remove_file(target)
+            }
+            .with_context(|| {
+                format!(
+                    "Could not remove '{}' to replace it with '{}'",
+                    target.display(),
+                    src.display()
+                )
+            })?;
+            Ok(true)
+        }
+    }
+}
+
+/// Formats a number of bytes for humans, such as `1.5 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Returns whether the files `a` and `b` have the same contents.
+pub fn same_contents(a: &Path, b: &Path) -> Result<bool, anyhow::Error> {
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let read = |path: &Path| {
+        std::fs::read(path).with_context(|| format!("Could not read '{}'", path.display()))
+    };
+    Ok(read(a)? == read(b)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_copy_path_with_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join("lib")).unwrap();
+        std::fs::write(src.join("lib/libc.a"), "libc").unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("libc.a", src.join("lib/libc-link.a")).unwrap();
+            std::os::unix::fs::symlink("lib", src.join("lib-link")).unwrap();
+        }
+
+        let target = dir.path().join("target");
+        copy_path(&src, &target, ExistingFiles::Error, false).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(target.join("lib/libc.a")).unwrap(),
+            "libc"
+        );
+        #[cfg(unix)]
+        {
+            for (link, dest) in [("lib/libc-link.a", "libc.a"), ("lib-link", "lib")] {
+                let link = target.join(link);
+                assert!(link.symlink_metadata().unwrap().is_symlink());
+                assert_eq!(std::fs::read_link(&link).unwrap(), Path::new(dest));
+            }
+            assert_eq!(
+                std::fs::read_to_string(target.join("lib-link/libc-link.a")).unwrap(),
+                "libc"
+            );
+        }
+
+        // Identical files and symlinks are skipped.
+        copy_path(&src, &target, ExistingFiles::Error, false).unwrap();
+    }
+
+    #[test]
+    fn test_ensure_binary() {
+        ensure_binary("cargo", &["--version"]).unwrap();
+
+        let err = ensure_binary("cargo-wasix-missing-binary", &["--version"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not find binary `cargo-wasix-missing-binary` in $PATH, is it installed?"
+        );
+
+        let err = ensure_binary("cargo", &["wasix-missing-subcommand"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Binary `cargo` was found, but `cargo wasix-missing-subcommand` failed"
+        );
+        // The output of the failed command is kept.
+        assert!(format!("{err:#}").contains("no such command"));
+    }
+
+    #[test]
+    fn test_workspace_metadata_is_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("Cargo.toml");
+        let write_manifest = |name: &str| {
+            let toml = format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n");
+            std::fs::write(&manifest, toml).unwrap();
+        };
+        write_manifest("first");
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+
+        let config = Config::new();
+        let flags = CargoFlags {
+            manifest_path: Some(&manifest),
+            ..CargoFlags::default()
+        };
+        let first = workspace_metadata(&config, flags).unwrap();
+        assert!(first.contains("\"name\":\"first\""));
+
+        // The output is kept until `Cargo.lock` changes, not the manifest.
+        write_manifest("second");
+        assert_eq!(workspace_metadata(&config, flags).unwrap(), first);
+        config.forget_cargo_metadata();
+        let second = workspace_metadata(&config, flags).unwrap();
+        assert!(second.contains("\"name\":\"second\""));
+    }
+
+    /// Creates `src` and `target` directories that share `same.txt`, differ in
+    /// `changed.txt` and each have a file of their own.
+    fn merge_dirs() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let target = dir.path().join("target");
+        for (path, contents) in [
+            (src.join("same.txt"), "same"),
+            (src.join("changed.txt"), "new"),
+            (src.join("sub/src-only.txt"), "src"),
+            (target.join("same.txt"), "same"),
+            (target.join("changed.txt"), "old"),
+            (target.join("sub/target-only.txt"), "target"),
+        ] {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        (dir, src, target)
+    }
+
+    fn read(path: PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn test_copy_path_merge() {
+        let (_dir, src, target) = merge_dirs();
+        copy_path(&src, &target, ExistingFiles::Keep, false).unwrap();
+        assert_eq!(read(target.join("same.txt")), "same");
+        assert_eq!(read(target.join("changed.txt")), "old");
+        assert_eq!(read(target.join("sub/src-only.txt")), "src");
+        assert_eq!(read(target.join("sub/target-only.txt")), "target");
+    }
+
+    #[test]
+    fn test_copy_path_overwrite() {
+        let (_dir, src, target) = merge_dirs();
+        copy_path(&src, &target, ExistingFiles::Overwrite, false).unwrap();
+        assert_eq!(read(target.join("same.txt")), "same");
+        assert_eq!(read(target.join("changed.txt")), "new");
+        assert_eq!(read(target.join("sub/src-only.txt")), "src");
+        assert_eq!(read(target.join("sub/target-only.txt")), "target");
+    }
+
+    #[test]
+    fn test_copy_path_conflict() {
+        let (_dir, src, target) = merge_dirs();
+        let err = copy_path(&src, &target, ExistingFiles::Error, false).unwrap_err();
+        assert!(err.to_string().contains("changed.txt"), "{err}");
+        assert_eq!(read(target.join("changed.txt")), "old");
+
+        // Identical files don't conflict.
+        std::fs::write(target.join("changed.txt"), "new").unwrap();
+        copy_path(&src, &target, ExistingFiles::Error, false).unwrap();
+        assert_eq!(read(target.join("sub/src-only.txt")), "src");
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1536 * 1024), "1.5 MiB");
+        assert_eq!(format_size(3 << 30), "3.0 GiB");
+    }
+}
@@ -0,0 +1,182 @@
+//! Helpers for working with the raw WebAssembly binary format, for the few
+//! cases where `walrus` doesn't give us enough control.
+
+use anyhow::{bail, Context, Result};
+
+/// The magic number and version every wasm module starts with.
+const HEADER: &[u8] = b"\0asm\x01\0\0\0";
+
+/// Id of custom sections in the binary format.
+const CUSTOM_SECTION_ID: u8 = 0;
+
+/// Splits the custom sections for which `extract` returns `true` out of the
+/// module `bytes`.
+///
+/// Returns the module without those sections, and the extracted sections
+/// wrapped in a module of their own.
+pub fn split_custom_sections(
+    bytes: &[u8],
+    extract: impl Fn(&str) -> bool,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    if !bytes.starts_with(HEADER) {
+        bail!("not a WebAssembly module");
+    }
+    let mut kept = HEADER.to_vec();
+    let mut extracted = HEADER.to_vec();
+
+    let mut pos = HEADER.len();
+    while pos < bytes.len() {
+        let start = pos;
+        let id = bytes[pos];
+        pos += 1;
+        let size = read_u32(bytes, &mut pos)? as usize;
+        let end = pos
+            .checked_add(size)
+            .filter(|end| *end <= bytes.len())
+            .context("section extends past the end of the module")?;
+        let section = &bytes[start..end];
+
+        if id == CUSTOM_SECTION_ID {
+            let mut name_pos = pos;
+            let name_len = read_u32(bytes, &mut name_pos)? as usize;
+            let name = bytes
+                .get(name_pos..name_pos + name_len)
+                .and_then(|name| std::str::from_utf8(name).ok())
+                .context("invalid custom section name")?;
+            if extract(name) {
+                extracted.extend_from_slice(section);
+                pos = end;
+                continue;
+            }
+        }
+        kept.extend_from_slice(section);
+        pos = end;
+    }
+
+    Ok((kept, extracted))
+}
+
+/// Id of the code section in the binary format.
+const CODE_SECTION_ID: u8 = 10;
+
+/// Returns the size in bytes of the body of every function defined in the
+/// module `bytes`, in the order of the code section.
+pub fn code_body_sizes(bytes: &[u8]) -> Result<Vec<u64>> {
+    if !bytes.starts_with(HEADER) {
+        bail!("not a WebAssembly module");
+    }
+    let mut pos = HEADER.len();
+    while pos < bytes.len() {
+        let id = bytes[pos];
+        pos += 1;
+        let size = read_u32(bytes, &mut pos)? as usize;
+        let end = pos
+            .checked_add(size)
+            .filter(|end| *end <= bytes.len())
+            .context("section extends past the end of the module")?;
+        if id == CODE_SECTION_ID {
+            let count = read_u32(bytes, &mut pos)?;
+            let mut sizes = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let body_start = pos;
+                let body_size = read_u32(bytes, &mut pos)? as usize;
+                pos += body_size;
+                if pos > end {
+                    bail!("function body extends past the end of the code section");
+                }
+                sizes.push((pos - body_start) as u64);
+            }
+            return Ok(sizes);
+        }
+        pos = end;
+    }
+    Ok(Vec::new())
+}
+
+/// Encodes a custom section with the given `name` and `data`.
+pub fn custom_section(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_u32(&mut payload, name.len() as u32);
+    payload.extend_from_slice(name.as_bytes());
+    payload.extend_from_slice(data);
+
+    let mut section = vec![CUSTOM_SECTION_ID];
+    write_u32(&mut section, payload.len() as u32);
+    section.extend_from_slice(&payload);
+    section
+}
+
+/// Encodes a string as it is stored in the binary format, prefixed by its
+/// length.
+pub fn encode_str(s: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_u32(&mut out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+    out
+}
+
+/// Reads an unsigned LEB128 encoded `u32` at `pos`, advancing it.
+fn read_u32(bytes: &[u8], pos: &mut usize) -> Result<u32> {
+    let mut result = 0u32;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(*pos).context("unexpected end of module")?;
+        *pos += 1;
+        if shift == 28 && byte > 0x0f {
+            bail!("invalid LEB128 encoded integer");
+        }
+        result |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+    }
+}
+
+/// Writes `value` as an unsigned LEB128 encoded integer.
+fn write_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_custom_sections() {
+        let mut module = HEADER.to_vec();
+        module.extend(custom_section(".debug_info", &[1, 2, 3]));
+        module.extend(custom_section("name", &[4]));
+        module.extend(custom_section(".debug_line", &[0; 200]));
+
+        let (kept, extracted) =
+            split_custom_sections(&module, |name| name.starts_with(".debug_")).unwrap();
+
+        let mut expected_kept = HEADER.to_vec();
+        expected_kept.extend(custom_section("name", &[4]));
+        assert_eq!(kept, expected_kept);
+
+        let mut expected_extracted = HEADER.to_vec();
+        expected_extracted.extend(custom_section(".debug_info", &[1, 2, 3]));
+        expected_extracted.extend(custom_section(".debug_line", &[0; 200]));
+        assert_eq!(extracted, expected_extracted);
+    }
+
+    #[test]
+    fn test_split_custom_sections_invalid() {
+        assert!(split_custom_sections(b"not wasm", |_| true).is_err());
+
+        let mut truncated = HEADER.to_vec();
+        truncated.extend(custom_section("name", &[1, 2, 3]));
+        truncated.pop();
+        assert!(split_custom_sections(&truncated, |_| true).is_err());
+    }
+}
@@ -32,6 +32,35 @@ fn help() {
     cargo_wasix("help").assert().success();
 }
 
+#[test]
+fn subcommand_help() {
+    cargo_wasix("run --help")
+        .assert()
+        .stdout(predicate::str::contains("Usage: cargo wasix run [OPTIONS]"))
+        .stdout(predicate::str::contains("--no-atomics"))
+        .stdout(predicate::str::contains("--net"))
+        .stdout(predicate::str::contains("--launcher").not())
+        // Followed by the help of `cargo run`.
+        .stdout(predicate::str::contains("forwarded to `cargo run`"))
+        .stdout(predicate::str::contains("--release"))
+        .success();
+
+    cargo_wasix("install -h")
+        .assert()
+        .stdout(predicate::str::contains("--launcher"))
+        .stdout(predicate::str::contains("forwarded to `cargo build`"))
+        .success();
+
+    cargo_wasix("download-toolchain --help")
+        .assert()
+        .stdout(predicate::str::contains(
+            "Usage: cargo wasix download-toolchain [VERSION] [OPTIONS]",
+        ))
+        .stdout(predicate::str::contains("--dry-run"))
+        .stdout(predicate::str::contains("forwarded").not())
+        .success();
+}
+
 #[test]
 fn version() {
     cargo_wasix("-V")
@@ -76,6 +105,40 @@ fn strip_debuginfo() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn split_debuginfo() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                split-debuginfo = true
+
+                [profile.release]
+                debug = 2
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build --release").assert().success();
+    let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
+    let sections = custom_sections(&bytes)?;
+    assert!(!sections.iter().any(|s| s.starts_with(".debug_info")));
+    assert!(sections.contains(&"external_debug_info"));
+    assert!(sections.contains(&"name"));
+
+    let sidecar = p.release_wasm("foo").with_extension("debug.wasm");
+    let bytes = std::fs::read(sidecar).context("failed to read debug wasm")?;
+    let sections = custom_sections(&bytes)?;
+    assert!(sections.iter().any(|s| s.starts_with(".debug_info")));
+    Ok(())
+}
+
 #[test]
 fn check_works() {
     let p = support::project()
@@ -94,6 +157,31 @@ fn fix_works() {
     p.cargo_wasix("fix --allow-no-vcs").assert().success();
 }
 
+#[test]
+fn fix_edition() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+                edition = "2015"
+            "#,
+        )
+        .file("src/main.rs", "fn async() {}\nfn main() { async(); }\n")
+        .build();
+
+    p.cargo_wasix("fix --edition --allow-no-vcs")
+        .assert()
+        .stderr(predicate::str::contains("Post-processing").not())
+        .success();
+    let main = std::fs::read_to_string(p.root().join("src/main.rs"))?;
+    assert_eq!(main, "fn r#async() {}\nfn main() { r#async(); }\n");
+    assert!(!p.debug_wasm("foo").exists());
+    Ok(())
+}
+
 #[test]
 fn rust_names_demangled() -> Result<()> {
     let p = support::project()
@@ -110,6 +198,56 @@ fn rust_names_demangled() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn process_order_opt_then_demangle() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                process-order = "opt-then-demangle"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build").assert().success();
+    let bytes = std::fs::read(p.debug_wasm("foo")).context("failed to read wasm")?;
+    assert_demangled(&bytes)?;
+
+    // Also after `wasm-opt` optimized the module.
+    p.cargo_wasix("build --release").assert().success();
+    let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
+    assert_demangled(&bytes)?;
+
+    // Parsing the module again doesn't add `walrus` back to a minimal
+    // `producers` section.
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                process-order = "opt-then-demangle"
+                producers = "minimal"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build --release").assert().success();
+    let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
+    assert_eq!(producers(&bytes)?, MINIMAL_PRODUCERS);
+    Ok(())
+}
+
 fn assert_demangled(wasm: &[u8]) -> Result<()> {
     let mut saw_name = false;
     for payload in wasmparser::Parser::new(0).parse_all(wasm) {
@@ -144,6 +282,46 @@ fn assert_demangled(wasm: &[u8]) -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn library_postprocess_wasm() -> Result<()> {
+    // A module with a single function, named by its mangled symbol.
+    fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        let mut section = vec![id, contents.len() as u8];
+        section.extend_from_slice(contents);
+        section
+    }
+    let symbol = b"_ZN3foo3bar17h0123456789abcdefE";
+    let mut function_names = vec![1, 0, symbol.len() as u8];
+    function_names.extend_from_slice(symbol);
+    let mut names = b"\x04name".to_vec();
+    names.extend(section(1, &function_names));
+
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+    module.extend(section(1, &[1, 0x60, 0, 0]));
+    module.extend(section(3, &[1, 0]));
+    module.extend(section(10, &[1, 2, 0, 0x0b]));
+    module.extend(section(0, &names));
+
+    let p = support::project().build();
+    let path = p.root().join("foo.wasm");
+    std::fs::write(&path, &module)?;
+
+    // Without `wasm-opt`, which would have to be downloaded.
+    let mut options = cargo_wasix::PostprocessOptions::default();
+    options.wasm_opt = false;
+    options.quiet = true;
+    cargo_wasix::postprocess_wasm(&path, &options)?;
+
+    let bytes = std::fs::read(&path)?;
+    assert_demangled(&bytes)?;
+    // The temporary copy of the input is removed again.
+    let wasms = std::fs::read_dir(p.root())?
+        .filter(|e| e.as_ref().unwrap().path().extension() == Some("wasm".as_ref()))
+        .count();
+    assert_eq!(wasms, 1);
+    Ok(())
+}
+
 #[test]
 fn check_output() -> Result<()> {
     // download the wasix target and get that out of the way
@@ -189,6 +367,7 @@ $",
 .*Processing .*foo.rustc.wasm
 .*Optimizing with wasm-opt
 .*Running .*wasm-opt.*--asyncify.*--debuginfo.*
+.*Timing .*foo.wasm: demangle .*
 $",
         )?)
         .success();
@@ -222,6 +401,36 @@ $",
     Ok(())
 }
 
+#[test]
+fn timings() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build --timings")
+        .assert()
+        .stderr(is_match("Timing .*foo.wasm: demangle .*s, wasm-opt .*s")?)
+        .success();
+    assert!(p.build_dir().join("cargo-timings").is_dir());
+    Ok(())
+}
+
+#[test]
+fn future_incompat_report() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // The messages Cargo sends for the report don't get in the way of the
+    // post-processing.
+    p.cargo_wasix("build --future-incompat-report")
+        .assert()
+        .stderr(predicates::str::contains("failed to parse").not())
+        .success();
+    assert!(p.debug_wasm("foo").exists());
+    Ok(())
+}
+
 // FIXME: wasm-opt isn't running in release mode, so this test is disabled for now
 #[test]
 fn check_output_release() -> Result<()> {
@@ -268,6 +477,7 @@ $",
 .*Processing .*foo.rustc.wasm
 .*Optimizing with wasm-opt
 .*Running .*wasm-opt.*
+.*Timing .*foo.wasm: demangle .*
 $",
         )?)
         .success();
@@ -352,7 +562,7 @@ Caused by:
     .*
 $",
         )?)
-        .code(1);
+        .code(3);
 
     p.cargo_wasix("build")
         .env("WASM_BINDGEN", "my-wasm-bindgen")
@@ -370,7 +580,7 @@ Caused by:
     .*
 $",
         )?)
-        .code(1);
+        .code(3);
 
     p.cargo_wasix("build --release")
         .env("WASM_BINDGEN", "my-wasm-bindgen")
@@ -390,7 +600,7 @@ Caused by:
     .*
 $",
         )?)
-        .code(1);
+        .code(3);
 
     Ok(())
 }
@@ -559,348 +769,3521 @@ $",
 }
 
 #[test]
-fn run_forward_args() -> Result<()> {
+fn run64_override_runtime() -> Result<()> {
+    // the 64-bit runner variable is the one that's checked
+    support::project()
+        .file("src/main.rs", "fn main() {}")
+        .override_runtime(
+            "command-and-path-that-is-unlikely-to-exist-eac9cb6c-fa25-4487-b07f-38116cc6dade",
+        )
+        .build()
+        .cargo_wasix("run64")
+        .assert()
+        .stdout("")
+        .stderr(is_match("CARGO_TARGET_WASM64_WASMER_WASI_RUNNER")?)
+        .stderr(is_match("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER")?.not())
+        .failure();
+
+    // ... and a 32-bit runner isn't used for 64-bit builds
+    support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build()
+        .cargo_wasix("run64")
+        .env(
+            "CARGO_TARGET_WASM32_WASMER_WASI_RUNNER",
+            "command-and-path-that-is-unlikely-to-exist-eac9cb6c-fa25-4487-b07f-38116cc6dade",
+        )
+        .assert()
+        .stderr(is_match(
+            "CARGO_TARGET_WASM32_WASMER_WASI_RUNNER is set but ignored when building for `wasm64-wasmer-wasi`",
+        )?)
+        .success();
+
+    // the override receives the 64-bit module
     support::project()
+        .file("src/main.rs", "fn main() {}")
+        .override_runtime("echo")
+        .build()
+        .cargo_wasix("run64")
+        .assert()
+        .stdout(is_match("target.wasm64-wasmer-wasi.debug.foo.wasm")?)
+        .success();
+    Ok(())
+}
+
+#[test]
+fn run_preopens_current_dir() -> Result<()> {
+    let p = support::project()
         .file(
             "src/main.rs",
             r#"
                 fn main() {
-                    println!("{:?}", std::env::args().skip(1).collect::<Vec<_>>());
+                    print!("{}", std::fs::read_to_string("data/foo.txt").unwrap());
                 }
             "#,
         )
-        .build()
-        .cargo_wasix("run a -b c")
+        .file("data/foo.txt", "hello from the host")
+        .build();
+
+    p.cargo_wasix("run")
         .assert()
-        .stdout("[\"a\", \"-b\", \"c\", \"--color=never\"]\n")
+        .stdout("hello from the host")
         .success();
+
+    p.cargo_wasix("run --no-preopen").assert().failure();
     Ok(())
 }
 
 #[test]
-fn test() -> Result<()> {
+fn run_net() -> Result<()> {
     support::project()
         .file(
-            "src/lib.rs",
+            "src/main.rs",
             r#"
-                #[test]
-                fn smoke() {}
+                fn main() {
+                    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+                    println!("{}", listener.local_addr().is_ok());
+                }
             "#,
         )
         .build()
-        .cargo_wasix("test")
+        .cargo_wasix("run --net")
         .assert()
-        .stdout(
-            "
-running 1 test
-test smoke ... ok
-
-test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out
-
-",
-        )
-        .stderr(is_match(
-            "^\
-.*Compiling foo v1.0.0 .*
-.*Finished test .*
-.*Running unittests src/lib.rs .*wasm.
-.*info: Post-processing WebAssembly files
-.*Optimizing with wasm-opt
-.*Running `.*wasm`
-$",
-        )?)
+        .stdout("true\n")
         .success();
-    Ok(())
-}
 
-#[test]
-fn run_nothing() -> Result<()> {
+    // only `wasmer` knows about networking
     support::project()
-        .file("src/lib.rs", "")
+        .file("src/main.rs", "fn main() {}")
+        .override_runtime("echo")
         .build()
-        .cargo_wasix("run")
+        .cargo_wasix("run --net")
         .assert()
-        .code(101);
+        .stderr(is_match("`--net` is only supported with `wasmer`")?)
+        .success();
     Ok(())
 }
 
 #[test]
-fn run_many() -> Result<()> {
-    support::project()
-        .file("src/bin/foo.rs", "")
-        .file("src/bin/bar.rs", "")
-        .build()
-        .cargo_wasix("run")
+#[cfg(unix)]
+fn run_runner_args() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file("runner.sh", "#!/bin/sh\necho \"$@\"\n")
+        .build();
+    let runner = p.root().join("runner.sh");
+    std::fs::set_permissions(&runner, std::fs::Permissions::from_mode(0o755))?;
+
+    p.cargo_wasix("run -- arg")
+        .env("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER", &runner)
+        .env("WASIX_RUNNER_ARGS", " --fuel 1000  --verbose ")
         .assert()
-        .code(101);
+        .stdout(is_match(
+            "^--fuel 1000 --verbose -- .*target.wasm32-wasmer-wasi.debug.foo.wasm arg\n$",
+        )?)
+        .success();
     Ok(())
 }
 
 #[test]
-fn run_one() -> Result<()> {
-    support::project()
-        .file("src/bin/foo.rs", "fn main() {}")
-        .file("src/bin/bar.rs", "")
-        .build()
-        .cargo_wasix("run --bin foo")
+#[cfg(unix)]
+fn test_runner_default_args() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A `wasmer` whose `run --help` doesn't mention `--enable-threads`.
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}\n#[test] fn smoke() {}")
+        .file("runner.sh", "#!/bin/sh\necho \"$@\"\n")
+        .file(
+            "bin/wasmer",
+            "#!/bin/sh\n\
+             if [ \"$2\" = --help ]; then exit 0; fi\n\
+             echo \"$@\"\n",
+        )
+        .build();
+    let runner = p.root().join("runner.sh");
+    std::fs::set_permissions(&runner, std::fs::Permissions::from_mode(0o755))?;
+    let wasmer = p.root().join("bin/wasmer");
+    std::fs::set_permissions(&wasmer, std::fs::Permissions::from_mode(0o755))?;
+    let mut path = vec![p.root().join("bin")];
+    path.extend(std::env::split_paths(
+        &std::env::var_os("PATH").unwrap_or_default(),
+    ));
+    let path = std::env::join_paths(path)?;
+
+    // The test harness runs one test at a time when `wasmer` runs it without
+    // threads ...
+    p.cargo_wasix("test")
+        .env("PATH", &path)
         .assert()
-        .code(0);
-    Ok(())
-}
+        .stdout(is_match("deps.foo-[a-f0-9]*.wasm --test-threads=1\n$")?)
+        .success();
+    // ... unless it's configured otherwise ...
+    p.cargo_wasix("test -- --test-threads=4")
+        .env("PATH", &path)
+        .assert()
+        .stdout(is_match("deps.foo-[a-f0-9]*.wasm --test-threads=4\n$")?)
+        .success();
+    // ... while another runtime decides itself how to run threads ...
+    p.cargo_wasix("test")
+        .env("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER", &runner)
+        .assert()
+        .stdout(is_match("^-- .*deps.foo-[a-f0-9]*.wasm\n$")?)
+        .success();
+    // ... and programs get exactly the arguments that were passed ...
+    p.cargo_wasix("run -- arg")
+        .env("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER", &runner)
+        .assert()
+        .stdout(is_match("^-- .*debug.foo.wasm arg\n$")?)
+        .success();
 
-#[test]
-fn test_flags() -> Result<()> {
-    support::project()
+    // ... as do tests without the harness, which aren't libtest's.
+    let p = support::project()
         .file(
-            "src/lib.rs",
+            "Cargo.toml",
             r#"
-                #[test]
-                fn smoke() {}
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [[test]]
+                name = "plain"
+                harness = false
             "#,
         )
-        .build()
-        .cargo_wasix("test -- --nocapture")
+        .file("src/lib.rs", "")
+        .file("tests/plain.rs", "fn main() {}")
+        .file("runner.sh", "#!/bin/sh\necho \"$@\"\n")
+        .build();
+    let runner = p.root().join("runner.sh");
+    std::fs::set_permissions(&runner, std::fs::Permissions::from_mode(0o755))?;
+    p.cargo_wasix("test --test plain")
+        .env("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER", &runner)
         .assert()
+        .stdout(is_match("^-- .*deps.plain-[a-f0-9]*.wasm\n$")?)
         .success();
     Ok(())
 }
 
 #[test]
-fn run_panic() -> Result<()> {
-    support::project()
-        .file(
+#[cfg(unix)]
+fn run_runtime_version() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                runtime-version = ">=4.2"
+            "#,
+        )
+        .file(
+            "runner.sh",
+            "#!/bin/sh\nif [ \"$1\" = --version ]; then echo \"runner 4.1.0\"; exit; fi\ntouch ran\n",
+        )
+        .build();
+    let runner = p.root().join("runner.sh");
+    std::fs::set_permissions(&runner, std::fs::Permissions::from_mode(0o755))?;
+
+    p.cargo_wasix("run")
+        .env("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER", &runner)
+        .assert()
+        .stderr(predicate::str::contains(
+            "is version 4.1.0, which doesn't satisfy `runtime-version = \">=4.2\"`",
+        ))
+        .success();
+    assert!(p.root().join("ran").exists());
+    std::fs::remove_file(p.root().join("ran"))?;
+
+    // Nothing is built or run with `--strict`.
+    p.cargo_wasix("run --strict")
+        .env("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER", &runner)
+        .assert()
+        .stderr(predicate::str::contains("doesn't satisfy"))
+        .stderr(predicate::str::contains("Compiling").not())
+        .code(2);
+    assert!(!p.root().join("ran").exists());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn run_package_mode() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A `wasmer` that prints the package it runs and remembers where it was.
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            "bin/wasmer",
+            "#!/bin/sh\n\
+             if [ \"$2\" = --help ]; then exit 0; fi\n\
+             for a; do if [ -d \"$a\" ]; then cat \"$a/wasmer.toml\"; ls \"$a\"; echo \"$a\" > package-dir; fi; done\n\
+             echo \"$@\"\n",
+        )
+        .build();
+    let wasmer = p.root().join("bin/wasmer");
+    std::fs::set_permissions(&wasmer, std::fs::Permissions::from_mode(0o755))?;
+    let mut path = vec![p.root().join("bin")];
+    path.extend(std::env::split_paths(
+        &std::env::var_os("PATH").unwrap_or_default(),
+    ));
+
+    p.cargo_wasix("run --package-mode -- arg")
+        .env("PATH", std::env::join_paths(path)?)
+        .assert()
+        .stdout(predicate::str::contains(
+            "[[module]]\nname = \"foo\"\nsource = \"foo.wasm\"",
+        ))
+        .stdout(predicate::str::contains("[[command]]\nname = \"foo\""))
+        .stdout(predicate::str::contains("foo.wasm\nwasmer.toml\n"))
+        .stdout(is_match(
+            "(?m)^run .*--dir=. -- .*cargo-wasix-package[^ ]* arg$",
+        )?)
+        .success();
+    let dir = std::fs::read_to_string(p.root().join("package-dir"))?;
+    assert!(!std::path::Path::new(dir.trim()).exists());
+
+    p.cargo_wasix("run --package-mode")
+        .env("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER", &wasmer)
+        .assert()
+        .stderr(predicate::str::contains("can't be used with"))
+        .code(2);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn run_runtime_without_threads() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A `wasmer` whose `run --help` doesn't mention `--enable-threads`.
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            "bin/wasmer",
+            "#!/bin/sh
+\
+             if [ \"$2\" = --help ]; then echo \"$HELP\"; exit 0; fi
+\
+             echo \"$@\"
+",
+        )
+        .build();
+    let wasmer = p.root().join("bin/wasmer");
+    std::fs::set_permissions(&wasmer, std::fs::Permissions::from_mode(0o755))?;
+    let mut path = vec![p.root().join("bin")];
+    path.extend(std::env::split_paths(
+        &std::env::var_os("PATH").unwrap_or_default(),
+    ));
+    let path = std::env::join_paths(path)?;
+
+    p.cargo_wasix("run")
+        .env("PATH", &path)
+        .env("HELP", "Usage: wasmer run [OPTIONS]")
+        .assert()
+        .stderr(predicate::str::contains(
+            "`wasmer` does not appear to support threads",
+        ))
+        .stdout(predicate::str::contains("--enable-threads").not())
+        .success();
+
+    p.cargo_wasix("run")
+        .env("PATH", &path)
+        .env("HELP", "Usage: wasmer run [OPTIONS]\n  --enable-threads")
+        .env_remove("RUSTFLAGS")
+        .assert()
+        .stderr(predicate::str::contains("support threads").not())
+        .stdout(is_match("^run --enable-threads ")?)
+        .success();
+
+    // Modules without atomics have no shared memory to run threads on.
+    p.cargo_wasix("run --no-atomics")
+        .env("PATH", &path)
+        .env("HELP", "Usage: wasmer run [OPTIONS]\n  --enable-threads")
+        .env_remove("RUSTFLAGS")
+        .assert()
+        .stdout(predicate::str::contains("--enable-threads").not())
+        .success();
+    p.cargo_wasix("run")
+        .env("PATH", &path)
+        .env("HELP", "Usage: wasmer run [OPTIONS]\n  --enable-threads")
+        .env("RUSTFLAGS", "")
+        .assert()
+        .stdout(predicate::str::contains("--enable-threads").not())
+        .success();
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn print_artifacts() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file("runner.sh", "#!/bin/sh\ntouch ran\n")
+        .build();
+    let runner = p.root().join("runner.sh");
+    std::fs::set_permissions(&runner, std::fs::Permissions::from_mode(0o755))?;
+
+    p.cargo_wasix("run --print-artifacts")
+        .env("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER", &runner)
+        .assert()
+        .stdout(format!("{}\n", p.debug_wasm("foo").display()))
+        .success();
+    assert!(!p.root().join("ran").exists());
+
+    p.cargo_wasix("build --print-artifacts")
+        .assert()
+        .stdout(format!("{}\n", p.debug_wasm("foo").display()))
+        .success();
+    Ok(())
+}
+
+#[test]
+fn very_verbose_prints_env() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build -vv")
+        .env("RUSTFLAGS", "-C target-feature=+atomics")
+        .assert()
+        .stderr(predicate::str::contains(
+            "Env RUSTFLAGS=-C target-feature=+atomics",
+        ))
+        .success();
+
+    p.cargo_wasix("build -v")
+        .assert()
+        .stderr(predicate::str::contains("Env RUSTFLAGS").not())
+        .success();
+    Ok(())
+}
+
+#[test]
+fn run_env_file() -> Result<()> {
+    let p = support::project()
+        .file(
+            "src/main.rs",
+            r#"
+                fn main() {
+                    println!("{}", std::env::var("GREETING").unwrap());
+                }
+            "#,
+        )
+        .file(".env", "# comment\nGREETING=\"hello there\"\n")
+        .build();
+
+    p.cargo_wasix("run --env-file .env")
+        .assert()
+        .stdout("hello there\n")
+        .success();
+    Ok(())
+}
+
+#[test]
+fn run_pass_env() -> Result<()> {
+    let p = support::project()
+        .file(
+            "src/main.rs",
+            r#"
+                fn main() {
+                    println!("{:?}", std::env::var("RUST_LOG").ok());
+                    println!("{:?}", std::env::var("MY_VAR").ok());
+                }
+            "#,
+        )
+        .build();
+
+    p.cargo_wasix("run")
+        .env("RUST_LOG", "debug")
+        .env("MY_VAR", "hello")
+        .assert()
+        .stdout("Some(\"debug\")\nNone\n")
+        .success();
+
+    p.cargo_wasix("run --pass-env MY_VAR")
+        .env("RUST_LOG", "debug")
+        .env("MY_VAR", "hello")
+        .assert()
+        .stdout("Some(\"debug\")\nSome(\"hello\")\n")
+        .success();
+    Ok(())
+}
+
+#[test]
+fn bench() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [[bench]]
+                name = "speed"
+                harness = false
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .file(
+            "benches/speed.rs",
+            r#"
+                fn main() {
+                    let args = std::env::args().skip(1).collect::<Vec<_>>();
+                    println!("args: {:?}", args);
+                    println!("test speed ... bench:           1 ns/iter (+/- 0)");
+                }
+            "#,
+        )
+        .build();
+
+    p.cargo_wasix("bench")
+        .assert()
+        .stdout(predicate::str::contains("args: [\"--bench\"]"))
+        .stdout(predicate::str::contains("test speed ... bench:"))
+        .success();
+
+    // Nothing is run with `--no-run`, so the runtime isn't needed.
+    p.cargo_wasix("bench --no-run")
+        .env("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER", "does-not-exist")
+        .assert()
+        .stderr(predicate::str::contains("does-not-exist").not())
+        .success();
+    Ok(())
+}
+
+#[test]
+fn run_forward_args() -> Result<()> {
+    support::project()
+        .file(
             "src/main.rs",
             r#"
                 fn main() {
-                    panic!("test");
+                    println!("{:?}", std::env::args().skip(1).collect::<Vec<_>>());
+                }
+            "#,
+        )
+        .build()
+        .cargo_wasix("run a -b c")
+        .assert()
+        .stdout("[\"a\", \"-b\", \"c\", \"--color=never\"]\n")
+        .success();
+    Ok(())
+}
+
+#[test]
+fn test() -> Result<()> {
+    support::project()
+        .file(
+            "src/lib.rs",
+            r#"
+                #[test]
+                fn smoke() {}
+            "#,
+        )
+        .build()
+        .cargo_wasix("test")
+        .assert()
+        .stdout(
+            "
+running 1 test
+test smoke ... ok
+
+test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out
+
+",
+        )
+        .stderr(is_match(
+            "^\
+.*Compiling foo v1.0.0 .*
+.*Finished test .*
+.*Running unittests src/lib.rs .*wasm.
+.*info: Post-processing WebAssembly files
+.*Optimizing with wasm-opt
+.*Running `.*wasm`
+$",
+        )?)
+        .success();
+    Ok(())
+}
+
+#[test]
+fn run_nothing() -> Result<()> {
+    support::project()
+        .file("src/lib.rs", "")
+        .build()
+        .cargo_wasix("run")
+        .assert()
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn build_nothing_to_process() -> Result<()> {
+    let p = support::project().file("src/lib.rs", "").build();
+
+    p.cargo_wasix("build")
+        .assert()
+        .stderr(is_match(
+            "^\
+.*Compiling foo v1.0.0 .*
+.*Finished dev .*
+.*info: no WebAssembly artifacts to post-process
+$",
+        )?)
+        .success();
+
+    p.cargo_wasix("build --quiet").assert().stderr("").success();
+    Ok(())
+}
+
+#[test]
+fn run_many() -> Result<()> {
+    support::project()
+        .file("src/bin/foo.rs", "")
+        .file("src/bin/bar.rs", "")
+        .build()
+        .cargo_wasix("run")
+        .assert()
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn run_one() -> Result<()> {
+    support::project()
+        .file("src/bin/foo.rs", "fn main() {}")
+        .file("src/bin/bar.rs", "")
+        .build()
+        .cargo_wasix("run --bin foo")
+        .assert()
+        .code(0);
+    Ok(())
+}
+
+#[test]
+fn run_example() -> Result<()> {
+    let p = support::project()
+        .file("src/lib.rs", "")
+        .file("examples/hello.rs", "fn main() { println!(\"hello\"); }")
+        .file("examples/other.rs", "fn main() { println!(\"other\"); }")
+        .build();
+    p.cargo_wasix("run --example hello")
+        .assert()
+        .stdout("hello\n")
+        .stderr(is_match("Optimizing with wasm-opt")?)
+        .success();
+    let wasm = p.debug_wasm("examples/hello");
+    assert!(wasm.with_extension("wasi.wasm").exists());
+    assert!(wasm.with_extension("rustc.wasm").exists());
+    assert!(!p.debug_wasm("examples/other").exists());
+
+    p.cargo_wasix("run --example")
+        .assert()
+        .stderr(is_match("Available examples:\n *hello\n *other")?)
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn test_flags() -> Result<()> {
+    support::project()
+        .file(
+            "src/lib.rs",
+            r#"
+                #[test]
+                fn smoke() {}
+            "#,
+        )
+        .build()
+        .cargo_wasix("test -- --nocapture")
+        .assert()
+        .success();
+    Ok(())
+}
+
+#[test]
+fn run_panic() -> Result<()> {
+    support::project()
+        .file(
+            "src/main.rs",
+            r#"
+                fn main() {
+                    panic!("test");
+                }
+            "#,
+        )
+        .build()
+        .cargo_wasix("run")
+        .assert()
+        .stderr(is_match(
+            "^\
+.*Compiling foo v1.0.0 .*
+.*Finished dev .*
+.*Running `.*cargo-wasix .*foo.wasm`
+.*info: Post-processing WebAssembly files
+.*Optimizing with wasm-opt
+.*Running `.*foo.wasm`
+thread 'main' panicked at 'test', src.main.rs.*
+note: run with `RUST_BACKTRACE=1` .*
+",
+        )?)
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn producers_section() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata]
+                wasm-producers-section = false
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // Should be included in debug build
+    p.cargo_wasix("build").assert().success();
+    let bytes = std::fs::read(p.debug_wasm("foo")).context("failed to read wasm")?;
+    assert!(custom_sections(&bytes)?.contains(&"producers"));
+
+    // ... and shouldnt be included in release build w/o debuginfo
+    p.cargo_wasix("build --release").assert().success();
+    let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
+    assert!(!custom_sections(&bytes)?.contains(&"producers"));
+    Ok(())
+}
+
+#[test]
+fn minimal_producers_section() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                producers = "minimal"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build --release").assert().success();
+    let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
+    assert_eq!(producers(&bytes)?, MINIMAL_PRODUCERS);
+    Ok(())
+}
+
+/// The `producers` section written with `producers = "minimal"`.
+const MINIMAL_PRODUCERS: [(&str, &str, &str); 2] = [
+    ("language", "Rust", ""),
+    ("processed-by", "cargo-wasix", ""),
+];
+
+/// Returns the fields, names and versions in the `producers` section of
+/// `wasm`.
+fn producers(wasm: &[u8]) -> Result<Vec<(&str, &str, &str)>> {
+    let mut producers = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::CustomSection {
+            name: "producers",
+            data,
+            data_offset,
+            ..
+        } = payload?
+        {
+            for field in wasmparser::ProducersSectionReader::new(data, data_offset)? {
+                let field = field?;
+                for value in field.get_producer_field_values_reader()? {
+                    let value = value?;
+                    producers.push((field.name, value.name, value.version));
+                }
+            }
+        }
+    }
+    Ok(producers)
+}
+
+#[test]
+fn reproducible_across_directories() -> Result<()> {
+    let build = || -> Result<Vec<u8>> {
+        let p = support::project()
+            .file(
+                "Cargo.toml",
+                r#"
+                    [package]
+                    name = "foo"
+                    version = "1.0.0"
+
+                    [package.metadata.wasix]
+                    producers = "minimal"
+                "#,
+            )
+            .file(
+                "src/main.rs",
+                r#"
+                    fn main() {
+                        if std::env::args().count() > 5 {
+                            panic!("too many arguments");
+                        }
+                    }
+                "#,
+            )
+            .build();
+        p.cargo_wasix("build --release --remap-path")
+            .env_remove("RUSTFLAGS")
+            .assert()
+            .success();
+        let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
+        let root = p.root().to_str().unwrap().to_string();
+        assert!(!bytes
+            .windows(root.len())
+            .any(|window| window == root.as_bytes()));
+        Ok(bytes)
+    };
+
+    // Every project is created in its own directory.
+    assert!(build()? == build()?);
+    Ok(())
+}
+
+#[test]
+fn name_section() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata]
+                wasm-name-section = false
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // Should be included in debug build
+    p.cargo_wasix("build").assert().success();
+    let bytes = std::fs::read(p.debug_wasm("foo")).context("failed to read wasm")?;
+    assert!(custom_sections(&bytes)?.contains(&"name"));
+
+    // ... and shouldnt be included in release build w/o debuginfo
+    p.cargo_wasix("build --release").assert().success();
+    let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
+    assert!(!custom_sections(&bytes)?.contains(&"name"));
+    Ok(())
+}
+
+#[test]
+fn release_keep_name_section() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata]
+                wasm-name-section = true
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // Names are kept by the linker and by `wasm-opt -O3`, without DWARF.
+    p.cargo_wasix("build --release -vv")
+        .assert()
+        .stderr(predicate::str::contains(
+            "Env CARGO_PROFILE_RELEASE_STRIP=none",
+        ))
+        .stderr(is_match("Running .*wasm-opt.*-O3.*--debuginfo")?)
+        .success();
+    let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
+    let sections = custom_sections(&bytes)?;
+    assert!(sections.contains(&"name"));
+    assert!(!sections.iter().any(|s| s.starts_with(".debug_")));
+    assert_demangled(&bytes)?;
+    Ok(())
+}
+
+fn custom_sections(bytes: &[u8]) -> Result<Vec<&str>> {
+    let mut sections = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+        if let wasmparser::Payload::CustomSection { name, .. } = payload? {
+            sections.push(name);
+        }
+    }
+    Ok(sections)
+}
+
+fn exports(bytes: &[u8]) -> Result<Vec<&str>> {
+    let mut exports = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+        if let wasmparser::Payload::ExportSection(reader) = payload? {
+            for export in reader {
+                exports.push(export?.field);
+            }
+        }
+    }
+    Ok(exports)
+}
+
+#[test]
+fn hide_asyncify_exports() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build").assert().success();
+    let bytes = std::fs::read(p.debug_wasm("foo")).context("failed to read wasm")?;
+    assert!(exports(&bytes)?.contains(&"asyncify_start_unwind"));
+
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                hide-asyncify-exports = true
+                producers = "minimal"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build").assert().success();
+    let bytes = std::fs::read(p.debug_wasm("foo")).context("failed to read wasm")?;
+    let exports = exports(&bytes)?;
+    assert!(exports.contains(&"_start"));
+    assert!(
+        !exports.iter().any(|e| e.starts_with("asyncify_")),
+        "{exports:?}"
+    );
+    // Hiding the exports parses the module again, which mustn't add
+    // `walrus` back to a minimal `producers` section.
+    assert_eq!(producers(&bytes)?, MINIMAL_PRODUCERS);
+    Ok(())
+}
+
+#[test]
+fn release_skip_wasm_opt() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata]
+                wasm-opt = false
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build --release")
+        .assert()
+        .stderr(is_match(
+            "^\
+.*Compiling foo v1.0.0 .*
+.*Finished release .*
+.*info: Post-processing WebAssembly files
+$",
+        )?)
+        .success();
+    Ok(())
+}
+
+#[test]
+fn skip_wasm_opt_if_debug() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [profile.release]
+                debug = 1
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build --release")
+        .assert()
+        .stderr(is_match(
+            "^\
+.*Compiling foo v1.0.0 .*
+.*Finished release .*
+.*info: Post-processing WebAssembly files
+.*Skipping wasm-opt optimizations because debuginfo is enabled \\(set `debug = 0` to optimize\\)
+$",
+        )?)
+        .success();
+    let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
+    let sections = custom_sections(&bytes)?;
+    assert!(sections.iter().any(|s| s.starts_with(".debug_info")));
+    Ok(())
+}
+
+#[test]
+fn build_script_sysroot() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            "build.rs",
+            r#"
+                fn main() {
+                    let sysroot = std::env::var("WASIX_SYSROOT").unwrap();
+                    assert!(std::path::Path::new(&sysroot).join("include").is_dir());
+                    assert!(sysroot.ends_with("sysroot32"));
+                }
+            "#,
+        )
+        .build();
+
+    p.cargo_wasix("build")
+        .env_remove("WASIX_SYSROOT")
+        .assert()
+        .success();
+    Ok(())
+}
+
+#[test]
+fn build_script_wasi_sdk_dir() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            "build.rs",
+            r#"
+                fn main() {
+                    let dir = std::env::var("WASI_SDK_DIR").unwrap();
+                    assert!(!dir.starts_with("/opt/"), "{dir}");
+                    assert!(std::path::Path::new(&dir).join("include").is_dir());
+                    assert_eq!(dir, std::env::var("WASIX_SYSROOT").unwrap());
+                }
+            "#,
+        )
+        .build();
+
+    p.cargo_wasix("build")
+        .env_remove("WASI_SDK_DIR")
+        .env_remove("WASIX_SYSROOT")
+        .assert()
+        .success();
+    Ok(())
+}
+
+#[test]
+fn unshared_memory() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // Setting RUSTFLAGS drops `+atomics`, so the memory isn't expected to be
+    // shared.
+    p.cargo_wasix("build")
+        .env("RUSTFLAGS", "")
+        .assert()
+        .stderr(predicate::str::contains("its memory is not shared").not())
+        .success();
+
+    p.cargo_wasix("build --deny-unshared-memory")
+        .env("RUSTFLAGS", "")
+        .assert()
+        .stderr(predicate::str::contains("its memory is not shared").not())
+        .success();
+
+    p.cargo_wasix("build --no-atomics")
+        .env("RUSTFLAGS", "")
+        .assert()
+        .stderr(predicate::str::contains("its memory is not shared").not())
+        .success();
+    Ok(())
+}
+
+#[test]
+fn no_atomics() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build -v")
+        .env_remove("RUSTFLAGS")
+        .assert()
+        .stderr(predicate::str::contains("target-feature=+atomics"))
+        .success();
+
+    p.cargo_wasix("build -v --no-atomics")
+        .env_remove("RUSTFLAGS")
+        .assert()
+        .stderr(predicate::str::contains("target-feature=+atomics").not())
+        .success();
+
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                atomics = false
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build -v")
+        .env_remove("RUSTFLAGS")
+        .assert()
+        .stderr(predicate::str::contains("target-feature=+atomics").not())
+        .success();
+    Ok(())
+}
+
+#[test]
+fn extra_target_features() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                target-features = ["simd128", "not-a-feature"]
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build -v")
+        .env_remove("RUSTFLAGS")
+        .assert()
+        .stderr(predicate::str::contains(
+            "unknown target feature `not-a-feature`",
+        ))
+        .stderr(predicate::str::contains(
+            "target-feature=+atomics,+simd128,+not-a-feature",
+        ))
+        .stderr(is_match("Running .*wasm-opt.*--enable-simd")?)
+        .success();
+    Ok(())
+}
+
+#[test]
+fn compat_mvp() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                compat = "mvp"
+            "#,
+        )
+        .file("src/main.rs", "fn main() { println!(\"hello\"); }")
+        .build();
+
+    p.cargo_wasix("build -v --release")
+        .env_remove("RUSTFLAGS")
+        .assert()
+        .stderr(predicate::str::contains("target-cpu=mvp"))
+        .stderr(predicate::str::contains("+atomics").not())
+        .stderr(is_match("Running .*wasm-opt.*--mvp-features")?)
+        .success();
+
+    let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
+    wasmparser::Validator::new()
+        .wasm_features(wasmparser::WasmFeatures {
+            reference_types: false,
+            multi_value: false,
+            bulk_memory: false,
+            ..Default::default()
+        })
+        .validate_all(&bytes)?;
+    // The validator always accepts the sign-extension and non-trapping
+    // float-to-int instructions, which the MVP doesn't have.
+    for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+        if let wasmparser::Payload::CodeSectionEntry(body) = payload? {
+            for op in body.get_operators_reader()? {
+                use wasmparser::Operator::*;
+                let op = op?;
+                assert!(
+                    !matches!(
+                        op,
+                        I32Extend8S
+                            | I32Extend16S
+                            | I64Extend8S
+                            | I64Extend16S
+                            | I64Extend32S
+                            | I32TruncSatF32S
+                            | I32TruncSatF32U
+                            | I32TruncSatF64S
+                            | I32TruncSatF64U
+                            | I64TruncSatF32S
+                            | I64TruncSatF32U
+                            | I64TruncSatF64S
+                            | I64TruncSatF64U
+                    ),
+                    "{op:?}"
+                );
+            }
+        }
+    }
+
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                compat = "mvp"
+                atomics = true
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build")
+        .assert()
+        .stderr(predicate::str::contains(
+            "`compat = \"mvp\"` in `[package.metadata.wasix]` can't be combined with `atomics = true`",
+        ))
+        .code(2);
+    Ok(())
+}
+
+#[test]
+fn explicit_target() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build --target wasm32-wasmer-wasi")
+        .assert()
+        .success();
+
+    p.cargo_wasix("build --target=wasm64-wasmer-wasi")
+        .assert()
+        .stderr(predicate::str::contains(
+            "`--target wasm64-wasmer-wasi` conflicts with the `wasm32-wasmer-wasi` target",
+        ))
+        .stderr(predicate::str::contains("cargo wasix build64"))
+        .code(2);
+
+    p.cargo_wasix("build64 --target x86_64-unknown-linux-gnu")
+        .assert()
+        .stderr(predicate::str::contains(
+            "the target is managed automatically",
+        ))
+        .code(2);
+
+    // Every `--target` is checked, not only the first one.
+    p.cargo_wasix("build --target wasm32-wasmer-wasi --target=x86_64-unknown-linux-gnu")
+        .assert()
+        .stderr(predicate::str::contains(
+            "`--target x86_64-unknown-linux-gnu` conflicts",
+        ))
+        .code(2);
+    Ok(())
+}
+
+#[test]
+fn out_dir() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                out-dir = "dist"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file("src/bin/bar.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build").assert().success();
+    let foo = std::fs::read(p.root().join("dist/foo.wasm")).context("failed to read wasm")?;
+    assert_eq!(foo, std::fs::read(p.debug_wasm("foo"))?);
+    assert!(p.root().join("dist/bar.wasm").is_file());
+
+    p.cargo_wasix("build --release --out-dir=other")
+        .assert()
+        .success();
+    let foo = std::fs::read(p.root().join("other/foo.wasm")).context("failed to read wasm")?;
+    assert_eq!(foo, std::fs::read(p.release_wasm("foo"))?);
+    Ok(())
+}
+
+#[test]
+fn per_profile_wasm_opt() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [profile.release-dist]
+                inherits = "release"
+
+                [package.metadata.wasix.profile.release]
+                wasm-opt = false
+
+                [package.metadata.wasix.profile.release-dist]
+                wasm-opt-args = ["--strip-producers", "-Oz"]
+            "#,
+        )
+        .file("src/main.rs", "fn main() { println!(\"hello\"); }")
+        .build();
+
+    p.cargo_wasix("build --release -v")
+        .assert()
+        .stderr(predicate::str::contains("Optimizing").not())
+        .success();
+    p.cargo_wasix("build --profile release-dist -v")
+        .assert()
+        .stderr(predicate::str::contains("Optimizing"))
+        .stderr(predicate::str::contains("--strip-producers -Oz"))
+        .success();
+
+    let release = std::fs::read(p.release_wasm("foo"))?;
+    let dist = std::fs::read(p.profile_wasm("release-dist", "foo"))?;
+    assert!(dist.len() < release.len());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn custom_profile() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [profile.release-lto]
+                inherits = "release"
+                lto = true
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file("runner.sh", "#!/bin/sh\necho \"$@\"\n")
+        .build();
+    let runner = p.root().join("runner.sh");
+    std::fs::set_permissions(&runner, std::fs::Permissions::from_mode(0o755))?;
+
+    // The modules are post-processed where Cargo put them.
+    p.cargo_wasix("build --profile release-lto")
+        .assert()
+        .stderr(predicate::str::contains("Optimizing with wasm-opt"))
+        .success();
+    let wasm = p.profile_wasm("release-lto", "foo");
+    assert!(wasm.exists());
+    assert!(wasm.with_extension("rustc.wasm").exists());
+    assert!(!p.release_wasm("foo").exists());
+
+    p.cargo_wasix("run --profile release-lto")
+        .env("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER", &runner)
+        .assert()
+        .stdout(format!("-- {}\n", wasm.display()))
+        .success();
+    Ok(())
+}
+
+#[test]
+fn wasm_opt_level() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [profile.release]
+                opt-level = "s"
+
+                [package.metadata.wasix]
+                wasm-opt-level = 3
+            "#,
+        )
+        .file("src/main.rs", "fn main() { println!(\"hello\"); }")
+        .build();
+    p.cargo_wasix("build --release -v")
+        .assert()
+        .stderr(is_match("Running .*wasm-opt.* -O3 ")?)
+        .stderr(predicate::str::contains(" -Os ").not())
+        .success();
+
+    std::fs::write(
+        p.root().join("Cargo.toml"),
+        r#"
+            [package]
+            name = "foo"
+            version = "1.0.0"
+
+            [package.metadata.wasix]
+            wasm-opt-level = "fast"
+        "#,
+    )?;
+    p.cargo_wasix("build --release")
+        .assert()
+        .stderr(predicate::str::contains(
+            "invalid `wasm-opt-level` `fast`, expected 0, 1, 2, 3, 4, \"s\" or \"z\"",
+        ))
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn test_list() {
+    let p = support::project()
+        .file(
+            "src/lib.rs",
+            r#"
+                #[test]
+                fn first() {
+                    panic!("must not run");
+                }
+
+                #[test]
+                fn second() {
+                    panic!("must not run");
+                }
+            "#,
+        )
+        .build();
+
+    for args in ["test -- --list", "test --list"] {
+        p.cargo_wasix(args)
+            .assert()
+            .stdout(predicate::str::contains("first: test"))
+            .stdout(predicate::str::contains("second: test"))
+            .stdout(predicate::str::contains("must not run").not())
+            .success();
+    }
+}
+
+#[test]
+fn test_json_output() -> Result<()> {
+    let p = support::project()
+        .file(
+            "src/lib.rs",
+            r#"
+                #[test]
+                fn works() {}
+            "#,
+        )
+        .build();
+
+    let output = p
+        .cargo_wasix("test -- -Z unstable-options --format json")
+        .output()?;
+    assert!(output.status.success());
+    // Every line is an event of the test harness, nothing else is mixed in.
+    let stdout = String::from_utf8(output.stdout)?;
+    let events = stdout
+        .lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .collect::<Result<Vec<_>, _>>()?;
+    assert!(events
+        .iter()
+        .any(|e| e["type"] == "test" && e["name"] == "works" && e["event"] == "ok"));
+    assert!(events.iter().all(|e| e.get("reason").is_none()));
+    Ok(())
+}
+
+#[test]
+fn custom_target_dir() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    let check = |build_dir: &std::path::Path| -> Result<()> {
+        let wasm = support::wasm_in(build_dir, "release", "foo");
+        let optimized = std::fs::read(&wasm).context("failed to read wasm")?;
+        assert_eq!(optimized, std::fs::read(wasm.with_extension("wasi.wasm"))?);
+        assert!(wasm.with_extension("rustc.wasm").is_file());
+        Ok(())
+    };
+
+    p.cargo_wasix("build --release --target-dir custom")
+        .assert()
+        .success();
+    check(&p.root().join("custom"))?;
+
+    let target_dir = p.root().join("shared-target");
+    p.cargo_wasix("build --release")
+        .env("CARGO_TARGET_DIR", &target_dir)
+        .assert()
+        .success();
+    check(&target_dir)?;
+
+    assert!(!p.build_dir().exists());
+    Ok(())
+}
+
+#[test]
+fn fresh_build_keeps_mtime() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                out-dir = "dist"
+                compress = "gzip"
+            "#,
+        )
+        .build();
+
+    p.cargo_wasix("build").assert().success();
+    let wasm = p.debug_wasm("foo");
+    let copy = p.root().join("dist/foo.wasm");
+    let compressed = wasm.with_extension("wasm.gz");
+    let bytes = std::fs::read(&wasm)?;
+    let modified = std::fs::metadata(&wasm)?.modified()?;
+    let copy_modified = std::fs::metadata(&copy)?.modified()?;
+    let compressed_modified = std::fs::metadata(&compressed)?.modified()?;
+
+    // Enough for a coarse filesystem clock to tell the builds apart.
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    p.cargo_wasix("build").assert().success();
+    assert_eq!(std::fs::read(&wasm)?, bytes);
+    assert_eq!(std::fs::metadata(&wasm)?.modified()?, modified);
+    // Neither copy is written again.
+    assert_eq!(std::fs::metadata(&copy)?.modified()?, copy_modified);
+    assert_eq!(
+        std::fs::metadata(&compressed)?.modified()?,
+        compressed_modified
+    );
+    Ok(())
+}
+
+#[test]
+fn compress() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                compress = "gzip"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build --release")
+        .assert()
+        .stderr(is_match("Compressed .*foo.wasm.gz \\([0-9]+ bytes\\)")?)
+        .success();
+    let wasm = std::fs::read(p.release_wasm("foo"))?;
+    let compressed = std::fs::File::open(p.release_wasm("foo").with_extension("wasm.gz"))?;
+    let mut decompressed = Vec::new();
+    std::io::Read::read_to_end(
+        &mut flate2::read::GzDecoder::new(compressed),
+        &mut decompressed,
+    )?;
+    assert_eq!(decompressed, wasm);
+
+    p.cargo_wasix("build --release --compress zstd")
+        .assert()
+        .stderr(predicate::str::contains(
+            "unsupported compression `zstd`, the only supported one is `gzip`",
+        ))
+        .failure();
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn post_build_hook() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                post-build = "scripts/post-build.sh"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            "scripts/post-build.sh",
+            "#!/bin/sh\necho \"$1 $CARGO_WASIX_TARGET_NAME $CARGO_WASIX_PROFILE\" > hook-ran\n",
+        )
+        .build();
+    let hook = p.root().join("scripts/post-build.sh");
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755))?;
+
+    p.cargo_wasix("build --release").assert().success();
+    let sentinel = std::fs::read_to_string(p.root().join("hook-ran"))?;
+    assert_eq!(
+        sentinel,
+        format!("{} foo release\n", p.release_wasm("foo").display())
+    );
+
+    std::fs::write(&hook, "#!/bin/sh\necho 'signing failed' >&2\nexit 3\n")?;
+    p.cargo_wasix("build --release")
+        .assert()
+        .stderr(predicate::str::contains("`post-build` hook failed for"))
+        .stderr(predicate::str::contains("signing failed"))
+        .failure();
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn keep_going() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                post-build = "post-build.sh"
+            "#,
+        )
+        .file("src/bin/good.rs", "fn main() {}")
+        .file("src/bin/bad.rs", "fn main() {}")
+        .file(
+            "post-build.sh",
+            "#!/bin/sh
+[ \"$CARGO_WASIX_TARGET_NAME\" = bad ] && exit 1
+\
+             echo $CARGO_WASIX_TARGET_NAME >> hook-ran
+",
+        )
+        .build();
+    let hook = p.root().join("post-build.sh");
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755))?;
+
+    p.cargo_wasix("build")
+        .assert()
+        .stderr(predicate::str::contains("failed to post-process").not())
+        .code(3);
+
+    drop(std::fs::remove_file(p.root().join("hook-ran")));
+    p.cargo_wasix("build --keep-going")
+        .assert()
+        .stderr(predicate::str::contains("`post-build` hook failed for"))
+        .stderr(predicate::str::contains(format!(
+            "failed to post-process 1 of 2 WebAssembly files:\n  {}",
+            p.debug_wasm("bad").display()
+        )))
+        .code(3);
+    assert_eq!(
+        std::fs::read_to_string(p.root().join("hook-ran"))?,
+        "good\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn size() -> Result<()> {
+    let p = support::project()
+        .file(
+            "src/main.rs",
+            r#"
+                #[inline(never)]
+                fn big() -> u64 {
+                    (0..std::env::args().count() as u64).map(|i| i.pow(3) % 7).sum()
+                }
+                fn main() {
+                    println!("{}", big());
+                }
+            "#,
+        )
+        .build();
+
+    p.cargo_wasix("build --release").assert().success();
+    let wasm = std::fs::read(p.release_wasm("foo"))?;
+
+    p.cargo_wasix("size --release --top 3")
+        .assert()
+        .stdout(predicate::str::starts_with(format!(
+            "{} (",
+            p.release_wasm("foo").display()
+        )))
+        .stdout(is_match(
+            "bytes\\)\n +Size +Percent  Function\n( +[0-9]+ +[0-9.]+%  .+\n){3}$",
+        )?)
+        .success();
+    p.cargo_wasix("size --release --dominators --depth 1")
+        .assert()
+        .stdout(predicate::str::contains("Dominator tree"))
+        .stdout(is_match("(?m)^ +[0-9]+ +[0-9.]+%  _start$")?)
+        .success();
+    // Only reported, the module stays the same.
+    assert_eq!(std::fs::read(p.release_wasm("foo"))?, wasm);
+    Ok(())
+}
+
+#[test]
+fn json_build_summary() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    let output = p
+        .cargo_wasix("build --release --cargo-wasix-json")
+        .output()?;
+    assert!(output.status.success());
+    let summary = serde_json::from_slice::<serde_json::Value>(&output.stdout)?;
+    assert_eq!(summary["profile"], "release");
+    let artifacts = summary["artifacts"].as_array().unwrap();
+    assert_eq!(artifacts.len(), 1);
+    let artifact = &artifacts[0];
+    assert_eq!(artifact["name"], "foo");
+    assert_eq!(artifact["wasm_opt"], true);
+    assert_eq!(artifact["fresh"], false);
+    let wasm = p.release_wasm("foo");
+    assert_eq!(artifact["path"], wasm.to_str().unwrap());
+    assert_eq!(artifact["size_after"], std::fs::metadata(&wasm)?.len());
+    assert_eq!(
+        artifact["size_before"],
+        std::fs::metadata(wasm.with_extension("rustc.wasm"))?.len()
+    );
+
+    // An up to date module isn't optimized again.
+    let output = p
+        .cargo_wasix("build --release --cargo-wasix-json")
+        .output()?;
+    assert!(output.status.success());
+    let summary = serde_json::from_slice::<serde_json::Value>(&output.stdout)?;
+    let artifact = &summary["artifacts"][0];
+    assert_eq!(artifact["wasm_opt"], false);
+    assert_eq!(artifact["fresh"], true);
+
+    // Off by default.
+    p.cargo_wasix("build --release")
+        .assert()
+        .stdout("")
+        .success();
+    Ok(())
+}
+
+#[test]
+fn component() -> Result<()> {
+    // Without std the module only imports what the adapter provides.
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [lib]
+                crate-type = ["cdylib"]
+
+                [package.metadata.wasix]
+                atomics = false
+                component = true
+            "#,
+        )
+        .file(
+            "src/lib.rs",
+            r#"
+                #![no_std]
+
+                #[panic_handler]
+                fn panic(_: &core::panic::PanicInfo) -> ! {
+                    loop {}
+                }
+
+                #[no_mangle]
+                pub extern "C" fn add(a: i32, b: i32) -> i32 {
+                    a + b
+                }
+            "#,
+        )
+        .build();
+
+    let output = p
+        .cargo_wasix("build --release --cargo-wasix-json")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .output()?;
+    assert!(output.status.success());
+    let summary = serde_json::from_slice::<serde_json::Value>(&output.stdout)?;
+    let wasm = p.release_wasm("foo");
+    let component = wasm.with_extension("component.wasm");
+    assert_eq!(
+        summary["artifacts"][0]["component_path"],
+        component.to_str().unwrap()
+    );
+
+    // The core module stays in place.
+    let module = std::fs::read(&wasm)?;
+    wasmparser::Validator::new().validate_all(&module)?;
+    // A component has a different version and layer in its preamble.
+    let bytes = std::fs::read(&component)?;
+    assert_eq!(bytes[..8], *b"\0asm\x0d\0\x01\0");
+    let wasm_tools = p
+        .root()
+        .join("cache")
+        .join(env!("CARGO_PKG_VERSION"))
+        .join("wasm-tools/1.219.1/wasm-tools")
+        .join(format!("wasm-tools{}", std::env::consts::EXE_SUFFIX));
+    let status = Command::new(wasm_tools)
+        .arg("validate")
+        .arg(&component)
+        .status()?;
+    assert!(status.success());
+
+    // With `atomics` the memory is shared, which the adapter rejects.
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    p.cargo_wasix("build --component")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .assert()
+        .stderr(predicate::str::contains(
+            "has a shared memory, which the WASI adapter doesn't support",
+        ))
+        .code(3);
+    Ok(())
+}
+
+#[test]
+fn install() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("install --root inst --launcher")
+        .assert()
+        .success();
+    let wasm = p.root().join("inst/bin/foo.wasm");
+    let installed = std::fs::read(&wasm).context("failed to read wasm")?;
+    assert_eq!(installed, std::fs::read(p.release_wasm("foo"))?);
+    #[cfg(unix)]
+    {
+        let launcher = std::fs::read_to_string(p.root().join("inst/bin/foo"))?;
+        assert!(launcher.starts_with("#!/bin/sh\nexec 'wasmer'"));
+        assert!(launcher.contains(&format!("'{}' \"$@\"", wasm.display())));
+    }
+    Ok(())
+}
+
+#[test]
+fn install_multiple_bins() {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file("src/bin/bar.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("install --root inst")
+        .assert()
+        .stderr(predicate::str::contains(
+            "multiple binaries are available: `bar`, `foo`",
+        ))
+        .failure();
+    p.cargo_wasix("install --root inst --bin bar")
+        .assert()
+        .success();
+    assert!(p.root().join("inst/bin/bar.wasm").is_file());
+    assert!(!p.root().join("inst/bin/foo.wasm").exists());
+}
+
+#[test]
+fn self_update_toolchain_offline() {
+    cargo_wasix("self update-toolchain")
+        .env("CARGO_WASIX_OFFLINE", "1")
+        .assert()
+        .stderr(predicate::str::contains("CARGO_WASIX_OFFLINE is set"))
+        .code(2);
+}
+
+#[test]
+fn self_fetch_tools_offline() {
+    cargo_wasix("self fetch-tools")
+        .env("CARGO_WASIX_OFFLINE", "1")
+        .assert()
+        .stderr(predicate::str::contains(
+            "cannot fetch tools because CARGO_WASIX_OFFLINE is set",
+        ))
+        .code(2);
+}
+
+#[test]
+fn self_fetch_tools() -> Result<()> {
+    // The list of incompatible crates was downloaded recently.
+    let p = support::project()
+        .file("cache/incompatible_crates.json", "[]")
+        .build();
+    let (toolchain, rustup_home) = p.link_fake_toolchain("wasix");
+
+    p.cargo_wasix("self fetch-tools")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env_remove("CARGO_WASIX_OFFLINE")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .env("WASM_OPT", p.root().join("wasm-opt"))
+        .env("WASM_TOOLS", p.root().join("wasm-tools"))
+        .env("WASI_ADAPTER", p.root().join("adapter.wasm"))
+        .assert()
+        .stderr(predicate::str::contains(format!(
+            "Cached toolchain wasix at {} (",
+            toolchain.display()
+        )))
+        .stderr(predicate::str::contains(
+            "$WASM_OPT is set, so `wasm-opt` isn't downloaded",
+        ))
+        .stderr(predicate::str::contains(
+            "$WASM_TOOLS is set, so `wasm-tools` isn't downloaded",
+        ))
+        .stderr(predicate::str::contains(format!(
+            "Cached known incompatible crates at {} (2 B)",
+            p.root().join("cache/incompatible_crates.json").display()
+        )))
+        .stderr(predicate::str::contains("Downloading").not())
+        .success();
+    Ok(())
+}
+
+#[test]
+fn existing_toolchain_is_not_downloaded() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    let (toolchain, rustup_home) = p.link_fake_toolchain("wasix");
+
+    p.cargo_wasix("build -v")
+        .env("RUSTUP_HOME", &rustup_home)
+        // Set by rustup for the tests, but not installed in `rustup_home`.
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env_remove("CARGO_WASIX_OFFLINE")
+        .assert()
+        .stderr(predicate::str::contains(format!(
+            "Using existing wasix toolchain at {}",
+            toolchain.display()
+        )))
+        .stderr(predicate::str::contains("release").not())
+        // The toolchain is used, but being fake it fails to compile.
+        .code(1);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn wasm_opt_not_executable() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // Simulate a cache on a filesystem that doesn't allow executing files.
+    let cache_dir = p.root().join("cache");
+    let wasm_opt = cache_dir
+        .join(env!("CARGO_PKG_VERSION"))
+        .join("wasm-opt/bin/wasm-opt");
+    std::fs::create_dir_all(wasm_opt.parent().unwrap())?;
+    std::fs::write(&wasm_opt, "")?;
+
+    p.cargo_wasix("build --release")
+        .env("WASIX_CACHE_DIR", &cache_dir)
+        .env_remove("WASM_OPT")
+        .assert()
+        .stderr(predicate::str::contains(
+            "was downloaded but can't be executed, the cache directory is probably on a \
+             filesystem that doesn't allow executing files",
+        ))
+        .failure();
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn wasm_opt_failure_prints_command() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let wasm_opt = p.root().join("failing-wasm-opt");
+    std::fs::write(&wasm_opt, "#!/bin/sh\nexit 1\n")?;
+    std::fs::set_permissions(&wasm_opt, std::fs::Permissions::from_mode(0o755))?;
+
+    p.cargo_wasix("build --release")
+        .env("WASM_OPT", &wasm_opt)
+        .assert()
+        .stderr(predicate::str::contains(format!(
+            "`wasm-opt` failed to execute: {}",
+            wasm_opt.display()
+        )))
+        .stderr(predicate::str::contains("-O3 -o"))
+        .failure();
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn wasm_opt_invalid_output() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    // Garbage, and a module that parses but whose function returns nothing
+    // instead of an `i32`.
+    let outputs = [
+        "garbage",
+        "\\000asm\\001\\000\\000\\000\\001\\005\\001\\140\\000\\001\\177\\003\\002\\001\\000\\012\\004\\001\\002\\000\\013",
+    ];
+    for output in outputs {
+        // Writes `output` to the path passed to `-o`.
+        let wasm_opt = p.root().join("broken-wasm-opt");
+        std::fs::write(
+            &wasm_opt,
+            format!(
+                r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    if [ "$1" = "-o" ]; then printf '{output}' > "$2"; fi
+    shift
+done
+"#
+            ),
+        )?;
+        std::fs::set_permissions(&wasm_opt, std::fs::Permissions::from_mode(0o755))?;
+
+        p.cargo_wasix("build --release")
+            .env("WASM_OPT", &wasm_opt)
+            .assert()
+            .stderr(predicate::str::contains(
+                "`wasm-opt` produced an invalid module",
+            ))
+            .stderr(predicate::str::contains("set `wasm-opt = false`"))
+            .failure();
+    }
+
+    // Modules with features that can't be validated aren't.
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                target-features = ["relaxed-simd"]
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let wasm_opt = p.root().join("broken-wasm-opt");
+    std::fs::write(
+        &wasm_opt,
+        r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    if [ "$1" = "-o" ]; then printf 'garbage' > "$2"; fi
+    shift
+done
+"#,
+    )?;
+    std::fs::set_permissions(&wasm_opt, std::fs::Permissions::from_mode(0o755))?;
+    p.cargo_wasix("build -v --release")
+        .env("WASM_OPT", &wasm_opt)
+        .env_remove("RUSTFLAGS")
+        .assert()
+        .stderr(predicate::str::contains(
+            "because `relaxed-simd` can't be validated",
+        ))
+        .stderr(predicate::str::contains("produced an invalid module").not());
+    Ok(())
+}
+
+#[test]
+fn wasm_opt_no_auto_download() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build --release")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .env("WASIX_NO_AUTO_DOWNLOAD", "1")
+        .env_remove("WASM_OPT")
+        .assert()
+        .stderr(predicate::str::contains(
+            "isn't downloaded because $WASIX_NO_AUTO_DOWNLOAD is set",
+        ))
+        .stderr(predicate::str::contains("Downloading").not())
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn binaryen_version_override() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build --release")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .env("WASIX_BINARYEN_VERSION", "latest")
+        .env_remove("WASM_OPT")
+        .assert()
+        .stderr(predicate::str::contains(
+            "$WASIX_BINARYEN_VERSION is set to `latest`, which is not a binaryen release tag",
+        ))
+        .stderr(predicate::str::contains("Downloading").not())
+        .failure();
+
+    p.cargo_wasix("build --release")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .env("WASIX_BINARYEN_VERSION", "version_116")
+        .env_remove("WASM_OPT")
+        .assert()
+        .stderr(predicate::str::contains(
+            "Downloading precompiled wasm-opt version_116",
+        ))
+        .success();
+    assert!(p
+        .root()
+        .join("cache")
+        .join(env!("CARGO_PKG_VERSION"))
+        .join("wasm-opt/version_116/wasm-opt")
+        .is_dir());
+    Ok(())
+}
+
+#[test]
+fn local_tools() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let cache_dir = p.root().join("cache");
+
+    p.cargo_wasix("build --release --local-tools")
+        .env("WASIX_CACHE_DIR", &cache_dir)
+        .env_remove("WASM_OPT")
+        .assert()
+        .stderr(predicate::str::contains("Downloading precompiled wasm-opt"))
+        .success();
+    assert!(p.build_dir().join("wasix-tools/wasm-opt").is_dir());
+    assert!(!cache_dir
+        .join(env!("CARGO_PKG_VERSION"))
+        .join("wasm-opt")
+        .exists());
+
+    // Already downloaded for this target directory.
+    p.cargo_wasix("build")
+        .env("WASIX_CACHE_DIR", &cache_dir)
+        .env("WASIX_LOCAL_TOOLS", "1")
+        .env_remove("WASM_OPT")
+        .assert()
+        .stderr(predicate::str::contains("Downloading").not())
+        .success();
+    Ok(())
+}
+
+#[test]
+fn custom_toolchain_name() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let (_, rustup_home) = p.link_fake_toolchain("wasix-custom");
+
+    p.cargo_wasix("build -v")
+        .env("RUSTUP_HOME", &rustup_home)
+        // Set by rustup for the tests, but not installed in `rustup_home`.
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env("CARGO_WASIX_TOOLCHAIN", "wasix-custom")
+        .assert()
+        .stderr(predicate::str::contains("\"+wasix-custom\""))
+        // The toolchain is used, but being fake it fails to compile.
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn self_paths() -> Result<()> {
+    let p = support::project().build();
+    let (toolchain, rustup_home) = p.link_fake_toolchain("wasix-custom");
+    // Sysroots are next to the toolchain, only the 32-bit one exists here.
+    std::fs::create_dir(p.root().join("sysroot32"))?;
+
+    p.cargo_wasix("self paths")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env("CARGO_WASIX_TOOLCHAIN", "wasix-custom")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .env("WASIX_TOOLCHAINS_ROOT", p.root().join("toolchains"))
+        .assert()
+        .stdout(predicate::str::contains(
+            "CARGO_WASIX_TOOLCHAIN=wasix-custom\n",
+        ))
+        .stdout(predicate::str::contains(format!(
+            "WASIX_TOOLCHAIN={}\n",
+            toolchain.display()
+        )))
+        .stdout(predicate::str::contains(format!(
+            "WASIX_SYSROOT32={}\n",
+            p.root().join("sysroot32").display()
+        )))
+        .stdout(predicate::str::contains("WASIX_SYSROOT64=\n"))
+        .stdout(predicate::str::contains(format!(
+            "WASIX_TOOLCHAINS_ROOT={}\n",
+            p.root().join("toolchains").display()
+        )))
+        .stdout(predicate::str::contains(format!(
+            "WASIX_CACHE_DIR={}\n",
+            p.root().join("cache").display()
+        )))
+        .success();
+
+    // Nothing is downloaded for a toolchain that isn't linked.
+    p.cargo_wasix("self paths")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .assert()
+        .stdout(predicate::str::contains("WASIX_TOOLCHAIN=\n"))
+        .stderr(predicate::str::contains(
+            "the `wasix` toolchain isn't linked with rustup",
+        ))
+        .success();
+    Ok(())
+}
+
+#[test]
+fn frozen_toolchain() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let (toolchain, rustup_home) = p.link_fake_toolchain("wasix");
+    let frozen = |version: Option<&str>| {
+        let mut cmd = p.cargo_wasix("build --frozen-toolchain");
+        cmd.env("RUSTUP_HOME", &rustup_home)
+            .env_remove("RUSTUP_TOOLCHAIN")
+            .env_remove("WASIX_TOOLCHAIN_VERSION");
+        if let Some(version) = version {
+            cmd.env("WASIX_TOOLCHAIN_VERSION", version);
+        }
+        cmd
+    };
+
+    frozen(None)
+        .assert()
+        .stderr(predicate::str::contains(
+            "`--frozen-toolchain` requires $WASIX_TOOLCHAIN_VERSION",
+        ))
+        .code(2);
+    frozen(Some("v2024-07-05.1"))
+        .assert()
+        .stderr(predicate::str::contains(format!(
+            "the wasix toolchain at {} is release unknown, but v2024-07-05.1 is expected",
+            toolchain.display()
+        )))
+        .code(2);
+
+    std::fs::write(toolchain.join("wasix-release"), "v2024-06-01.1\n")?;
+    frozen(Some("v2024-07-05.1"))
+        .assert()
+        .stderr(predicate::str::contains(
+            "is release v2024-06-01.1, but v2024-07-05.1 is expected",
+        ))
+        .code(2);
+    // The toolchain is accepted, but being fake it fails to compile.
+    frozen(Some("v2024-06-01.1"))
+        .assert()
+        .stderr(predicate::str::contains("is expected").not())
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn offline_incompatible_crates_warning() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let build = |args: &str| {
+        let mut cmd = p.cargo_wasix(args);
+        cmd.env("CARGO_WASIX_OFFLINE", "1")
+            .env("WASIX_CACHE_DIR", p.root().join("cache"));
+        cmd
+    };
+
+    build("build")
+        .assert()
+        .stderr(predicate::str::contains(
+            "the incompatible crates list bundled with cargo-wasix (from ",
+        ))
+        .success();
+    build("build -q")
+        .assert()
+        .stderr(predicate::str::contains("incompatible crates list").not())
+        .success();
+    Ok(())
+}
+
+#[test]
+fn toolchain_without_cargo() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let (toolchain, rustup_home) = p.link_fake_toolchain("wasix");
+    std::fs::remove_file(toolchain.join("bin/cargo"))?;
+
+    p.cargo_wasix("build")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .assert()
+        .stderr(predicate::str::contains(format!(
+            "the `wasix` toolchain at {} doesn't contain `cargo`",
+            toolchain.display()
+        )))
+        // Detected before `cargo +wasix` is run.
+        .stderr(predicate::str::contains("is not installed for the toolchain").not())
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn exit_codes() -> Result<()> {
+    let p = support::project()
+        .file(
+            "src/main.rs",
+            "fn main() { let _: u32 = \"not a number\"; }",
+        )
+        .build();
+
+    // Setup errors, such as a missing toolchain.
+    p.cargo_wasix("build")
+        .env("RUSTUP_HOME", p.root().join("empty-rustup-home"))
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env("CARGO_WASIX_OFFLINE", "1")
+        .assert()
+        .stderr(predicate::str::contains("Could not detect wasix toolchain"))
+        .code(2);
+
+    // Compile errors, where `cargo` itself exits with 101.
+    p.cargo_wasix("build")
+        .assert()
+        .stderr(predicate::str::contains("mismatched types"))
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn toolchain_without_wasm64() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let (toolchain, rustup_home) = p.link_fake_toolchain("wasix");
+    std::fs::write(toolchain.join("wasix-release"), "v2024-01-01.1\n")?;
+
+    p.cargo_wasix("build64")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .assert()
+        .stderr(predicate::str::contains(format!(
+            "the `wasix` toolchain at {} (release v2024-01-01.1) doesn't support the 64-bit \
+             wasm64-wasmer-wasi target",
+            toolchain.display()
+        )))
+        .stderr(predicate::str::contains("`cargo wasix build-toolchain`"))
+        .failure();
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn toolchain_rustc_without_target() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let (toolchain, rustup_home) = p.link_fake_toolchain("wasix");
+    // The libraries of both targets exist, but `rustc` only knows one.
+    std::fs::create_dir_all(toolchain.join("lib/rustlib/wasm64-wasmer-wasi"))?;
+    std::fs::write(
+        toolchain.join("bin/rustc"),
+        format!(
+            "#!/bin/sh
+\
+             if [ \"$2\" = target-list ]; then echo wasm32-wasmer-wasi; else echo {}; fi\n",
+            toolchain.display()
+        ),
+    )?;
+
+    p.cargo_wasix("build64")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .assert()
+        .stderr(predicate::str::contains(format!(
+            "the `wasix` toolchain at {} (release unknown) has the libraries for \
+             wasm64-wasmer-wasi, but its `rustc` doesn't support that target",
+            toolchain.display()
+        )))
+        .stderr(predicate::str::contains("Compiling").not())
+        .code(2);
+
+    p.cargo_wasix("build -v")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .assert()
+        .stderr(predicate::str::contains("doesn't support that target").not());
+    Ok(())
+}
+
+#[test]
+fn verbose_effective_config() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                wasm-opt-level = "s"
+                wasm-opt-args = ["-g"]
+
+                [package.metadata.wasix.profile.release]
+                wasm-opt = false
+                wasm-opt-args = ["--strip-producers"]
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let (toolchain, rustup_home) = p.link_fake_toolchain("wasix");
+
+    // The fake toolchain can't build anything, but the configuration is
+    // printed before Cargo runs.
+    p.cargo_wasix("build -v --release")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env_remove("RUSTFLAGS")
+        .assert()
+        .stderr(is_match("Config target = wasm32-wasmer-wasi\n")?)
+        .stderr(is_match("Config profile = release\n")?)
+        .stderr(predicate::str::contains(format!(
+            "Config toolchain = wasix ({})",
+            toolchain.display()
+        )))
+        .stderr(is_match("Config RUSTFLAGS = -C target-feature=\\+atomics")?)
+        .stderr(is_match("Config wasm-opt-level = s\n")?)
+        .stderr(is_match("Config wasm-opt = false ")?)
+        .stderr(is_match("Config wasm-opt-args = --strip-producers\n")?)
+        .stderr(predicate::str::contains("Config runtime").not());
+
+    // Without the overrides of the release profile.
+    p.cargo_wasix("build -v")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .assert()
+        .stderr(is_match("Config wasm-opt = true ")?)
+        .stderr(is_match("Config wasm-opt-args = -g\n")?);
+
+    p.cargo_wasix("build")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .assert()
+        .stderr(predicate::str::contains("Config").not());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn relink_toolchain() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let (toolchain, rustup_home) = p.link_fake_toolchain("wasix");
+    let local = p.fake_toolchain("stage2");
+    let linked = || -> Result<String> {
+        let output = Command::new("rustup")
+            .args(["toolchain", "list", "--verbose"])
+            .env("RUSTUP_HOME", &rustup_home)
+            .env_remove("RUSTUP_TOOLCHAIN")
+            .output()?;
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    // An incomplete build is never linked.
+    std::fs::remove_file(local.join("bin/cargo"))?;
+    p.cargo_wasix("build64")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env("WASIX_RELINK_TOOLCHAIN", "1")
+        .env("WASIX_TOOLCHAIN_DIR", &local)
+        .assert()
+        .stderr(predicate::str::contains(
+            "the toolchain in $WASIX_TOOLCHAIN_DIR",
+        ))
+        .code(2);
+    assert!(linked()?.contains(&toolchain.display().to_string()));
+
+    // Neither is a build without the target that is built for.
+    std::fs::copy(toolchain.join("bin/cargo"), local.join("bin/cargo"))?;
+    p.cargo_wasix("build64")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env("WASIX_RELINK_TOOLCHAIN", "1")
+        .env("WASIX_TOOLCHAIN_DIR", &local)
+        .assert()
+        .stderr(predicate::str::contains(
+            "is incomplete, so it was not linked",
+        ))
+        .stderr(predicate::str::contains(format!(
+            "{} missing",
+            local.join("lib/rustlib/wasm64-wasmer-wasi").display()
+        )))
+        .stderr(predicate::str::contains("was linked").not())
+        .code(2);
+    assert!(linked()?.contains(&toolchain.display().to_string()));
+
+    // The toolchain is linked, but being fake it fails to compile.
+    p.cargo_wasix("build")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env("WASIX_RELINK_TOOLCHAIN", "1")
+        .env("WASIX_TOOLCHAIN_DIR", &local)
+        .assert()
+        .stderr(predicate::str::contains(
+            "rustup toolchain wasix was linked",
+        ))
+        .code(1);
+    assert!(linked()?.contains(&local.display().to_string()));
+
+    p.cargo_wasix("build")
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env("WASIX_RELINK_TOOLCHAIN", "1")
+        .env_remove("WASIX_TOOLCHAIN_DIR")
+        .assert()
+        .stderr(predicate::str::contains(
+            "$WASIX_RELINK_TOOLCHAIN requires $WASIX_TOOLCHAIN_DIR",
+        ))
+        .code(2);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn cargo_not_installed() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    let (_, rustup_home) = p.link_fake_toolchain("wasix");
+    // Only the `rustup` and `rustc` proxies are available, `cargo` is not.
+    let path = p.root().join("path");
+    std::fs::create_dir(&path)?;
+    let rustup = String::from_utf8(Command::new("which").arg("rustup").output()?.stdout)?;
+    std::os::unix::fs::symlink(rustup.trim(), path.join("rustup"))?;
+    std::os::unix::fs::symlink(rustup.trim(), path.join("rustc"))?;
+
+    p.cargo_wasix("build")
+        .env("PATH", &path)
+        .env("RUSTUP_HOME", &rustup_home)
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .assert()
+        .stderr(predicate::str::contains(
+            "`cargo` was not found in $PATH, install Rust and Cargo",
+        ))
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn download_toolchain_dry_run() {
+    cargo_wasix("download-toolchain --dry-run v2024-07-05.1")
+        .assert()
+        .stderr(predicate::str::contains(
+            "Dry run: would download the v2024-07-05.1 release \
+             (https://api.github.com/repos/wasix-org/rust/releases/tags/v2024-07-05.1)",
+        ))
+        .success();
+}
+
+#[test]
+fn download_toolchain_version_from_env() {
+    cargo_wasix("download-toolchain --dry-run")
+        .env("WASIX_TOOLCHAIN_VERSION", "v2024-07-05.1")
+        .assert()
+        .stderr(predicate::str::contains(
+            "Dry run: would download the v2024-07-05.1 release",
+        ))
+        .success();
+}
+
+#[test]
+fn build_toolchain_dry_run() {
+    let root = support::root();
+    drop(std::fs::remove_dir_all(&root));
+    cargo_wasix("build-toolchain")
+        .env("WASIX_DRY_RUN", "1")
+        .env("WASIX_BUILD_DIR", &root)
+        .env("WASIX_COMPONENTS", "rust")
+        .assert()
+        .stderr(predicate::str::contains("components: rust"))
+        .stderr(predicate::str::contains(format!(
+            "git clone --progress https://github.com/wasix-org/rust.git {}",
+            root.join("wasix-rust").display()
+        )))
+        .success();
+    assert!(!root.exists());
+}
+
+#[test]
+fn build_dry_run() {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    p.cargo_wasix("build")
+        .env("WASIX_DRY_RUN", "1")
+        .env("CARGO_WASIX_OFFLINE", "1")
+        .assert()
+        .stderr(predicate::str::contains("none would be downloaded"))
+        .stderr(predicate::str::contains(
+            "Dry run: stopping before `cargo build`, nothing was built",
+        ))
+        .success();
+    assert!(!p.debug_wasm("foo").exists());
+}
+
+#[test]
+fn build_toolchain_components() {
+    let root = support::root();
+    drop(std::fs::remove_dir_all(&root));
+    // The flag overrides the environment variable.
+    cargo_wasix("build-toolchain --dry-run --components libc")
+        .env("WASIX_BUILD_DIR", &root)
+        .env("WASIX_COMPONENTS", "rust")
+        .assert()
+        .stderr(predicate::str::contains("components: libc\n"))
+        .success();
+    cargo_wasix("build-toolchain --dry-run --components=rust")
+        .env("WASIX_BUILD_DIR", &root)
+        .env("WASIX_COMPONENTS", "nope")
+        .assert()
+        .stderr(predicate::str::contains("components: rust\n"))
+        .success();
+
+    cargo_wasix("build-toolchain --dry-run --components llvm")
+        .env("WASIX_BUILD_DIR", &root)
+        .assert()
+        .stderr(predicate::str::contains(
+            "invalid value `llvm` for `--components`, expected `all`, `libc` or `rust`",
+        ))
+        .failure();
+    cargo_wasix("build-toolchain --dry-run")
+        .env("WASIX_BUILD_DIR", &root)
+        .env("WASIX_COMPONENTS", "llvm")
+        .assert()
+        .stderr(predicate::str::contains(
+            "Invalid env var WASIX_COMPONENTS with value 'llvm' - expected 'all', 'libc' or 'rust'",
+        ))
+        .failure();
+    assert!(!root.exists());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn build_toolchain_quiet() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A `git` that fails to clone anything.
+    let p = support::project()
+        .file(
+            "bin/git",
+            "#!/bin/sh\nif [ \"$1\" = --version ]; then exit 0; fi\nexit 1\n",
+        )
+        .build();
+    let git = p.root().join("bin/git");
+    std::fs::set_permissions(&git, std::fs::Permissions::from_mode(0o755))?;
+    let mut path = vec![p.root().join("bin")];
+    path.extend(std::env::split_paths(
+        &std::env::var_os("PATH").unwrap_or_default(),
+    ));
+    let path = std::env::join_paths(path)?;
+
+    let build_toolchain = |args: &str| {
+        let mut cmd = cargo_wasix(args);
+        cmd.env("PATH", &path)
+            .env("WASIX_BUILD_DIR", p.root().join("build"))
+            .env("WASIX_SKIP_APT", "1")
+            .env("WASIX_MIN_DISK_SPACE", "0");
+        cmd
+    };
+    build_toolchain("build-toolchain --components libc")
+        .assert()
+        .stderr(predicate::str::contains("Preparing git repo"))
+        .failure();
+    build_toolchain("build-toolchain --components libc --quiet")
+        .assert()
+        .stderr(predicate::str::contains("Preparing git repo").not())
+        .stderr(predicate::str::contains("Building the wasix toolchain").not())
+        // Errors are still reported.
+        .stderr(predicate::str::contains("failed to clone"))
+        .failure();
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn build_toolchain_continue() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A `git` that fails to clone anything, so both components fail.
+    let p = support::project()
+        .file(
+            "bin/git",
+            "#!/bin/sh\nif [ \"$1\" = --version ]; then exit 0; fi\nexit 1\n",
+        )
+        .build();
+    let git = p.root().join("bin/git");
+    std::fs::set_permissions(&git, std::fs::Permissions::from_mode(0o755))?;
+    let mut path = vec![p.root().join("bin")];
+    path.extend(std::env::split_paths(
+        &std::env::var_os("PATH").unwrap_or_default(),
+    ));
+    let path = std::env::join_paths(path)?;
+
+    let build_toolchain = |args: &str| {
+        let mut cmd = cargo_wasix(args);
+        cmd.env("PATH", &path)
+            .env("WASIX_BUILD_DIR", p.root().join("build"))
+            .env("WASIX_SKIP_APT", "1")
+            .env("WASIX_MIN_DISK_SPACE", "0");
+        cmd
+    };
+    build_toolchain("build-toolchain")
+        .assert()
+        .stderr(predicate::str::contains(
+            "failed to build the `libc` component",
+        ))
+        .stderr(predicate::str::contains("wasix-org/rust").not())
+        .failure();
+
+    // Without an earlier sysroot there is nothing to build rust against.
+    build_toolchain("build-toolchain --continue")
+        .assert()
+        .stderr(predicate::str::contains(
+            "failed to build the `libc` component",
+        ))
+        .stderr(predicate::str::contains(
+            "cannot continue with the `rust` component because there is no sysroot",
+        ))
+        .stderr(predicate::str::contains("wasix-org/rust").not())
+        .failure();
+
+    for archive in [
+        "sysroot32/lib/wasm32-wasi/libc.a",
+        "sysroot64/lib/wasm64-wasi/libc.a",
+    ] {
+        let archive = p.root().join("build/wasix-libc").join(archive);
+        std::fs::create_dir_all(archive.parent().unwrap())?;
+        std::fs::write(archive, "")?;
+    }
+    build_toolchain("build-toolchain --continue")
+        .assert()
+        .stderr(predicate::str::contains(
+            "failed to build the `libc` component",
+        ))
+        .stderr(predicate::str::contains(
+            "continuing with the `rust` component, using the sysroot of an earlier libc build",
+        ))
+        .stderr(predicate::str::contains(
+            "failed to build the `rust` component",
+        ))
+        .failure();
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn build_toolchain_continue_after_clean() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A libc checkout with the sysroots of an earlier build, whose build
+    // script fails after `make clean` removed them and it wrote a partial
+    // sysroot.
+    let p = support::project()
+        .file("bin/git", "#!/bin/sh\nexit 0\n")
+        .file("bin/make", "#!/bin/sh\nrm -rf sysroot32 sysroot64\n")
+        .file("build/llvm-15/bin/clang", "#!/bin/sh\nexit 0\n")
+        .file(
+            "build/wasix-libc/build32.sh",
+            "mkdir -p sysroot32/lib/wasm32-wasi\n\
+             echo partial > sysroot32/lib/wasm32-wasi/libc.a\n\
+             exit 1\n",
+        )
+        .file("build/wasix-libc/sysroot32/lib/wasm32-wasi/libc.a", "good")
+        .file("build/wasix-libc/sysroot64/lib/wasm64-wasi/libc.a", "good")
+        .build();
+    for bin in ["bin/git", "bin/make", "build/llvm-15/bin/clang"] {
+        std::fs::set_permissions(p.root().join(bin), std::fs::Permissions::from_mode(0o755))?;
+    }
+    let mut path = vec![p.root().join("bin")];
+    path.extend(std::env::split_paths(
+        &std::env::var_os("PATH").unwrap_or_default(),
+    ));
+
+    cargo_wasix("build-toolchain --continue")
+        .env("PATH", std::env::join_paths(path)?)
+        .env("WASIX_BUILD_DIR", p.root().join("build"))
+        .env("WASIX_NO_UPDATE_REPOS", "1")
+        .env("WASIX_SKIP_APT", "1")
+        .env("WASIX_MIN_DISK_SPACE", "0")
+        .assert()
+        .stderr(predicate::str::contains("could not build sysroot32"))
+        .stderr(predicate::str::contains(
+            "continuing with the `rust` component, using the sysroot of an earlier libc build",
+        ))
+        .stderr(predicate::str::contains(
+            "failed to build the `rust` component",
+        ))
+        .failure();
+    // Rust was built against the sysroots from before the clean.
+    for archive in [
+        "sysroot32/lib/wasm32-wasi/libc.a",
+        "sysroot64/lib/wasm64-wasi/libc.a",
+    ] {
+        let archive = p.root().join("build/wasix-libc").join(archive);
+        assert_eq!(std::fs::read_to_string(archive)?, "good");
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn unknown_cargo_message() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A `cargo` that sends messages we don't know before building.
+    let cargo = which::which("cargo")?;
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            "bin/cargo",
+            &format!(
+                "#!/bin/sh\n\
+                 case \"$*\" in *--message-format*)\n\
+                 echo '{{\"reason\":\"something-new\",\"value\":1}}'\n\
+                 echo '{{\"not-a-reason\":true}}'\n\
+                 esac\n\
+                 exec '{}' \"$@\"\n",
+                cargo.display()
+            ),
+        )
+        // And one that sends a known message we can't parse.
+        .file(
+            "broken/cargo",
+            &format!(
+                "#!/bin/sh\n\
+                 case \"$*\" in *--message-format*)\n\
+                 echo '{{\"reason\":\"compiler-artifact\",\"filenames\":1}}'\n\
+                 esac\n\
+                 exec '{}' \"$@\"\n",
+                cargo.display()
+            ),
+        )
+        .build();
+    let path = |dir: &str| -> Result<std::ffi::OsString> {
+        let wrapper = p.root().join(dir).join("cargo");
+        std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
+        let mut path = vec![p.root().join(dir)];
+        path.extend(std::env::split_paths(
+            &std::env::var_os("PATH").unwrap_or_default(),
+        ));
+        Ok(std::env::join_paths(path)?)
+    };
+
+    p.cargo_wasix("build -v")
+        .env("PATH", path("bin")?)
+        .assert()
+        .stderr(predicate::str::contains("Ignoring"))
+        .success();
+    assert!(p.debug_wasm("foo").exists());
+
+    p.cargo_wasix("build")
+        .env("PATH", path("broken")?)
+        .assert()
+        .stderr(predicate::str::contains("failed to parse"))
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn build_toolchain_disk_space() {
+    let root = support::root();
+    drop(std::fs::remove_dir_all(&root));
+    // Fails before anything is installed, cloned or built.
+    cargo_wasix("build-toolchain")
+        .env("WASIX_BUILD_DIR", &root)
+        .env("WASIX_MIN_DISK_SPACE", "1000000000")
+        .assert()
+        .stderr(predicate::str::contains(
+            "Building the toolchain needs about 1000000000 GiB of free disk space, but only",
+        ))
+        .stderr(predicate::str::contains("Running").not())
+        .failure();
+    cargo_wasix("build-toolchain")
+        .env("WASIX_BUILD_DIR", &root)
+        .env("WASIX_MIN_DISK_SPACE", "lots")
+        .assert()
+        .stderr(predicate::str::contains(
+            "$WASIX_MIN_DISK_SPACE is set to `lots`, which is not a number of GiB",
+        ))
+        .failure();
+    assert!(!root.exists());
+}
+
+#[test]
+fn toolchain_dir_override() -> Result<()> {
+    let p = support::project().build();
+    p.cargo_wasix("download-toolchain --dry-run")
+        .env("WASIX_TOOLCHAINS_ROOT", "big-disk")
+        .assert()
+        .stderr(predicate::str::contains(format!(
+            "into {}",
+            p.root().join("big-disk").display()
+        )))
+        .success();
+
+    // Not writable, as a file is in the way.
+    std::fs::write(p.root().join("file"), "")?;
+    p.cargo_wasix("download-toolchain")
+        .env("WASIX_TOOLCHAINS_ROOT", p.root().join("file/toolchains"))
+        .assert()
+        .stderr(predicate::str::contains(format!(
+            "cannot install toolchains into {} (set by $WASIX_TOOLCHAINS_ROOT)",
+            p.root().join("file/toolchains").display()
+        )))
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn self_help() {
+    cargo_wasix("self --help")
+        .assert()
+        .stdout(predicate::str::contains(
+            "Usage: cargo wasix self <COMMAND>",
+        ))
+        .stdout(predicate::str::contains(
+            "    update-toolchain  download and link",
+        ))
+        .success();
+}
+
+#[test]
+fn self_bad() {
+    cargo_wasix("self")
+        .assert()
+        .stderr(
+            "error: `self` command must be followed by `clean`, `fetch-tools`, `paths`, \
+             `update-check` or `update-toolchain`\n",
+        )
+        .code(2);
+    cargo_wasix("self x")
+        .assert()
+        .stderr(
+            "error: unsupported `self` command: x, expected `clean`, `fetch-tools`, \
+             `paths`, `update-check` or `update-toolchain`\n",
+        )
+        .code(2);
+}
+
+// REMOVE ME: The cargo wasix build with workspace doens't work with incompatible crates PR
+#[test]
+fn workspace_works() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [workspace]
+                members = ['a']
+            "#,
+        )
+        .file(
+            "a/Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+            "#,
+        )
+        .file("a/src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build")
+        .assert()
+        .stderr(is_match(
+            "(?m)^\
+.*Compiling foo v1.0.0 .*
+.*Finished dev .*
+.*info: Post-processing WebAssembly files
+.*Optimizing with wasm-opt
+$",
+        )?)
+        .success();
+    Ok(())
+}
+
+#[test]
+fn workspace_per_package_config() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "root"
+                version = "1.0.0"
+
+                [package.metadata]
+                wasm-name-section = false
+
+                [workspace]
+                members = ['a', 'b']
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            "a/Cargo.toml",
+            r#"
+                [package]
+                name = "a"
+                version = "1.0.0"
+
+                [package.metadata]
+                wasm-opt = false
+            "#,
+        )
+        .file("a/src/main.rs", "fn main() {}")
+        .file(
+            "b/Cargo.toml",
+            r#"
+                [package]
+                name = "b"
+                version = "1.0.0"
+            "#,
+        )
+        .file("b/src/main.rs", "fn main() {}")
+        .build();
+
+    let output = p
+        .cargo_wasix("build --release --workspace --cargo-wasix-json")
+        .output()?;
+    assert!(output.status.success());
+    let summary = serde_json::from_slice::<serde_json::Value>(&output.stdout)?;
+    let wasm_opt = |name: &str| {
+        summary["artifacts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["name"] == name)
+            .map(|a| a["wasm_opt"].clone())
+    };
+    assert_eq!(wasm_opt("root"), Some(true.into()));
+    assert_eq!(wasm_opt("a"), Some(false.into()));
+    assert_eq!(wasm_opt("b"), Some(true.into()));
+
+    // Settings of the root package apply to the others unless they override
+    // them.
+    for name in ["a", "b"] {
+        let bytes = std::fs::read(p.release_wasm(name))?;
+        assert!(!custom_sections(&bytes)?.contains(&"name"));
+    }
+    Ok(())
+}
+
+#[test]
+fn manifest_path_from_sibling_dir() -> Result<()> {
+    let p = support::project()
+        .file(
+            "foo/Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasix]
+                out-dir = "dist"
+            "#,
+        )
+        .file("foo/src/main.rs", "fn main() {}")
+        .file(
+            "other/Cargo.toml",
+            r#"
+                [package]
+                name = "other"
+                version = "1.0.0"
+            "#,
+        )
+        .file("other/src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasix("build --manifest-path ../foo/Cargo.toml")
+        .current_dir(p.root().join("other"))
+        .assert()
+        .success();
+    assert!(p.root().join("foo/dist/foo.wasm").exists());
+    assert!(!p.root().join("other/dist").exists());
+    assert!(p.root().join("foo/Cargo.lock").exists());
+    assert!(!p.root().join("other/Cargo.lock").exists());
+    Ok(())
+}
+
+#[test]
+fn verbose_build_script_works() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            "build.rs",
+            r#"
+                fn main() {
+                    println!("hello");
                 }
             "#,
         )
-        .build()
-        .cargo_wasix("run")
-        .assert()
-        .stderr(is_match(
-            "^\
-.*Compiling foo v1.0.0 .*
-.*Finished dev .*
-.*Running `.*cargo-wasix .*foo.wasm`
-.*info: Post-processing WebAssembly files
-.*Optimizing with wasm-opt
-.*Running `.*foo.wasm`
-thread 'main' panicked at 'test', src.main.rs.*
-note: run with `RUST_BACKTRACE=1` .*
-",
-        )?)
-        .failure();
+        .build();
+
+    p.cargo_wasix("build -vv").assert().success();
     Ok(())
 }
 
 #[test]
-fn producers_section() -> Result<()> {
+fn dependencies_check() -> Result<()> {
     let p = support::project()
+        .file("src/main.rs", "fn main() {}")
         .file(
             "Cargo.toml",
             r#"
                 [package]
                 name = "foo"
-                version = "1.0.0"
+                version = '1.0.0'
 
-                [package.metadata]
-                wasm-producers-section = false
+                [dependencies]
+                mio = "0.8.8"
             "#,
         )
-        .file("src/main.rs", "fn main() {}")
         .build();
 
-    // Should be included in debug build
-    p.cargo_wasix("build").assert().success();
-    let bytes = std::fs::read(p.debug_wasm("foo")).context("failed to read wasm")?;
-    assert!(custom_sections(&bytes)?.contains(&"producers"));
-
-    // ... and shouldnt be included in release build w/o debuginfo
-    p.cargo_wasix("build --release").assert().success();
-    let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
-    assert!(!custom_sections(&bytes)?.contains(&"producers"));
+    p.cargo_wasix("check")
+        .assert()
+        .stderr(predicates::str::contains("Found incompatible crates in dependencies (of dependencies): libc, mio\n\nTo fix this add the following to \'Cargo.toml\':\n[patch.crates-io]\nlibc = { git = \"https://github.com/wasix-org/libc\", branch = \"master\" }\nmio = { git = \"https://github.com/wasix-org/mio\" }\n\nYou might have to run `cargo update` to ensure the dependencies are used properly\n"))
+        .success();
     Ok(())
 }
 
 #[test]
-fn name_section() -> Result<()> {
+fn dependencies_check_explain() -> Result<()> {
     let p = support::project()
+        .file("src/main.rs", "fn main() {}")
         .file(
             "Cargo.toml",
             r#"
                 [package]
                 name = "foo"
-                version = "1.0.0"
+                version = '1.0.0'
 
-                [package.metadata]
-                wasm-name-section = false
+                [dependencies]
+                mio = "0.8.8"
             "#,
         )
-        .file("src/main.rs", "fn main() {}")
+        .file(
+            "cache/incompatible_crates.json",
+            r#"[
+                {
+                    "name": "mio",
+                    "reason": "has no wasix event loop",
+                    "url": "https://example.com/mio",
+                    "replacements": [
+                        {
+                            "version": "0.8",
+                            "repo": "https://github.com/wasix-org/mio",
+                            "note": "implements it with wasix sockets"
+                        }
+                    ]
+                },
+                {
+                    "name": "libc",
+                    "replacements": []
+                }
+            ]"#,
+        )
         .build();
 
-    // Should be included in debug build
-    p.cargo_wasix("build").assert().success();
-    let bytes = std::fs::read(p.debug_wasm("foo")).context("failed to read wasm")?;
-    assert!(custom_sections(&bytes)?.contains(&"name"));
+    p.cargo_wasix("check --explain")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .assert()
+        .stderr(predicates::str::contains(
+            "[patch.crates-io]\n\
+             # has no wasix event loop (see https://example.com/mio)\n\
+             # the replacement implements it with wasix sockets\n\
+             mio = { git = \"https://github.com/wasix-org/mio\" }\n",
+        ))
+        .stderr(predicates::str::contains("--explain").not())
+        .success();
 
-    // ... and shouldnt be included in release build w/o debuginfo
-    p.cargo_wasix("build --release").assert().success();
-    let bytes = std::fs::read(p.release_wasm("foo")).context("failed to read wasm")?;
-    assert!(!custom_sections(&bytes)?.contains(&"name"));
+    // Without `--explain` only a hint is printed.
+    p.cargo_wasix("check")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .assert()
+        .stderr(predicates::str::contains("has no wasix event loop").not())
+        .stderr(predicates::str::contains(
+            "Run with `--explain` to see why these crates are incompatible",
+        ))
+        .success();
     Ok(())
 }
 
-fn custom_sections(bytes: &[u8]) -> Result<Vec<&str>> {
-    let mut sections = Vec::new();
-    for payload in wasmparser::Parser::new(0).parse_all(bytes) {
-        match payload? {
-            wasmparser::Payload::CustomSection { name, .. } => sections.push(name),
-            _ => {}
-        }
-    }
-    Ok(sections)
-}
-
 #[test]
-fn release_skip_wasm_opt() -> Result<()> {
+fn dependencies_check_existing_patch() -> Result<()> {
     let p = support::project()
+        .file("src/main.rs", "fn main() {}")
         .file(
             "Cargo.toml",
             r#"
                 [package]
                 name = "foo"
-                version = "1.0.0"
+                version = '1.0.0'
 
-                [package.metadata]
-                wasm-opt = false
+                [dependencies]
+                mio = "0.8.8"
+
+                [patch.crates-io]
+                mio = { path = "mio" }
             "#,
         )
-        .file("src/main.rs", "fn main() {}")
+        .file(
+            "mio/Cargo.toml",
+            r#"
+                [package]
+                name = "mio"
+                version = '0.8.8'
+            "#,
+        )
+        .file("mio/src/lib.rs", "")
         .build();
 
-    p.cargo_wasix("build --release")
+    p.cargo_wasix("check")
         .assert()
-        .stderr(is_match(
-            "^\
-.*Compiling foo v1.0.0 .*
-.*Finished release .*
-.*info: Post-processing WebAssembly files
-$",
-        )?)
+        .stderr(predicates::str::contains(
+            "The following crates already have a `[patch.crates-io]` entry in 'Cargo.toml' that doesn't use a replacement supporting wasix:\n\
+             * mio is patched with { path = \"mio\" }, replace it with: mio = { git = \"https://github.com/wasix-org/mio\" }\n",
+        ))
+        .stderr(predicates::str::contains("[patch.crates-io]\nmio").not())
         .success();
     Ok(())
 }
 
 #[test]
-fn skip_wasm_opt_if_debug() -> Result<()> {
+fn dependencies_check_unused_patch() -> Result<()> {
     let p = support::project()
+        .file("src/main.rs", "fn main() {}")
         .file(
             "Cargo.toml",
             r#"
                 [package]
                 name = "foo"
-                version = "1.0.0"
+                version = '1.0.0'
 
-                [profile.release]
-                debug = 1
+                [dependencies]
+                mio = "0.8.8"
+
+                [patch.crates-io]
+                mio = { path = "mio" }
+            "#,
+        )
+        .file(
+            "mio/Cargo.toml",
+            r#"
+                [package]
+                name = "mio"
+                version = '0.7.0'
             "#,
         )
+        .file("mio/src/lib.rs", "")
+        .build();
+
+    p.cargo_wasix("check")
+        .assert()
+        .stderr(predicates::str::contains(
+            "the `[patch.crates-io]` entry for `mio` ({ path = \"mio\" }) is ignored by cargo, \
+             so the incompatible crate is still used: its version 0.7.0 doesn't match the \
+             requirements (foo v1.0.0 requires ^0.8.8), use a replacement that does instead: \
+             mio = { git = \"https://github.com/wasix-org/mio\" }",
+        ))
+        .success();
+
+    // An ignored patch with the fork is only reported as such, not as a
+    // patch that doesn't use a replacement.
+    let p = support::project()
         .file("src/main.rs", "fn main() {}")
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = '1.0.0'
+
+                [dependencies]
+                mio = "0.6"
+
+                [patch.crates-io]
+                mio = { git = "https://github.com/wasix-org/mio" }
+            "#,
+        )
         .build();
 
-    p.cargo_wasix("build --release")
+    p.cargo_wasix("check")
         .assert()
-        .stderr(is_match(
-            "^\
-.*Compiling foo v1.0.0 .*
-.*Finished release .*
-.*info: Post-processing WebAssembly files
-.*Optimizing with wasm-opt
-$",
-        )?)
+        .stderr(predicates::str::contains(
+            "the `[patch.crates-io]` entry for `mio` ({ git = \"https://github.com/wasix-org/mio\" }) \
+             is ignored by cargo",
+        ))
+        .stderr(predicates::str::contains("doesn't use a replacement supporting wasix").not())
         .success();
     Ok(())
 }
 
 #[test]
-fn self_bad() {
-    cargo_wasix("self")
+fn dependencies_update() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = '1.0.0'
+
+                [dependencies]
+                mio = "0.8.8"
+            "#,
+        )
+        .build();
+
+    p.cargo_wasix("update -p mio")
         .assert()
-        .stderr("error: `self` command must be followed by `clean` or `update-check`\n")
-        .code(1);
-    cargo_wasix("self x")
+        .stderr(predicates::str::contains(
+            "Found incompatible crates in dependencies (of dependencies): libc, mio",
+        ))
+        .stderr(predicates::str::contains("[patch.crates-io]\n"))
+        .success();
+    assert!(p.root().join("Cargo.lock").exists());
+
+    // Nothing changes, so nothing is checked.
+    p.cargo_wasix("update --dry-run")
         .assert()
-        .stderr("error: unsupported `self` command: x\n")
-        .code(1);
+        .stderr(predicates::str::contains("Found incompatible crates").not())
+        .success();
+    Ok(())
 }
 
-// REMOVE ME: The cargo wasix build with workspace doens't work with incompatible crates PR
 #[test]
-fn workspace_works() -> Result<()> {
+fn dependencies_download_fallback() -> Result<()> {
     let p = support::project()
+        .file("src/main.rs", "fn main() {}")
         .file(
             "Cargo.toml",
             r#"
-                [workspace]
-                members = ['a']
+                [package]
+                name = "foo"
+                version = '1.0.0'
+
+                [dependencies]
+                mio = { path = "mio" }
             "#,
         )
         .file(
-            "a/Cargo.toml",
+            "mio/Cargo.toml",
             r#"
                 [package]
-                name = "foo"
-                version = "1.0.0"
+                name = "mio"
+                version = "0.8.8"
             "#,
         )
-        .file("a/src/main.rs", "fn main() {}")
+        .file("mio/src/lib.rs", "")
         .build();
 
-    p.cargo_wasix("build")
+    // The download can't connect, so after retrying the bundled list is used.
+    p.cargo_wasix("update -v")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .env("HTTPS_PROXY", "http://127.0.0.1:9")
         .assert()
-        .stderr(is_match(
-            "(?m)^\
-.*Compiling foo v1.0.0 .*
-.*Finished dev .*
-.*info: Post-processing WebAssembly files
-.*Optimizing with wasm-opt
-$",
-        )?)
+        .stderr(predicates::str::contains("Retrying"))
+        .stderr(predicates::str::contains(
+            "failed to get the list of known incompatible crates, using the one bundled \
+             with cargo-wasix",
+        ))
+        .stderr(predicates::str::contains(
+            "Found incompatible crates in dependencies (of dependencies): mio",
+        ))
+        .success();
+    assert!(!p.root().join("cache/incompatible_crates.json").exists());
+
+    // An outdated cached list is still preferred over the bundled one.
+    let cached = p.root().join("cache/incompatible_crates.json");
+    std::fs::write(&cached, "[]")?;
+    std::fs::File::options()
+        .write(true)
+        .open(&cached)?
+        .set_modified(
+            std::time::SystemTime::now() - std::time::Duration::from_secs(60 * 24 * 60 * 60),
+        )?;
+    p.cargo_wasix("update")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .env("HTTPS_PROXY", "http://127.0.0.1:9")
+        .assert()
+        .stderr(predicates::str::contains(format!(
+            "failed to get the list of known incompatible crates, using the outdated one \
+             cached at {} instead",
+            cached.display()
+        )))
+        .stderr(predicates::str::contains("bundled").not())
+        .stderr(predicates::str::contains("Found incompatible crates").not())
+        .success();
+
+    // Which is expected on flaky networks, so `--quiet` silences it.
+    p.cargo_wasix("update --quiet")
+        .env("WASIX_CACHE_DIR", p.root().join("cache"))
+        .env("HTTPS_PROXY", "http://127.0.0.1:9")
+        .assert()
+        .stderr(predicates::str::contains("known incompatible crates").not())
         .success();
     Ok(())
 }
 
 #[test]
-fn verbose_build_script_works() -> Result<()> {
+fn dependencies_check_target() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // The dependencies are resolved for the target of the subcommand.
+    p.cargo_wasix("update64 -v")
+        .assert()
+        .stderr(predicates::str::contains(
+            "Checking dependencies for `wasm64-wasmer-wasi`",
+        ))
+        .success();
+    p.cargo_wasix("update -v")
+        .assert()
+        .stderr(predicates::str::contains(
+            "Checking dependencies for `wasm32-wasmer-wasi`",
+        ))
+        .success();
+    Ok(())
+}
+
+#[test]
+fn dependencies_check_generates_lockfile() -> Result<()> {
     let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    assert!(!p.root().join("Cargo.lock").exists());
+    p.cargo_wasix("check --offline").assert().success();
+    assert!(p.root().join("Cargo.lock").exists());
+
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
         .file(
             "Cargo.toml",
             r#"
                 [package]
                 name = "foo"
-                version = "1.0.0"
-            "#,
-        )
-        .file("src/main.rs", "fn main() {}")
-        .file(
-            "build.rs",
-            r#"
-                fn main() {
-                    println!("hello");
-                }
+                version = '1.0.0'
+
+                [dependencies]
+                bar = { path = "bar" }
             "#,
         )
         .build();
 
-    p.cargo_wasix("build -vv").assert().success();
+    p.cargo_wasix("check")
+        .assert()
+        .stderr(predicates::str::contains(
+            "failed to check dependencies: failed to generate `Cargo.lock`",
+        ))
+        .failure();
     Ok(())
 }
 
 #[test]
-fn dependencies_check() -> Result<()> {
+fn dependencies_check_locked() -> Result<()> {
     let p = support::project()
         .file("src/main.rs", "fn main() {}")
         .file(
@@ -916,10 +4299,27 @@ fn dependencies_check() -> Result<()> {
         )
         .build();
 
-    p.cargo_wasix("check")
+    // Without a lock file there is nothing to check against.
+    p.cargo_wasix("check --locked")
         .assert()
-        .stderr(predicates::str::contains("Found incompatible crates in dependencies (of dependencies): libc, mio\n\nTo fix this add the following to \'Cargo.toml\':\n[patch.crates-io]\nlibc = { git = \"https://github.com/wasix-org/libc\", branch = \"master\" }\nmio = { git = \"https://github.com/wasix-org/mio\" }\n\nYou might have to run `cargo update` to ensure the dependencies are used properly\n"))
-        .success();
+        .stderr(predicates::str::contains(
+            "Cargo.lock` doesn't exist and `--locked` was passed",
+        ))
+        .failure();
+    assert!(!p.root().join("Cargo.lock").exists());
+
+    p.cargo_wasix("check").assert().success();
+    assert!(p.root().join("Cargo.lock").exists());
+
+    for flag in ["--locked", "--frozen"] {
+        p.cargo_wasix(&format!("check {flag}"))
+            .assert()
+            .stderr(predicates::str::contains(format!(
+                "`Cargo.lock` has to be updated to use the patches, which `{flag}` doesn't allow"
+            )))
+            .stderr(predicates::str::contains("cargo update").not())
+            .success();
+    }
     Ok(())
 }
 
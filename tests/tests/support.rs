@@ -16,7 +16,16 @@ pub fn root() -> PathBuf {
     path.pop(); // chop off 'debug'
     path.push("tests");
     fs::create_dir_all(&path).unwrap();
-    path.join(&format!("t{}", id))
+    path.join(format!("t{}", id))
+}
+
+/// Path of the `name` wasm built with `profile` in the target directory
+/// `build_dir`.
+pub fn wasm_in(build_dir: &Path, profile: &str, name: &str) -> PathBuf {
+    build_dir
+        .join("wasm32-wasmer-wasi")
+        .join(profile)
+        .join(format!("{}.wasm", name))
 }
 
 pub fn project() -> ProjectBuilder {
@@ -98,27 +107,92 @@ impl Project {
     }
 
     pub fn debug_wasm(&self, name: &str) -> PathBuf {
-        self.build_dir()
-            .join("wasm32-wasmer-wasi")
-            .join("debug")
-            .join(format!("{}.wasm", name))
+        self.profile_wasm("dev", name)
     }
 
     pub fn release_wasm(&self, name: &str) -> PathBuf {
-        self.build_dir()
-            .join("wasm32-wasmer-wasi")
-            .join("release")
-            .join(format!("{}.wasm", name))
+        self.profile_wasm("release", name)
+    }
+
+    /// Returns the path of a module built with a profile, whose directory is
+    /// named after the profile, except for `dev` (`debug`).
+    pub fn profile_wasm(&self, profile: &str, name: &str) -> PathBuf {
+        let dir = if profile == "dev" { "debug" } else { profile };
+        wasm_in(&self.build_dir(), dir, name)
+    }
+
+    /// Creates a fake toolchain whose `rustc` passes the sanity checks of
+    /// `cargo wasix`, and links it as `name` in a private rustup home. Its
+    /// `cargo` is the real one, but nothing can actually be compiled.
+    ///
+    /// Returns the toolchain directory and the rustup home to use with
+    /// `RUSTUP_HOME`.
+    pub fn link_fake_toolchain(&self, name: &str) -> (PathBuf, PathBuf) {
+        let toolchain = self.fake_toolchain("toolchain");
+        let rustup_home = self.root().join("rustup-home");
+        let status = Command::new("rustup")
+            .args(["toolchain", "link", name])
+            .arg(&toolchain)
+            .env("RUSTUP_HOME", &rustup_home)
+            .env_remove("RUSTUP_TOOLCHAIN")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        (toolchain, rustup_home)
+    }
+
+    /// Creates a toolchain in `dir` that only has the 32-bit target, without
+    /// linking it. Its `rustc` supports the targets in `lib/rustlib`.
+    pub fn fake_toolchain(&self, dir: &str) -> PathBuf {
+        let toolchain = self.root().join(dir);
+        fs::create_dir_all(toolchain.join("bin")).unwrap();
+        fs::create_dir_all(toolchain.join("lib/rustlib/wasm32-wasmer-wasi")).unwrap();
+        // `cargo` forwards to the one of the toolchain running the tests.
+        let real_cargo = Command::new("rustup")
+            .args(["which", "cargo"])
+            .output()
+            .unwrap();
+        let real_cargo = String::from_utf8(real_cargo.stdout).unwrap();
+        let scripts = [
+            (
+                "rustc",
+                format!(
+                    "if [ \"$2\" = target-list ]; then \
+                     for t in {0}/lib/rustlib/*; do echo \"${{t##*/}}\"; done; \
+                     else echo {0}; fi",
+                    toolchain.display()
+                ),
+            ),
+            ("cargo", format!("exec {} \"$@\"", real_cargo.trim())),
+        ];
+        for (name, script) in scripts {
+            let path = toolchain.join("bin").join(name);
+            fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            }
+        }
+        toolchain
     }
 
     pub fn cargo_wasix(&self, cmd: &str) -> Command {
         let mut process = super::cargo_wasix(cmd);
         process
             .current_dir(&self.root)
-            .env("CARGO_HOME", self.root.join("cargo-home"));
+            .env("CARGO_HOME", self.root.join("cargo-home"))
+            // Keep the artifacts in `build_dir`.
+            .env_remove("CARGO_TARGET_DIR");
 
         if let Some(runtime_override) = &self.runtime_override {
-            process.env("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER", runtime_override);
+            let is64bit = cmd.split_whitespace().next().unwrap_or("").ends_with("64");
+            let runner_env_var = if is64bit {
+                "CARGO_TARGET_WASM64_WASMER_WASI_RUNNER"
+            } else {
+                "CARGO_TARGET_WASM32_WASMER_WASI_RUNNER"
+            };
+            process.env(runner_env_var, runtime_override);
         }
 
         process.arg("--color=never");